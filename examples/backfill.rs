@@ -0,0 +1,35 @@
+use clap::Parser;
+use std::fs::{create_dir_all, read_to_string};
+use std::path::PathBuf;
+
+extern crate tsdb;
+use tsdb::backfill;
+use tsdb::chunkenc::DEFAULT_CHUNK_RANGE;
+
+// Creates blocks from an OpenMetrics or Prometheus text format file with
+// timestamps, like `promtool tsdb create-blocks-from openmetrics`.
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(parse(from_os_str), value_name = "FILE")]
+    input: PathBuf,
+    #[clap(parse(from_os_str), value_name = "DIR", default_value = "data")]
+    output: PathBuf,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let input = read_to_string(&cli.input).expect("Failed to read input.");
+    let samples = backfill::parse(&input).expect("Failed to parse input.");
+    create_dir_all(&cli.output).expect("Failed to create output directory.");
+
+    let blocks = backfill::create_blocks(&cli.output, samples, DEFAULT_CHUNK_RANGE)
+        .expect("Failed to write blocks.");
+    for meta in blocks {
+        println!(
+            "Wrote block {} with {} samples from {} to {}.",
+            meta.ulid, meta.stats.num_samples, meta.min_time, meta.max_time
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use clap::Parser;
+use std::fs::read;
+use std::path::PathBuf;
+
+extern crate tsdb;
+use tsdb::head::Head;
+use tsdb::remote;
+
+const REMOTE_WRITE_PATH: &str = "/api/v1/write";
+
+// Receives Prometheus remote write requests into the head of a data directory
+// and cuts it into blocks. Recorded request payloads can be ingested instead
+// of listening.
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(short, long, parse(from_os_str), value_name = "DIR")]
+    data_dir: PathBuf,
+    #[clap(short, long, default_value = "127.0.0.1:9201")]
+    listen: String,
+    #[clap(parse(from_os_str), value_name = "PAYLOAD")]
+    payloads: Vec<PathBuf>,
+}
+
+fn ingest(head: &mut Head, payload: &[u8]) -> bool {
+    let ok = match remote::write(head, payload) {
+        Ok(n) => {
+            println!("Appended {} samples.", n);
+            true
+        }
+        Err(e) => {
+            println!("Failed to append samples: {:?}", e);
+            false
+        }
+    };
+    match head.compact() {
+        Ok(blocks) => {
+            for meta in blocks {
+                println!("Wrote block {}.", meta.ulid);
+            }
+        }
+        Err(e) => println!("Failed to cut block: {:?}", e),
+    }
+    ok
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let mut head = Head::open(&cli.data_dir).expect("Failed to open head.");
+
+    if !cli.payloads.is_empty() {
+        for path in cli.payloads {
+            let payload = read(&path).expect("Failed to read payload.");
+            ingest(&mut head, &payload);
+        }
+        return;
+    }
+
+    let server = tiny_http::Server::http(&cli.listen).expect("Failed to listen.");
+    println!("Receiving on {}{}.", cli.listen, REMOTE_WRITE_PATH);
+
+    for mut request in server.incoming_requests() {
+        if request.url() != REMOTE_WRITE_PATH {
+            let _ = request.respond(tiny_http::Response::empty(404));
+            continue;
+        }
+
+        let mut body = Vec::<u8>::new();
+        let status = match request.as_reader().read_to_end(&mut body) {
+            Ok(_) if ingest(&mut head, &body) => 204,
+            _ => 400,
+        };
+        if let Err(e) = request.respond(tiny_http::Response::empty(status)) {
+            println!("Failed to respond: {}", e);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+extern crate tsdb;
+use tsdb::api;
+use tsdb::datadir::DataDir;
+use tsdb::engine::Engine;
+use tsdb::querier::MergeQuerier;
+use tsdb::remote;
+
+const REMOTE_READ_PATH: &str = "/api/v1/read";
+
+fn header(name: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(name, value).expect("Invalid header.")
+}
+
+// Serves the Prometheus HTTP query API over the blocks of a data directory, so
+// Grafana can use archived blocks as a Prometheus data source. Prometheus can
+// read the blocks with remote read.
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(short, long, parse(from_os_str), value_name = "DIR")]
+    data_dir: PathBuf,
+    #[clap(short, long, default_value = "127.0.0.1:9090")]
+    listen: String,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let data_dir = DataDir::open(&cli.data_dir).expect("Failed to open data directory.");
+    let querier = MergeQuerier::open(&data_dir).expect("Failed to open blocks.");
+    let engine = Engine::new();
+
+    let server = tiny_http::Server::http(&cli.listen).expect("Failed to listen.");
+    println!(
+        "Serving {} blocks on {}.",
+        data_dir.blocks.len(),
+        cli.listen
+    );
+
+    for mut request in server.incoming_requests() {
+        let mut body = Vec::<u8>::new();
+        if request.as_reader().read_to_end(&mut body).is_err() {
+            body.clear();
+        }
+
+        let response = if request.url() == REMOTE_READ_PATH {
+            match remote::read(&querier, &body) {
+                Ok(result) => {
+                    let mut response = tiny_http::Response::from_data(result.body)
+                        .with_header(header("Content-Type", result.content_type));
+                    if let Some(encoding) = result.content_encoding {
+                        response.add_header(header("Content-Encoding", encoding));
+                    }
+                    response
+                }
+                Err(e) => tiny_http::Response::from_data(format!("{:?}", e)).with_status_code(400),
+            }
+        } else {
+            let body = String::from_utf8_lossy(&body);
+            let (status, json) = api::handle(&querier, &engine, request.url(), &body);
+            tiny_http::Response::from_string(json)
+                .with_status_code(status)
+                .with_header(header("Content-Type", "application/json"))
+        };
+        if let Err(e) = request.respond(response) {
+            println!("Failed to respond: {}", e);
+        }
+    }
+}
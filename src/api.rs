@@ -0,0 +1,471 @@
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value as Json};
+
+use crate::common::*;
+use crate::engine::{format_value, Engine, RangeSeries, Value};
+use crate::labels::Matcher;
+use crate::promql::{parse, parse_duration, Expr};
+use crate::querier::Querier;
+
+const API_PREFIX: &str = "/api/v1/";
+// the limit of Prometheus for range queries
+const MAX_POINTS: i64 = 11000;
+
+struct ApiError {
+    status: u16,
+    kind: &'static str,
+    msg: String,
+}
+
+fn bad_data(msg: &str) -> ApiError {
+    ApiError {
+        status: 400,
+        kind: "bad_data",
+        msg: msg.to_string(),
+    }
+}
+
+fn execution(e: TSDBError) -> ApiError {
+    match e {
+        TSDBError::InvalidQuery | TSDBError::InvalidMatcher => bad_data("invalid query"),
+        e => ApiError {
+            status: 422,
+            kind: "execution",
+            msg: format!("{:?}", e),
+        },
+    }
+}
+
+type Params = Vec<(String, String)>;
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// parameters of a query string or a form encoded body
+fn parse_params(params: &mut Params, s: &str) {
+    for pair in s.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.push((decode(name), decode(value)));
+    }
+}
+
+fn param<'a>(params: &'a Params, name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// NOTE: Timestamps are Unix times in seconds or RFC 3339 dates:
+// https://prometheus.io/docs/prometheus/latest/querying/api/#format-overview
+//
+// Returns milliseconds.
+pub fn parse_time(s: &str) -> Option<i64> {
+    if let Ok(secs) = s.parse::<f64>() {
+        return secs.is_finite().then(|| (secs * 1000.0).round() as i64);
+    }
+
+    // 2015-07-01T20:10:51.781Z, all offsets below are byte offsets and all
+    // fields unsigned digits
+    let digits = |s: &str, from: usize, to: usize| {
+        s.get(from..to)
+            .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|n| n.parse::<i64>().ok())
+    };
+    let num = |from: usize, to: usize| digits(s, from, to);
+    if !s.is_ascii() || s.len() < 20 || s.as_bytes()[10] != b'T' {
+        return None;
+    }
+    if [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')]
+        .iter()
+        .any(|(i, c)| s.as_bytes()[*i] != *c)
+    {
+        return None;
+    }
+    let (year, month, day) = (num(0, 4)?, num(5, 7)?, num(8, 10)?);
+    let (hour, minute, second) = (num(11, 13)?, num(14, 16)?, num(17, 19)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [31, 28 + leap as i64, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if !(1..=12).contains(&month)
+        || !(1..=month_days[month as usize - 1]).contains(&day)
+        || hour >= 24
+        || minute >= 60
+        || second >= 60
+    {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let mut ms = ((days * 24 + hour) * 60 + minute) * 60 * 1000 + second * 1000;
+
+    let mut rest = &s[19..];
+    if let Some(frac) = rest.strip_prefix('.') {
+        let digits = frac.chars().take_while(|c| c.is_ascii_digit()).count();
+        let millis: String = frac[..digits]
+            .chars()
+            .chain("000".chars())
+            .take(3)
+            .collect();
+        ms += millis.parse::<i64>().ok()?;
+        rest = &frac[digits..];
+    }
+    match rest.as_bytes().first() {
+        Some(b'Z') if rest.len() == 1 => Some(ms),
+        Some(sign @ (b'+' | b'-')) if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let (hours, minutes) = (digits(rest, 1, 3)?, digits(rest, 4, 6)?);
+            if hours >= 24 || minutes >= 60 {
+                return None;
+            }
+            let offset = (hours * 60 + minutes) * 60 * 1000;
+            Some(if *sign == b'+' {
+                ms - offset
+            } else {
+                ms + offset
+            })
+        }
+        _ => None,
+    }
+}
+
+fn time_param(params: &Params, name: &str, default: i64) -> std::result::Result<i64, ApiError> {
+    match param(params, name) {
+        Some(s) => parse_time(s).ok_or_else(|| bad_data(&format!("invalid parameter {:?}", name))),
+        None => Ok(default),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+// times are seconds, without fraction for whole seconds
+fn json_time(t: i64) -> Json {
+    if t % 1000 == 0 {
+        json!(t / 1000)
+    } else {
+        json!(t as f64 / 1000.0)
+    }
+}
+
+fn json_labels(labels: &Labels) -> Json {
+    let mut map = Map::new();
+    for (n, v) in labels {
+        map.insert(n.clone(), json!(v));
+    }
+    Json::Object(map)
+}
+
+fn json_matrix(matrix: &[RangeSeries]) -> Json {
+    let series: Vec<Json> = matrix
+        .iter()
+        .map(|s| {
+            let values: Vec<Json> = s
+                .samples
+                .iter()
+                .map(|(t, v)| json!([json_time(*t), format_value(*v)]))
+                .collect();
+            json!({"metric": json_labels(&s.labels), "values": values})
+        })
+        .collect();
+    json!({"resultType": "matrix", "result": series})
+}
+
+fn json_value(value: &Value) -> Json {
+    match value {
+        Value::Scalar(t, v) => {
+            json!({"resultType": "scalar", "result": [json_time(*t), format_value(*v)]})
+        }
+        Value::String(t, s) => json!({"resultType": "string", "result": [json_time(*t), s]}),
+        Value::Vector(vector) => {
+            let samples: Vec<Json> = vector
+                .iter()
+                .map(|s| {
+                    json!({"metric": json_labels(&s.labels), "value": [json_time(s.t), format_value(s.v)]})
+                })
+                .collect();
+            json!({"resultType": "vector", "result": samples})
+        }
+        Value::Matrix(matrix) => json_matrix(matrix),
+    }
+}
+
+// matchers of the match[] parameters, each has to be a vector selector
+fn match_params(params: &Params) -> std::result::Result<Vec<Vec<Matcher>>, ApiError> {
+    let mut selectors = Vec::<Vec<Matcher>>::new();
+    for (_, s) in params.iter().filter(|(n, _)| n == "match[]") {
+        match parse(s) {
+            Ok(Expr::VectorSelector(vs)) => selectors.push(vs.matchers),
+            _ => return Err(bad_data(&format!("invalid series selector {:?}", s))),
+        }
+    }
+    Ok(selectors)
+}
+
+// labels of all series matching any of the selectors
+fn series_labels(
+    querier: &dyn Querier,
+    selectors: &[Vec<Matcher>],
+    params: &Params,
+) -> std::result::Result<BTreeSet<Labels>, ApiError> {
+    let start = time_param(params, "start", i64::MIN)?;
+    let end = time_param(params, "end", i64::MAX)?;
+
+    let mut all = BTreeSet::<Labels>::new();
+    for matchers in selectors {
+        for s in querier.select(matchers, start, end).map_err(execution)? {
+            all.insert(s.labels);
+        }
+    }
+    Ok(all)
+}
+
+fn route(
+    querier: &dyn Querier,
+    engine: &Engine,
+    path: &str,
+    params: &Params,
+) -> std::result::Result<Json, ApiError> {
+    let endpoint = match path.strip_prefix(API_PREFIX) {
+        Some(endpoint) => endpoint,
+        None => {
+            return Err(ApiError {
+                status: 404,
+                kind: "not_found",
+                msg: format!("unknown path {:?}", path),
+            })
+        }
+    };
+
+    match endpoint {
+        "query" => {
+            let query = param(params, "query").ok_or_else(|| bad_data("missing query"))?;
+            let t = time_param(params, "time", now())?;
+            let value = engine.query(querier, query, t).map_err(execution)?;
+            Ok(json_value(&value))
+        }
+        "query_range" => {
+            let query = param(params, "query").ok_or_else(|| bad_data("missing query"))?;
+            let start = time_param(params, "start", now())?;
+            let end = time_param(params, "end", now())?;
+            let step = param(params, "step")
+                .and_then(|s| parse_duration(s).or_else(|| parse_time(s)))
+                .ok_or_else(|| bad_data("invalid parameter \"step\""))?;
+            if end < start {
+                return Err(bad_data("end timestamp must not be before start time"));
+            }
+            if step <= 0 {
+                return Err(bad_data(
+                    "zero or negative query resolution step widths are not accepted",
+                ));
+            }
+            if end.saturating_sub(start) / step > MAX_POINTS {
+                return Err(bad_data(
+                    "exceeded maximum resolution of 11,000 points per timeseries",
+                ));
+            }
+            let matrix = engine
+                .query_range(querier, query, start, end, step)
+                .map_err(execution)?;
+            Ok(json_matrix(&matrix))
+        }
+        "series" => {
+            let selectors = match_params(params)?;
+            if selectors.is_empty() {
+                return Err(bad_data("no match[] parameter provided"));
+            }
+            let all = series_labels(querier, &selectors, params)?;
+            Ok(Json::Array(all.iter().map(json_labels).collect()))
+        }
+        "labels" => {
+            let selectors = match_params(params)?;
+            if selectors.is_empty() {
+                return Ok(json!(querier.label_names().map_err(execution)?));
+            }
+            let names: BTreeSet<String> = series_labels(querier, &selectors, params)?
+                .into_iter()
+                .flat_map(|l| l.into_iter().map(|(n, _)| n))
+                .collect();
+            Ok(json!(names))
+        }
+        _ => {
+            let name = endpoint
+                .strip_prefix("label/")
+                .and_then(|e| e.strip_suffix("/values"))
+                .map(decode)
+                .ok_or_else(|| ApiError {
+                    status: 404,
+                    kind: "not_found",
+                    msg: format!("unknown path {:?}", path),
+                })?;
+            let selectors = match_params(params)?;
+            if selectors.is_empty() {
+                return Ok(json!(querier.label_values(&name).map_err(execution)?));
+            }
+            let values: BTreeSet<String> = series_labels(querier, &selectors, params)?
+                .iter()
+                .map(|l| crate::labels::get(l, &name).to_string())
+                .filter(|v| !v.is_empty())
+                .collect();
+            Ok(json!(values))
+        }
+    }
+}
+
+// NOTE: Format of the HTTP API of Prometheus:
+// https://prometheus.io/docs/prometheus/latest/querying/api/
+//
+// Handles a request for the URL and the form encoded body of POST requests and
+// returns the status code and the JSON response.
+pub fn handle(querier: &dyn Querier, engine: &Engine, url: &str, body: &str) -> (u16, String) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let mut params = Params::new();
+    parse_params(&mut params, query);
+    parse_params(&mut params, body);
+
+    match route(querier, engine, path, &params) {
+        Ok(data) => (200, json!({"status": "success", "data": data}).to_string()),
+        Err(e) => (
+            e.status,
+            json!({"status": "error", "errorType": e.kind, "error": e.msg}).to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::BlockWriter;
+    use crate::querier::BlockQuerier;
+
+    fn labels(job: &str) -> Labels {
+        vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("job"), String::from(job)),
+        ]
+    }
+
+    fn get(querier: &dyn Querier, url: &str) -> (u16, Json) {
+        let (status, body) = handle(querier, &Engine::new(), url, "");
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[test]
+    fn serve_api() {
+        assert_eq!(Some(1435781451781), parse_time("2015-07-01T20:10:51.781Z"));
+        assert_eq!(Some(1435781451000), parse_time("2015-07-01T22:10:51+02:00"));
+        assert_eq!(Some(1435781451500), parse_time("1435781451.5"));
+        assert_eq!(None, parse_time("2015-07-01T20:10:51+0é00"));
+        assert_eq!(None, parse_time("NaN"));
+        assert_eq!(Some(951782400000), parse_time("2000-02-29T00:00:00Z"));
+        for s in [
+            "2015-13-45T25:61:61Z",
+            "2015-00-01T20:10:51Z",
+            "2015-02-29T20:10:51Z",
+            "2015-04-31T20:10:51Z",
+            "2015-07-01T24:10:51Z",
+            "2015-07-01T20:60:51Z",
+            "2015-07-01T20:10:60Z",
+            "2015-07-+1T20:10:51Z",
+            "2015-07-01T-1:10:51Z",
+            "2015-07-01T20:10:51+-1:00",
+            "2015-07-01T20:10:51+02:60",
+        ] {
+            assert_eq!(None, parse_time(s));
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let samples: Vec<(i64, f64)> = (0..240).map(|i| (i * 15000, 1.0)).collect();
+        let mut writer = BlockWriter::new(dir.path());
+        writer.add_series(labels("a"), &samples);
+        writer.add_series(labels("b d"), &samples);
+        let meta = writer.write().unwrap();
+        let querier = BlockQuerier::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+
+        let (status, json) = get(&querier, "/api/v1/query?query=sum(up)&time=600");
+        assert_eq!(200, status);
+        assert_eq!(
+            json!({"status": "success", "data": {"resultType": "vector", "result": [
+                {"metric": {}, "value": [600, "2"]}
+            ]}}),
+            json
+        );
+
+        let (_, json) = get(
+            &querier,
+            "/api/v1/query_range?query=up%7Bjob%3D%22a%22%7D&start=0&end=60&step=30s",
+        );
+        assert_eq!(
+            json!({"resultType": "matrix", "result": [{
+                "metric": {"__name__": "up", "job": "a"},
+                "values": [[0, "1"], [30, "1"], [60, "1"]]
+            }]}),
+            json["data"]
+        );
+
+        let (_, json) = get(&querier, "/api/v1/series?match[]=up&start=0&end=100");
+        assert_eq!(
+            json!([{"__name__": "up", "job": "a"}, {"__name__": "up", "job": "b d"}]),
+            json["data"]
+        );
+        let (_, json) = get(&querier, "/api/v1/labels");
+        assert_eq!(json!(["__name__", "job"]), json["data"]);
+        let (_, json) = get(
+            &querier,
+            "/api/v1/label/job/values?match[]=up{job=~\"b.*\"}",
+        );
+        assert_eq!(json!(["b d"]), json["data"]);
+
+        let (status, json) = get(&querier, "/api/v1/query?query=sum(");
+        assert_eq!(400, status);
+        assert_eq!(json!("bad_data"), json["errorType"]);
+        assert_eq!(404, get(&querier, "/api/v2/query").0);
+        let (status, json) = get(&querier, "/api/v1/query?query=up&time=2015-13-45T25:61:61Z");
+        assert_eq!(400, status);
+        assert_eq!(json!("bad_data"), json["errorType"]);
+
+        let (status, json) = get(
+            &querier,
+            "/api/v1/query_range?query=up&start=0&end=86400&step=1",
+        );
+        assert_eq!(400, status);
+        assert_eq!(json!("bad_data"), json["errorType"]);
+        let (status, _) = get(
+            &querier,
+            "/api/v1/query_range?query=up&start=0&end=60&step=0",
+        );
+        assert_eq!(400, status);
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, path::Path};
+
+use crate::block::BlockWriter;
+use crate::common::*;
+use crate::meta::MetaData;
+
+const EOF_MARKER: &str = "# EOF";
+
+// A sample of an exposition with the labels of its series, including the
+// metric name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedSample {
+    pub labels: Labels,
+    pub t: i64,
+    pub v: f64,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+// quoted label value with \\, \" and \n escapes, returns the value and the
+// rest of the line after the closing quote
+fn parse_label_value(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.strip_prefix('"')?.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+// labels between braces, returns the rest of the line after the closing brace
+fn parse_labels<'a>(mut s: &'a str, labels: &mut Labels) -> Option<&'a str> {
+    loop {
+        s = s.trim_start();
+        if let Some(rest) = s.strip_prefix('}') {
+            return Some(rest);
+        }
+
+        let end = s.find(|c: char| !is_name_char(c))?;
+        let name = &s[..end];
+        let rest = s[end..].trim_start().strip_prefix('=')?.trim_start();
+        let (value, rest) = parse_label_value(rest)?;
+        if name.is_empty() {
+            return None;
+        }
+        labels.push((name.to_string(), value));
+
+        s = rest.trim_start();
+        if let Some(rest) = s.strip_prefix(',') {
+            s = rest;
+        } else if !s.starts_with('}') {
+            return None;
+        }
+    }
+}
+
+// A sample line without its exemplar, a missing timestamp is returned as None.
+// OpenMetrics timestamps are in seconds, the ones of the text format in
+// milliseconds.
+fn parse_sample(line: &str, openmetrics: bool) -> Option<(Labels, f64, Option<i64>)> {
+    let end = line.find(|c: char| !is_name_char(c)).unwrap_or(line.len());
+    if end == 0 {
+        return None;
+    }
+    let mut labels = vec![(String::from("__name__"), line[..end].to_string())];
+
+    let mut rest = &line[end..];
+    if let Some(r) = rest.strip_prefix('{') {
+        rest = parse_labels(r, &mut labels)?;
+    }
+    if openmetrics {
+        if let Some(i) = rest.find(" # ") {
+            rest = &rest[..i];
+        }
+    }
+
+    let mut fields = rest.split_whitespace();
+    let v = fields.next()?.parse::<f64>().ok()?;
+    let t = match fields.next() {
+        Some(t) if openmetrics => Some((t.parse::<f64>().ok()? * 1000.0).round() as i64),
+        Some(t) => Some(t.parse::<i64>().ok()?),
+        None => None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+
+    Some((labels, v, t))
+}
+
+// NOTE: Formats of the expositions:
+// https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+//
+// An input ending with "# EOF" is read as OpenMetrics, any other as the text
+// format. Metadata and comments are skipped, every sample needs a timestamp.
+pub fn parse(input: &str) -> Result<Vec<ParsedSample>> {
+    let openmetrics = input.trim_end().ends_with(EOF_MARKER);
+    let mut samples = Vec::<ParsedSample>::new();
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line == EOF_MARKER && openmetrics {
+            break;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_sample(line, openmetrics) {
+            Some((labels, v, Some(t))) => samples.push(ParsedSample { labels, t, v }),
+            Some((_, _, None)) => {
+                println!("Sample without timestamp in line {}.", i + 1);
+                return Err(TSDBError::Default);
+            }
+            None => {
+                println!("Invalid sample in line {}: {}", i + 1, line);
+                return Err(TSDBError::Default);
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+// Write samples into blocks in dir, one per range they fall into, like
+// `promtool tsdb create-blocks-from openmetrics` does it. The samples do not
+// have to be sorted, for duplicate timestamps of a series the first one is
+// kept. Returns the written blocks sorted by time.
+pub fn create_blocks(dir: &Path, samples: Vec<ParsedSample>, range: i64) -> Result<Vec<MetaData>> {
+    if range <= 0 {
+        println!("Invalid range {}.", range);
+        return Err(TSDBError::Default);
+    }
+
+    let mut blocks = BTreeMap::<i64, BTreeMap<Labels, Vec<(i64, f64)>>>::new();
+    for s in samples {
+        let mut labels = s.labels;
+        labels.sort();
+        blocks
+            .entry(s.t - s.t.rem_euclid(range))
+            .or_default()
+            .entry(labels)
+            .or_default()
+            .push((s.t, s.v));
+    }
+
+    let mut metas = Vec::<MetaData>::with_capacity(blocks.len());
+    for (start, series) in blocks {
+        let mut writer = BlockWriter::new(dir);
+        writer.set_time_range(start, start + range);
+        for (labels, samples) in series {
+            writer.add_series(labels, &samples);
+        }
+        metas.push(writer.write()?);
+    }
+
+    Ok(metas)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunkenc::DEFAULT_CHUNK_RANGE;
+    use crate::labels::{MatchType, Matcher};
+    use crate::querier::{BlockQuerier, Querier};
+
+    #[test]
+    fn parse_expositions() {
+        let text = r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post", code="400",} 3 1395066363000
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9 1395066363000
+metric_without_labels +Inf 1395066363001
+"#;
+        let samples = parse(text).unwrap();
+        assert_eq!(4, samples.len());
+        assert_eq!(
+            ParsedSample {
+                labels: vec![
+                    (
+                        String::from("__name__"),
+                        String::from("http_requests_total")
+                    ),
+                    (String::from("method"), String::from("post")),
+                    (String::from("code"), String::from("400")),
+                ],
+                t: 1395066363000,
+                v: 3.0,
+            },
+            samples[1]
+        );
+        assert_eq!("C:\\DIR\\FILE.TXT", samples[2].labels[1].1);
+        assert_eq!("Cannot find file:\n\"FILE.TXT\"", samples[2].labels[2].1);
+        assert_eq!(f64::INFINITY, samples[3].v);
+
+        let openmetrics = r#"# TYPE foo counter
+foo_total{a="b"} 17.0 1520879607.789 # {trace_id="KOO5S4vxi0o"} 0.67
+foo_created{a="b"} 1520430000.123 1520879607.789
+# EOF
+"#;
+        let samples = parse(openmetrics).unwrap();
+        assert_eq!(2, samples.len());
+        assert_eq!((1520879607789, 17.0), (samples[0].t, samples[0].v));
+
+        assert!(parse("up 1\n").is_err());
+        assert!(parse("up{a=\"b} 1 1000\n").is_err());
+        assert!(parse("up 1 1.5\n").is_err());
+    }
+
+    #[test]
+    fn backfill_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut input = String::new();
+        // 5h of samples, the newest first
+        for i in (0..1200).rev() {
+            input.push_str(&format!("up{{job=\"a\"}} {} {}\n", i, i * 15));
+        }
+        input.push_str("# EOF\n");
+
+        let metas = create_blocks(dir.path(), parse(&input).unwrap(), DEFAULT_CHUNK_RANGE).unwrap();
+        assert_eq!(3, metas.len());
+        assert_eq!(
+            (DEFAULT_CHUNK_RANGE, 2 * DEFAULT_CHUNK_RANGE),
+            (metas[1].min_time, metas[1].max_time)
+        );
+        assert_eq!(480, metas[1].stats.num_samples);
+
+        let querier = BlockQuerier::open(&dir.path().join(metas[2].ulid.to_string())).unwrap();
+        let job = Matcher::new(MatchType::Equal, "job", "a").unwrap();
+        let set = querier.select(&[job], 0, i64::MAX).unwrap();
+        let samples: Vec<(i64, f64)> = set.into_iter().next().unwrap().collect();
+        assert_eq!(240, samples.len());
+        assert_eq!((2 * DEFAULT_CHUNK_RANGE, 960.0), samples[0]);
+    }
+}
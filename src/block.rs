@@ -0,0 +1,482 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{create_dir_all, remove_dir_all, rename},
+    iter::once,
+    path::{Path, PathBuf},
+};
+
+use crate::chunkenc::{
+    cut_chunks, decode_xor_chunk, num_samples, DEFAULT_CHUNK_RANGE, ENCODING_AGGR, ENCODING_XOR,
+};
+use crate::chunks::{ChunkReader, ChunkWriter};
+use crate::common::*;
+use crate::index::{series, symbol_table, ChunkMeta, Index, IndexWriter};
+use crate::meta::{BlockCompaction, BlockStats, MetaData, ThanosMeta};
+use crate::tombstones::{read_tombstones, write_tombstones};
+use crate::ulid::Ulid;
+
+const META_VERSION: u8 = 1;
+const TMP_SUFFIX: &str = ".tmp-for-creation";
+const META_FILENAME: &str = "meta.json";
+const INDEX_FILENAME: &str = "index";
+const CHUNKS_DIR: &str = "chunks";
+const TOMBSTONES_FILENAME: &str = "tombstones";
+
+// decode the samples of an XOR chunk
+fn decode_chunk(encoding: u8, data: &[u8]) -> Result<Vec<(i64, f64)>> {
+    if encoding == ENCODING_AGGR {
+        println!("Aggregate chunks of downsampled blocks can not be merged or cut.");
+        return Err(TSDBError::Default);
+    }
+    if encoding != ENCODING_XOR {
+        println!("Unsupported chunk encoding {}.", encoding);
+        return Err(TSDBError::Default);
+    }
+    decode_xor_chunk(data)
+}
+
+// A series of a block with its labels and chunks resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSeries {
+    pub series_ref: u64,
+    pub labels: Labels,
+    pub chunks: Vec<ChunkMeta>,
+}
+
+// Reads the series, chunks and tombstones of a block directory.
+#[derive(Debug)]
+pub struct BlockReader {
+    pub meta: MetaData,
+    index: Index,
+    chunks: ChunkReader,
+    // deleted intervals by series reference, both ends are inclusive
+    tombstones: HashMap<u64, Vec<(i64, i64)>>,
+}
+
+impl BlockReader {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let meta = MetaData::load(&dir.join(META_FILENAME))?;
+        let index = Index::open(&dir.join(INDEX_FILENAME))?;
+        let chunks = ChunkReader::open(&dir.join(CHUNKS_DIR))?;
+
+        let mut tombstones = HashMap::<u64, Vec<(i64, i64)>>::new();
+        let path = dir.join(TOMBSTONES_FILENAME);
+        if path.exists() {
+            for t in read_tombstones(&path)? {
+                tombstones
+                    .entry(t.series_ref)
+                    .or_default()
+                    .push((t.mint, t.maxt));
+            }
+        }
+
+        Ok(Self {
+            meta,
+            index,
+            chunks,
+            tombstones,
+        })
+    }
+
+    // all series of the block sorted by their labels
+    pub fn series(&self) -> Result<Vec<BlockSeries>> {
+        let mut sym = symbol_table(&self.index)?;
+        let mut all = Vec::<BlockSeries>::new();
+        for s in series(&self.index)? {
+            all.push(BlockSeries {
+                series_ref: s.series_ref,
+                labels: s.labels(&mut sym)?,
+                chunks: s.chunk_metas(),
+            });
+        }
+
+        Ok(all)
+    }
+
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    pub fn chunk(&self, chunk_ref: u64) -> Result<(u8, &[u8])> {
+        self.chunks.chunk(chunk_ref)
+    }
+
+    pub fn tombstones(&self, series_ref: u64) -> &[(i64, i64)] {
+        self.tombstones
+            .get(&series_ref)
+            .map_or(&[], |t| t.as_slice())
+    }
+
+    // true if the chunk holds samples deleted by a tombstone
+    pub fn has_deletions(&self, series_ref: u64, chunk: &ChunkMeta) -> bool {
+        self.tombstones(series_ref)
+            .iter()
+            .any(|(mint, maxt)| *mint <= chunk.maxt && chunk.mint <= *maxt)
+    }
+
+    // samples of a chunk without the deleted ones
+    pub fn samples(&self, series_ref: u64, chunk: &ChunkMeta) -> Result<Vec<(i64, f64)>> {
+        let (encoding, data) = self.chunk(chunk.chunk_ref)?;
+        let deleted = self.tombstones(series_ref);
+
+        let mut samples = decode_chunk(encoding, data)?;
+        samples.retain(|(t, _)| !deleted.iter().any(|(mint, maxt)| mint <= t && t <= maxt));
+
+        Ok(samples)
+    }
+}
+
+// An encoded chunk that is written to a block as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawChunk {
+    pub mint: i64,
+    pub maxt: i64,
+    pub encoding: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct SeriesData {
+    samples: Vec<(i64, f64)>,
+    chunks: Vec<RawChunk>,
+}
+
+impl SeriesData {
+    fn is_empty(&self) -> bool {
+        self.samples.is_empty() && self.chunks.is_empty()
+    }
+
+    // Raw chunks overlapping other chunks, added samples or the bounds of the
+    // block are decoded, so their samples are merged and cut into new chunks.
+    // For duplicate timestamps added samples win over the ones of raw chunks.
+    fn prepare(&mut self, time_range: Option<(i64, i64)>) -> Result<()> {
+        self.chunks.sort_by_key(|c| (c.mint, c.maxt));
+        self.samples.sort_by_key(|(t, _)| *t);
+
+        let mut dirty = vec![false; self.chunks.len()];
+        for i in 0..self.chunks.len() {
+            let c = &self.chunks[i];
+            if let Some((mint, maxt)) = time_range {
+                dirty[i] |= c.mint < mint || c.maxt >= maxt;
+            }
+            for j in i + 1..self.chunks.len() {
+                if self.chunks[j].mint > c.maxt {
+                    break;
+                }
+                dirty[i] = true;
+                dirty[j] = true;
+            }
+            let n = self.samples.partition_point(|(t, _)| *t < c.mint);
+            dirty[i] |= n < self.samples.len() && self.samples[n].0 <= c.maxt;
+        }
+
+        let mut decoded = Vec::<(i64, f64)>::new();
+        let mut clean = Vec::<RawChunk>::with_capacity(self.chunks.len());
+        for (c, d) in self.chunks.drain(..).zip(dirty) {
+            if d {
+                decoded.extend(decode_chunk(c.encoding, &c.data)?);
+            } else {
+                clean.push(c);
+            }
+        }
+        self.chunks = clean;
+
+        // the sort is stable, so the sample added first is kept
+        self.samples.extend(decoded);
+        self.samples.sort_by_key(|(t, _)| *t);
+        self.samples.dedup_by_key(|(t, _)| *t);
+        if let Some((mint, maxt)) = time_range {
+            self.samples.retain(|(t, _)| mint <= *t && *t < maxt);
+        }
+
+        Ok(())
+    }
+}
+
+// NOTE: Layout of a block directory:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/README.md
+//
+// <ulid>
+// ├── chunks
+// │   └── 000001
+// ├── index
+// ├── meta.json
+// └── tombstones
+//
+// The block is written to a temporary directory first and renamed once it is
+// complete, so Prometheus never loads a partially written block.
+#[derive(Debug)]
+pub struct BlockWriter {
+    dir: PathBuf,
+    series: BTreeMap<Labels, SeriesData>,
+    // min time is inclusive and max time exclusive
+    time_range: Option<(i64, i64)>,
+    compaction: Option<BlockCompaction>,
+    thanos: Option<ThanosMeta>,
+}
+
+impl BlockWriter {
+    // blocks are written as subdirectories of dir
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            series: BTreeMap::new(),
+            time_range: None,
+            compaction: None,
+            thanos: None,
+        }
+    }
+
+    // samples of series with the same labels are merged, for duplicate
+    // timestamps the sample added first is kept
+    pub fn add_series(&mut self, mut labels: Labels, samples: &[(i64, f64)]) {
+        labels.sort();
+        self.series
+            .entry(labels)
+            .or_default()
+            .samples
+            .extend_from_slice(samples);
+    }
+
+    // add an encoded chunk of a series, it is copied unless it overlaps
+    // other data of the series
+    pub fn add_chunk(&mut self, mut labels: Labels, chunk: RawChunk) {
+        labels.sort();
+        self.series.entry(labels).or_default().chunks.push(chunk);
+    }
+
+    // By default the block covers the time range of its samples. Samples
+    // outside of a set time range are dropped.
+    pub fn set_time_range(&mut self, mint: i64, maxt: i64) {
+        self.time_range = Some((mint, maxt));
+    }
+
+    // defaults to level 1 with the new block as only source
+    pub fn set_compaction(&mut self, compaction: BlockCompaction) {
+        self.compaction = Some(compaction);
+    }
+
+    pub fn set_thanos(&mut self, thanos: ThanosMeta) {
+        self.thanos = Some(thanos);
+    }
+
+    pub fn write(mut self) -> Result<MetaData> {
+        let ulid = Ulid::new();
+        let tmp = self.dir.join(format!("{}{}", ulid, TMP_SUFFIX));
+        let block = self.dir.join(ulid.to_string());
+
+        for data in self.series.values_mut() {
+            data.prepare(self.time_range)?;
+        }
+        self.series.retain(|_, data| !data.is_empty());
+
+        if self.series.is_empty() {
+            println!("No samples to write.");
+            return Err(TSDBError::Default);
+        }
+
+        create_dir_all(&tmp)?;
+        let meta = match self.write_block(&tmp, ulid) {
+            Ok(meta) => meta,
+            Err(e) => {
+                remove_dir_all(&tmp)?;
+                return Err(e);
+            }
+        };
+        rename(&tmp, &block)?;
+
+        Ok(meta)
+    }
+
+    fn write_block(&self, dir: &Path, ulid: Ulid) -> Result<MetaData> {
+        let mut chunk_writer = ChunkWriter::new(&dir.join(CHUNKS_DIR))?;
+        let mut index_writer = IndexWriter::new();
+        let mut stats = BlockStats::default();
+        let mut min_time = i64::MAX;
+        let mut max_time = i64::MIN;
+
+        let mut symbols = BTreeSet::<&str>::new();
+        for labels in self.series.keys() {
+            for (name, value) in labels {
+                symbols.insert(name);
+                symbols.insert(value);
+            }
+        }
+        for s in symbols {
+            index_writer.add_symbol(s)?;
+        }
+
+        for (labels, data) in self.series.iter() {
+            let mut chunk_metas = Vec::<ChunkMeta>::new();
+            let mut samples = &data.samples[..];
+
+            // samples before the next raw chunk are cut into new chunks
+            for raw in data.chunks.iter().map(Some).chain(once(None)) {
+                let n = match raw {
+                    Some(c) => samples.partition_point(|(t, _)| *t < c.mint),
+                    None => samples.len(),
+                };
+                for chunk in cut_chunks(&samples[..n], DEFAULT_CHUNK_RANGE) {
+                    let chunk_ref = chunk_writer.write_chunk(ENCODING_XOR, chunk.bytes())?;
+
+                    chunk_metas.push(ChunkMeta {
+                        mint: chunk.min_time(),
+                        maxt: chunk.max_time(),
+                        chunk_ref,
+                    });
+                    stats.num_samples += chunk.num_samples() as u64;
+                }
+                samples = &samples[n..];
+
+                if let Some(c) = raw {
+                    let chunk_ref = chunk_writer.write_chunk(c.encoding, &c.data)?;
+
+                    chunk_metas.push(ChunkMeta {
+                        mint: c.mint,
+                        maxt: c.maxt,
+                        chunk_ref,
+                    });
+                    stats.num_samples += num_samples(c.encoding, &c.data)?;
+                }
+            }
+
+            index_writer.add_series(labels, &chunk_metas)?;
+
+            min_time = min_time.min(chunk_metas[0].mint);
+            max_time = max_time.max(chunk_metas[chunk_metas.len() - 1].maxt);
+            stats.num_series += 1;
+            stats.num_chunks += chunk_metas.len() as u64;
+        }
+
+        chunk_writer.close()?;
+        index_writer.write(&dir.join(INDEX_FILENAME))?;
+        write_tombstones(&dir.join(TOMBSTONES_FILENAME), &[])?;
+
+        let mut compaction = self.compaction.clone().unwrap_or(BlockCompaction {
+            level: 1,
+            ..Default::default()
+        });
+        if compaction.sources.is_empty() {
+            compaction.sources.push(ulid);
+        }
+
+        // the max time of a block is exclusive
+        let (min_time, max_time) = self.time_range.unwrap_or((min_time, max_time + 1));
+
+        let meta = MetaData {
+            version: META_VERSION,
+            ulid,
+            min_time,
+            max_time,
+            stats,
+            compaction,
+            thanos: self.thanos.clone(),
+            ..Default::default()
+        };
+        meta.write(&dir.join(META_FILENAME))?;
+
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunks::Chunks;
+    use crate::datadir::DataDir;
+    use crate::index::{series, Index};
+
+    #[test]
+    fn write_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = BlockWriter::new(dir.path());
+
+        let samples: Vec<(i64, f64)> = (0..250).map(|i| (i * 15000, i as f64)).collect();
+        writer.add_series(
+            vec![
+                (String::from("job"), String::from("a")),
+                (String::from("__name__"), String::from("up")),
+            ],
+            &samples,
+        );
+        writer.add_series(
+            vec![(String::from("__name__"), String::from("up"))],
+            &samples[..10],
+        );
+        let meta = writer.write().unwrap();
+
+        assert_eq!(0, meta.min_time);
+        assert_eq!(249 * 15000 + 1, meta.max_time);
+        assert_eq!(2, meta.stats.num_series);
+        assert_eq!(4, meta.stats.num_chunks);
+        assert_eq!(260, meta.stats.num_samples);
+
+        let data_dir = DataDir::open(dir.path()).unwrap();
+        assert_eq!(1, data_dir.blocks.len());
+        assert_eq!(meta, data_dir.blocks[0].meta);
+
+        let block = dir.path().join(meta.ulid.to_string());
+        let index = Index::new(&block.join("index"));
+        let chunks: Vec<usize> = series(&index).unwrap().map(|s| s.chunks.len()).collect();
+        assert_eq!(vec![1, 3], chunks);
+        assert_eq!(4, Chunks::new(&block.join("chunks/000001")).count());
+    }
+
+    #[test]
+    fn copy_raw_chunks() {
+        let src = tempfile::tempdir().unwrap();
+        let up = vec![(String::from("__name__"), String::from("up"))];
+        let samples: Vec<(i64, f64)> = (0..250).map(|i| (i * 15000, i as f64)).collect();
+
+        let mut writer = BlockWriter::new(src.path());
+        writer.add_series(up.clone(), &samples);
+        let meta = writer.write().unwrap();
+        let reader = BlockReader::open(&src.path().join(meta.ulid.to_string())).unwrap();
+
+        let s = reader.series().unwrap();
+        assert_eq!(1, s.len());
+        assert_eq!(up, s[0].labels);
+        assert_eq!(3, s[0].chunks.len());
+
+        let dst = tempfile::tempdir().unwrap();
+        let mut writer = BlockWriter::new(dst.path());
+        for c in s[0].chunks.iter() {
+            let (encoding, data) = reader.chunk(c.chunk_ref).unwrap();
+            writer.add_chunk(
+                up.clone(),
+                RawChunk {
+                    mint: c.mint,
+                    maxt: c.maxt,
+                    encoding,
+                    data: data.to_vec(),
+                },
+            );
+        }
+        // overlaps the last chunk, which gets re-encoded
+        writer.add_series(up.clone(), &[(249 * 15000, -1.0), (250 * 15000, 250.0)]);
+        writer.set_time_range(0, 250 * 15000);
+        let copied = writer.write().unwrap();
+
+        assert_eq!(250, copied.stats.num_samples);
+        assert_eq!((0, 250 * 15000), (copied.min_time, copied.max_time));
+
+        let copy = BlockReader::open(&dst.path().join(copied.ulid.to_string())).unwrap();
+        let series = copy.series().unwrap();
+        let chunks = &series[0].chunks;
+        assert_eq!(3, chunks.len());
+        for (a, b) in s[0].chunks.iter().zip(chunks).take(2) {
+            assert_eq!(
+                reader.chunk(a.chunk_ref).unwrap(),
+                copy.chunk(b.chunk_ref).unwrap()
+            );
+        }
+
+        let mut expected = samples.clone();
+        expected[249].1 = -1.0;
+        let mut read = Vec::<(i64, f64)>::new();
+        for c in chunks {
+            read.extend(copy.samples(series[0].series_ref, c).unwrap());
+        }
+        assert_eq!(expected, read);
+    }
+}
@@ -0,0 +1,676 @@
+use unsigned_varint::encode;
+
+use crate::common::*;
+
+pub const ENCODING_XOR: u8 = 1;
+// Thanos picked the highest value to avoid collisions with Prometheus
+pub const ENCODING_AGGR: u8 = 0xff;
+pub const SAMPLES_PER_CHUNK: usize = 120;
+// default range of a head chunk and block, 2h
+pub const DEFAULT_CHUNK_RANGE: i64 = 2 * 60 * 60 * 1000;
+const NUM_SAMPLES_SIZE: usize = 2;
+
+// NOTE: Format of XOR chunks:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/chunks.md#xor-chunk-data
+//
+// ┌──────────────────────┬───────────────┬───────────────┬──────────────────────┬──────────────────────┬─────┐
+// │ num_samples <uint16> │ ts_0 <varint> │ v_0 <float64> │ ts_1_delta <uvarint> │ v_1_xor <varbit_xor> │ ... │
+// └──────────────────────┴───────────────┴───────────────┴──────────────────────┴──────────────────────┴─────┘
+//
+// All following timestamps are encoded as delta of deltas and values as XOR to
+// the previous value.
+#[derive(Debug, Clone)]
+pub struct XorChunk {
+    stream: BitWriter,
+    num_samples: u16,
+    min_t: i64,
+    t: i64,
+    v: f64,
+    t_delta: u64,
+    leading: u8,
+    trailing: u8,
+}
+
+impl Default for XorChunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl XorChunk {
+    pub fn new() -> Self {
+        Self {
+            // the first two bytes hold the number of samples
+            stream: BitWriter {
+                buf: vec![0, 0],
+                count: 0,
+            },
+            num_samples: 0,
+            min_t: 0,
+            t: 0,
+            v: 0.0,
+            t_delta: 0,
+            leading: 0xff,
+            trailing: 0,
+        }
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.num_samples as usize
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.stream.buf
+    }
+
+    pub fn min_time(&self) -> i64 {
+        self.min_t
+    }
+
+    pub fn max_time(&self) -> i64 {
+        self.t
+    }
+
+    pub fn last(&self) -> Option<(i64, f64)> {
+        (self.num_samples > 0).then_some((self.t, self.v))
+    }
+
+    pub fn append(&mut self, t: i64, v: f64) {
+        let mut t_delta = 0;
+
+        match self.num_samples {
+            0 => {
+                let mut buf = Vec::<u8>::new();
+                write_varint_i64(&mut buf, t);
+                for b in buf {
+                    self.stream.write_byte(b);
+                }
+                self.stream.write_bits(v.to_bits(), 64);
+                self.min_t = t;
+            }
+            1 => {
+                t_delta = (t - self.t) as u64;
+                for b in encode::u64(t_delta, &mut encode::u64_buffer()) {
+                    self.stream.write_byte(*b);
+                }
+                self.write_value(v);
+            }
+            _ => {
+                t_delta = (t - self.t) as u64;
+                let dod = t_delta.wrapping_sub(self.t_delta) as i64;
+
+                // Prometheus uses millisecond timestamps, so the bit ranges are
+                // larger than the ones in the Gorilla paper
+                match dod {
+                    0 => self.stream.write_bit(false),
+                    _ if bit_range(dod, 14) => {
+                        self.stream.write_bits(0b10, 2);
+                        self.stream.write_bits(dod as u64, 14);
+                    }
+                    _ if bit_range(dod, 17) => {
+                        self.stream.write_bits(0b110, 3);
+                        self.stream.write_bits(dod as u64, 17);
+                    }
+                    _ if bit_range(dod, 20) => {
+                        self.stream.write_bits(0b1110, 4);
+                        self.stream.write_bits(dod as u64, 20);
+                    }
+                    _ => {
+                        self.stream.write_bits(0b1111, 4);
+                        self.stream.write_bits(dod as u64, 64);
+                    }
+                }
+                self.write_value(v);
+            }
+        }
+
+        self.t = t;
+        self.v = v;
+        self.t_delta = t_delta;
+        self.num_samples += 1;
+        self.stream.buf[..2].copy_from_slice(&self.num_samples.to_be_bytes());
+    }
+
+    fn write_value(&mut self, v: f64) {
+        let delta = v.to_bits() ^ self.v.to_bits();
+        if delta == 0 {
+            self.stream.write_bit(false);
+            return;
+        }
+        self.stream.write_bit(true);
+
+        // the number of leading zeros is stored in 5 bits
+        let leading = (delta.leading_zeros() as u8).min(31);
+        let trailing = delta.trailing_zeros() as u8;
+
+        // reuse the previous window of meaningful bits if the delta fits
+        if self.leading != 0xff && leading >= self.leading && trailing >= self.trailing {
+            self.stream.write_bit(false);
+            self.stream.write_bits(
+                delta >> self.trailing,
+                64 - self.leading as usize - self.trailing as usize,
+            );
+            return;
+        }
+
+        self.leading = leading;
+        self.trailing = trailing;
+
+        // 64 significant bits do not fit into 6 bits and are written as 0,
+        // 0 significant bits can not happen as the delta would be 0
+        let sigbits = 64 - leading - trailing;
+        self.stream.write_bit(true);
+        self.stream.write_bits(leading as u64, 5);
+        self.stream.write_bits(sigbits as u64, 6);
+        self.stream.write_bits(delta >> trailing, sigbits as usize);
+    }
+}
+
+fn bit_range(x: i64, nbits: u8) -> bool {
+    -((1 << (nbits - 1)) - 1) <= x && x <= 1 << (nbits - 1)
+}
+
+// end of the chunk range a timestamp falls into
+fn range_for_timestamp(t: i64, width: i64) -> i64 {
+    (t / width) * width + width
+}
+
+// predict the end time of a chunk so that the samples are distributed equally
+// over the remaining chunks of the range
+fn compute_chunk_end_time(start: i64, cur: i64, max: i64, ratio_to_full: f64) -> i64 {
+    let n = (max - start) as f64 / ((cur - start + 1) as f64 * ratio_to_full);
+    if n <= 1.0 {
+        return max;
+    }
+    (start as f64 + (max - start) as f64 / n.floor()) as i64
+}
+
+// Encode sorted samples into chunks the way the Prometheus head cuts them. A
+// chunk is cut at the end of each chunk range, aiming for 120 samples per
+// chunk.
+pub fn cut_chunks(samples: &[(i64, f64)], chunk_range: i64) -> Vec<XorChunk> {
+    let mut chunks = Vec::<XorChunk>::new();
+    let mut next_at = 0;
+
+    for (t, v) in samples {
+        append_sample(&mut chunks, &mut next_at, *t, *v, chunk_range);
+    }
+
+    chunks
+}
+
+// Append a sample after the last chunk, cutting a new chunk if needed. next_at
+// is the predicted end time of the last chunk and updated on the way.
+pub fn append_sample(
+    chunks: &mut Vec<XorChunk>,
+    next_at: &mut i64,
+    t: i64,
+    v: f64,
+    chunk_range: i64,
+) {
+    let cut = match chunks.last() {
+        None => true,
+        Some(c) => {
+            // once a chunk is a quarter full the end time can be predicted
+            if c.num_samples() == SAMPLES_PER_CHUNK / 4 {
+                *next_at = compute_chunk_end_time(c.min_time(), c.max_time(), *next_at, 4.0);
+            }
+            t >= *next_at || c.num_samples() >= SAMPLES_PER_CHUNK * 2
+        }
+    };
+
+    if cut {
+        chunks.push(XorChunk::new());
+        *next_at = range_for_timestamp(t, chunk_range);
+    }
+    if let Some(c) = chunks.last_mut() {
+        c.append(t, v);
+    }
+}
+
+#[derive(Debug)]
+pub struct XorIterator<'a> {
+    stream: BitReader<'a>,
+    num_total: u16,
+    num_read: u16,
+    t: i64,
+    v: f64,
+    t_delta: u64,
+    leading: u8,
+    trailing: u8,
+}
+
+impl<'a> XorIterator<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        if buf.len() < NUM_SAMPLES_SIZE {
+            return Err(TSDBError::Default);
+        }
+
+        Ok(Self {
+            stream: BitReader {
+                buf,
+                pos: NUM_SAMPLES_SIZE * 8,
+            },
+            num_total: read_u16(buf, 0)?,
+            num_read: 0,
+            t: 0,
+            v: 0.0,
+            t_delta: 0,
+            leading: 0,
+            trailing: 0,
+        })
+    }
+
+    fn read(&mut self) -> Option<(i64, f64)> {
+        match self.num_read {
+            0 => {
+                self.t = self.stream.read_varint_i64()?;
+                self.v = f64::from_bits(self.stream.read_bits(64)?);
+            }
+            1 => {
+                self.t_delta = self.stream.read_varint_u64()?;
+                self.t = self.t.checked_add(self.t_delta as i64)?;
+                self.read_value()?;
+            }
+            _ => {
+                // the prefix of up to 4 bits determines the size of the delta
+                // of deltas
+                let mut d = 0u8;
+                for _ in 0..4 {
+                    d <<= 1;
+                    if !self.stream.read_bit()? {
+                        break;
+                    }
+                    d |= 1;
+                }
+
+                let dod = match d {
+                    0b0 => 0,
+                    0b10 => self.read_dod(14)?,
+                    0b110 => self.read_dod(17)?,
+                    0b1110 => self.read_dod(20)?,
+                    _ => self.stream.read_bits(64)? as i64,
+                };
+
+                // timestamps of corrupted chunks can overflow
+                self.t_delta = (self.t_delta as i64).checked_add(dod)? as u64;
+                self.t = self.t.checked_add(self.t_delta as i64)?;
+                self.read_value()?;
+            }
+        }
+        self.num_read += 1;
+
+        Some((self.t, self.v))
+    }
+
+    fn read_dod(&mut self, size: u32) -> Option<i64> {
+        let mut bits = self.stream.read_bits(size as usize)?;
+        // negative numbers come back as high unsigned numbers
+        if bits > 1 << (size - 1) {
+            bits = bits.wrapping_sub(1 << size);
+        }
+        Some(bits as i64)
+    }
+
+    fn read_value(&mut self) -> Option<()> {
+        if !self.stream.read_bit()? {
+            return Some(());
+        }
+
+        if self.stream.read_bit()? {
+            self.leading = self.stream.read_bits(5)? as u8;
+            let mut sigbits = self.stream.read_bits(6)? as u8;
+            // 64 significant bits are written as 0
+            if sigbits == 0 {
+                sigbits = 64;
+            }
+            self.trailing = 64u8.checked_sub(self.leading + sigbits)?;
+        }
+
+        let sigbits = 64 - self.leading - self.trailing;
+        let bits = self.stream.read_bits(sigbits as usize)?;
+        self.v = f64::from_bits(self.v.to_bits() ^ bits << self.trailing);
+
+        Some(())
+    }
+}
+
+impl Iterator for XorIterator<'_> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.num_read >= self.num_total {
+            return None;
+        }
+        // a truncated or corrupted chunk ends the samples for good,
+        // decode_xor_chunk returns it as an error
+        let sample = self.read();
+        if sample.is_none() {
+            self.num_total = self.num_read;
+        }
+        sample
+    }
+}
+
+// Samples of an XOR chunk, chunks that end before all of their samples are
+// read are corrupted.
+pub fn decode_xor_chunk(buf: &[u8]) -> Result<Vec<(i64, f64)>> {
+    let iter = XorIterator::new(buf)?;
+    let num_total = iter.num_total as usize;
+    let samples: Vec<(i64, f64)> = iter.collect();
+    if samples.len() != num_total {
+        println!(
+            "Corrupted chunk with {} of {} samples.",
+            samples.len(),
+            num_total
+        );
+        return Err(TSDBError::Default);
+    }
+
+    Ok(samples)
+}
+
+// Aggregates of downsampled data in the order they are stored in aggregate
+// chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggrType {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Counter,
+}
+
+pub const NUM_AGGR_TYPES: usize = 5;
+
+// NOTE: Format of aggregate chunks of Thanos:
+// https://github.com/thanos-io/thanos/blob/main/pkg/compact/downsample/aggr.go
+//
+// ┌───────────────┬─────────────────┬──────────────┐
+// │ len <uvarint> │ encoding <1b>   │ data <bytes> │ count
+// ├───────────────┼─────────────────┼──────────────┤
+// │                      . . .                     │ sum, min, max
+// ├───────────────┼─────────────────┼──────────────┤
+// │ len <uvarint> │ encoding <1b>   │ data <bytes> │ counter
+// └───────────────┴─────────────────┴──────────────┘
+//
+// Aggregates that are not set only have a length of 0.
+pub fn encode_aggr_chunk(chunks: &[Option<XorChunk>; NUM_AGGR_TYPES]) -> Vec<u8> {
+    let mut buf = Vec::<u8>::new();
+    for c in chunks {
+        match c {
+            Some(c) => {
+                write_varint_u64(&mut buf, c.bytes().len() as u64);
+                buf.push(ENCODING_XOR);
+                buf.extend_from_slice(c.bytes());
+            }
+            None => write_varint_u64(&mut buf, 0),
+        }
+    }
+    buf
+}
+
+// data of the XOR chunks of the aggregates that are set
+pub fn decode_aggr_chunk(buf: &[u8]) -> Result<[Option<&[u8]>; NUM_AGGR_TYPES]> {
+    let mut chunks = [None; NUM_AGGR_TYPES];
+    let mut pos = 0;
+
+    for c in chunks.iter_mut() {
+        let (len, size) = read_varint_u64(buf, pos)?;
+        if size == 0 {
+            return Err(TSDBError::Default);
+        }
+        pos += size;
+        if len == 0 {
+            continue;
+        }
+
+        let end = pos + 1 + len as usize;
+        if buf.len() < end || buf[pos] != ENCODING_XOR {
+            return Err(TSDBError::Default);
+        }
+        *c = Some(&buf[pos + 1..end]);
+        pos = end;
+    }
+
+    Ok(chunks)
+}
+
+// Number of samples of an encoded chunk. Like Thanos, aggregate chunks count
+// the samples of their count aggregate.
+pub fn num_samples(encoding: u8, buf: &[u8]) -> Result<u64> {
+    match encoding {
+        ENCODING_AGGR => match decode_aggr_chunk(buf)?[AggrType::Count as usize] {
+            Some(c) => Ok(read_u16(c, 0)? as u64),
+            None => Ok(0),
+        },
+        // all Prometheus encodings start with the number of samples
+        _ => Ok(read_u16(buf, 0)? as u64),
+    }
+}
+
+// Writes bits from the most significant one on. This mirrors the bstream of
+// Prometheus byte by byte, a byte write always leaves an empty byte at the end
+// of the stream.
+#[derive(Debug, Clone)]
+struct BitWriter {
+    buf: Vec<u8>,
+    // number of bits left in the last byte
+    count: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.count == 0 {
+            self.buf.push(0);
+            self.count = 8;
+        }
+        if bit {
+            let i = self.buf.len() - 1;
+            self.buf[i] |= 1 << (self.count - 1);
+        }
+        self.count -= 1;
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if self.count == 0 {
+            self.buf.push(0);
+            self.count = 8;
+        }
+        let i = self.buf.len() - 1;
+        // complete the last byte with the leftmost bits and write the rest to
+        // a new one
+        self.buf[i] |= byte.checked_shr(8 - self.count as u32).unwrap_or(0);
+        self.buf
+            .push(byte.checked_shl(self.count as u32).unwrap_or(0));
+    }
+
+    fn write_bits(&mut self, u: u64, nbits: usize) {
+        let mut u = u.checked_shl(64 - nbits as u32).unwrap_or(0);
+        let mut nbits = nbits;
+
+        while nbits >= 8 {
+            self.write_byte((u >> 56) as u8);
+            u <<= 8;
+            nbits -= 8;
+        }
+        while nbits > 0 {
+            self.write_bit(u >> 63 == 1);
+            u <<= 1;
+            nbits -= 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BitReader<'a> {
+    buf: &'a [u8],
+    // position in bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.buf.get(self.pos / 8)?;
+        let bit = byte >> (7 - self.pos % 8) & 1 == 1;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, nbits: usize) -> Option<u64> {
+        let mut u = 0u64;
+        for _ in 0..nbits {
+            u = u << 1 | self.read_bit()? as u64;
+        }
+        Some(u)
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        Some(self.read_bits(8)? as u8)
+    }
+
+    fn read_varint_u64(&mut self) -> Option<u64> {
+        let mut u = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.read_byte()?;
+            u |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Some(u);
+            }
+        }
+        None
+    }
+
+    fn read_varint_i64(&mut self) -> Option<i64> {
+        let u = self.read_varint_u64()?;
+        Some((u >> 1) as i64 ^ -((u & 1) as i64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunks::Chunks;
+    use std::path::Path;
+
+    fn encode(samples: &[(i64, f64)]) -> XorChunk {
+        let mut chunk = XorChunk::new();
+        for (t, v) in samples {
+            chunk.append(*t, *v);
+        }
+        chunk
+    }
+
+    #[test]
+    fn encode_single_sample() {
+        // first chunk of testdata/index_format_v1/chunks/000001
+        let mut chunk = XorChunk::new();
+        chunk.append(0, 0.0);
+
+        assert_eq!(&[0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], chunk.bytes());
+    }
+
+    #[test]
+    fn round_trip_samples() {
+        // regular, jittered and large timestamp deltas with changing values
+        let mut samples = Vec::<(i64, f64)>::new();
+        let mut t = -1000;
+        for i in 0..120i64 {
+            t += match i % 7 {
+                0 => 15000,
+                1 => 15003,
+                2 => 14990,
+                3 => 80000,
+                4 => 600000,
+                5 => 1 << 40,
+                _ => 1,
+            };
+            let v = match i % 5 {
+                0 => i as f64,
+                1 => -0.5 * i as f64,
+                2 => f64::MAX,
+                3 => f64::NAN,
+                _ => 1.0 / 3.0,
+            };
+            samples.push((t, v));
+        }
+
+        let chunk = encode(&samples);
+        let decoded: Vec<(i64, f64)> = XorIterator::new(chunk.bytes()).unwrap().collect();
+
+        assert_eq!(samples.len(), decoded.len());
+        for (a, b) in samples.iter().zip(decoded.iter()) {
+            assert_eq!(a.0, b.0);
+            assert_eq!(a.1.to_bits(), b.1.to_bits());
+        }
+        assert_eq!(samples[0].0, chunk.min_time());
+        assert_eq!(t, chunk.max_time());
+
+        // a truncated chunk misses samples
+        let bytes = chunk.bytes();
+        assert_eq!(120, decode_xor_chunk(bytes).unwrap().len());
+        assert!(decode_xor_chunk(&bytes[..bytes.len() / 2]).is_err());
+
+        // a delta overflowing the first timestamp ends the samples early
+        let mut overflow = vec![0, 2];
+        write_varint_i64(&mut overflow, i64::MAX);
+        overflow.extend_from_slice(&[0; 8]);
+        write_varint_u64(&mut overflow, 1);
+        overflow.push(0);
+        assert_eq!(1, XorIterator::new(&overflow).unwrap().count());
+        assert!(decode_xor_chunk(&overflow).is_err());
+    }
+
+    #[test]
+    fn round_trip_test_chunks() {
+        let path = Path::new("testdata/index_format_v1/chunks/000001");
+        let chunks = Chunks::new(path);
+        let data = Chunks::new(path);
+
+        let mut count = 0;
+        for pos in chunks {
+            let (encoding, bytes) = data.chunk(pos).unwrap();
+            assert_eq!(ENCODING_XOR, encoding);
+
+            let samples: Vec<(i64, f64)> = XorIterator::new(bytes).unwrap().collect();
+            assert_eq!(bytes, encode(&samples).bytes());
+            count += 1;
+        }
+        assert_eq!(102, count);
+    }
+
+    #[test]
+    fn cut_chunks_per_range() {
+        // 15s scrape interval over 4h in two 2h chunk ranges
+        let samples: Vec<(i64, f64)> = (0..960).map(|i| (i * 15000, i as f64)).collect();
+        let chunks = cut_chunks(&samples, DEFAULT_CHUNK_RANGE);
+
+        assert_eq!(8, chunks.len());
+        assert!(chunks.iter().all(|c| c.num_samples() == SAMPLES_PER_CHUNK));
+        assert_eq!(DEFAULT_CHUNK_RANGE - 15000, chunks[3].max_time());
+        assert_eq!(DEFAULT_CHUNK_RANGE, chunks[4].min_time());
+
+        // if the sample rate increases after the end time was predicted the
+        // chunk is cut at twice the samples per chunk
+        let mut samples: Vec<(i64, f64)> = (0..30).map(|i| (i * 60000, 0.0)).collect();
+        samples.extend((0..1000).map(|i| (1800000 + i, 0.0)));
+        let chunks = cut_chunks(&samples, DEFAULT_CHUNK_RANGE);
+        assert_eq!(240, chunks[0].num_samples());
+    }
+
+    #[test]
+    fn round_trip_aggr_chunk() {
+        let count = encode(&[(299999, 20.0), (599999, 20.0)]);
+        let max = encode(&[(299999, 7.5), (599999, 9.0)]);
+        let buf = encode_aggr_chunk(&[Some(count.clone()), None, None, Some(max.clone()), None]);
+
+        let chunks = decode_aggr_chunk(&buf).unwrap();
+        assert_eq!(Some(count.bytes()), chunks[AggrType::Count as usize]);
+        assert_eq!(None, chunks[AggrType::Sum as usize]);
+        assert_eq!(Some(max.bytes()), chunks[AggrType::Max as usize]);
+        assert_eq!(2, num_samples(ENCODING_AGGR, &buf).unwrap());
+        assert_eq!(2, num_samples(ENCODING_XOR, max.bytes()).unwrap());
+
+        assert!(decode_aggr_chunk(&buf[..buf.len() - 1]).is_err());
+    }
+}
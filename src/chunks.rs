@@ -1,5 +1,10 @@
 use crc::{Crc, CRC_32_ISCSI};
-use std::{fs::File, io::Read, path::Path};
+use memmap::Mmap;
+use std::{
+    fs::{create_dir_all, read_dir, write, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use crate::common::*;
 
@@ -8,6 +13,10 @@ const ENCODING_SIZE: usize = 1;
 const CHECKSUM_SIZE: usize = 4;
 const MAGIC_SIZE: usize = 4;
 const VERSION_SIZE: usize = 1;
+const MAGIC_CHUNKS: u32 = 0x85BD40DD;
+const CHUNKS_FORMAT_V1: u8 = 1;
+const SEGMENT_HEADER_SIZE: usize = 8;
+pub const DEFAULT_SEGMENT_SIZE: usize = 512 * 1024 * 1024;
 
 // NOTE: Format of a chunk file:
 // https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/chunks.md
@@ -37,6 +46,22 @@ impl Chunks {
     }
 }
 
+impl Chunks {
+    // encoding and data of the chunk at a position returned by the iterator
+    pub fn chunk(&self, pos: usize) -> Result<(u8, &[u8])> {
+        let (len, size) = read_varint_u32(&self.buf, pos)?;
+        let start = pos + size;
+        if size == 0 || self.buf.len() < start + ENCODING_SIZE + len as usize {
+            return Err(TSDBError::Default);
+        }
+
+        let encoding = self.buf[start];
+        let data = slice_bytes(&self.buf, len as usize, start + ENCODING_SIZE);
+
+        Ok((encoding, data))
+    }
+}
+
 impl Iterator for Chunks {
     type Item = usize;
 
@@ -81,6 +106,143 @@ impl Iterator for Chunks {
     }
 }
 
+// Reads chunks by their reference from the numbered segment files of a block.
+#[derive(Debug)]
+pub struct ChunkReader {
+    segments: Vec<Mmap>,
+}
+
+impl ChunkReader {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let mut paths = Vec::<(u64, PathBuf)>::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            if let Ok(n) = name.parse::<u64>() {
+                paths.push((n, path));
+            }
+        }
+        paths.sort();
+
+        let mut segments = Vec::<Mmap>::with_capacity(paths.len());
+        for (i, (n, path)) in paths.iter().enumerate() {
+            // references hold the index of the segment, so there can be no gaps
+            if *n != i as u64 + 1 {
+                println!("Missing chunk segment {:06}.", i + 1);
+                return Err(TSDBError::Default);
+            }
+
+            let f = File::open(path)?;
+            let buf = unsafe { Mmap::map(&f)? };
+            if buf.len() < SEGMENT_HEADER_SIZE
+                || read_u32(&buf, 0)? != MAGIC_CHUNKS
+                || buf[MAGIC_SIZE] != CHUNKS_FORMAT_V1
+            {
+                println!("Invalid chunk segment {}.", path.display());
+                return Err(TSDBError::Default);
+            }
+            segments.push(buf);
+        }
+
+        Ok(Self { segments })
+    }
+
+    // encoding and data of a chunk, the checksum is verified
+    pub fn chunk(&self, chunk_ref: u64) -> Result<(u8, &[u8])> {
+        let segment = match self.segments.get((chunk_ref >> 32) as usize) {
+            Some(s) => s,
+            None => return Err(TSDBError::Default),
+        };
+        let pos = (chunk_ref & 0xffff_ffff) as usize;
+
+        let (len, size) = read_varint_u32(segment, pos)?;
+        let start = pos + size;
+        let end = start + ENCODING_SIZE + len as usize;
+        if size == 0 || segment.len() < end + CHECKSUM_SIZE {
+            return Err(TSDBError::Default);
+        }
+
+        if get_checksum(segment, end)? != CASTAGNIOLI.checksum(&segment[start..end]) {
+            return Err(TSDBError::Checksum);
+        }
+
+        Ok((segment[start], &segment[start + ENCODING_SIZE..end]))
+    }
+}
+
+// Writes chunks into numbered segment files. References to chunks hold the
+// index of the segment in the upper and the offset in the lower 4 bytes.
+#[derive(Debug)]
+pub struct ChunkWriter {
+    dir: PathBuf,
+    segment_size: usize,
+    buf: Vec<u8>,
+    // index of the current segment starting at 0 for file 000001
+    seq: u64,
+}
+
+impl ChunkWriter {
+    pub fn new(dir: &Path) -> Result<Self> {
+        Self::with_segment_size(dir, DEFAULT_SEGMENT_SIZE)
+    }
+
+    pub fn with_segment_size(dir: &Path, segment_size: usize) -> Result<Self> {
+        create_dir_all(dir)?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            segment_size,
+            buf: ChunkWriter::segment_header(),
+            seq: 0,
+        })
+    }
+
+    fn segment_header() -> Vec<u8> {
+        let mut buf = Vec::<u8>::with_capacity(SEGMENT_HEADER_SIZE);
+        write_u32(&mut buf, MAGIC_CHUNKS);
+        buf.push(CHUNKS_FORMAT_V1);
+        // padding
+        buf.resize(SEGMENT_HEADER_SIZE, 0);
+        buf
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let path = self.dir.join(format!("{:06}", self.seq + 1));
+        write(path, &self.buf)?;
+        Ok(())
+    }
+
+    pub fn write_chunk(&mut self, encoding: u8, data: &[u8]) -> Result<u64> {
+        let mut chunk = Vec::<u8>::with_capacity(data.len() + 16);
+        write_varint_u64(&mut chunk, data.len() as u64);
+        let start = chunk.len();
+        chunk.push(encoding);
+        chunk.extend_from_slice(data);
+        let crc = CASTAGNIOLI.checksum(&chunk[start..]);
+        write_u32(&mut chunk, crc);
+
+        // cut a new segment if the chunk does not fit into the current one
+        if self.buf.len() > SEGMENT_HEADER_SIZE && self.buf.len() + chunk.len() > self.segment_size
+        {
+            self.flush()?;
+            self.buf = ChunkWriter::segment_header();
+            self.seq += 1;
+        }
+
+        let chunk_ref = self.seq << 32 | self.buf.len() as u64;
+        self.buf.extend_from_slice(&chunk);
+
+        Ok(chunk_ref)
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -97,4 +259,33 @@ mod test {
         let expected = 37020;
         assert_eq!(expected, chunks.count());
     }
+
+    #[test]
+    fn write_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = ChunkWriter::with_segment_size(dir.path(), 64).unwrap();
+
+        let data = [0u8, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let refs: Vec<u64> = (0..5)
+            .map(|_| writer.write_chunk(1, &data).unwrap())
+            .collect();
+        writer.close().unwrap();
+
+        // 18 bytes per chunk, 3 chunks fit into a 64 byte segment
+        assert_eq!(vec![8, 26, 44, 1 << 32 | 8, 1 << 32 | 26], refs);
+
+        // same layout as the chunks Prometheus wrote for the v1 test block
+        let expected = std::fs::read("testdata/index_format_v1/chunks/000001").unwrap();
+        let written = std::fs::read(dir.path().join("000001")).unwrap();
+        assert_eq!(expected[..62], written[..]);
+
+        let chunks = Chunks::new(&dir.path().join("000002"));
+        assert_eq!(2, chunks.count());
+
+        let reader = ChunkReader::open(dir.path()).unwrap();
+        for r in refs {
+            assert_eq!((1, &data[..]), reader.chunk(r).unwrap());
+        }
+        assert!(reader.chunk(2 << 32 | 8).is_err());
+    }
 }
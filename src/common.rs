@@ -1,14 +1,31 @@
 use std::mem::size_of;
-use unsigned_varint::decode;
+use unsigned_varint::{decode, encode};
 
 #[derive(Debug, Clone)]
 pub enum TSDBError {
     Default,
     SymbolTableLookup,
+    Io,
+    Checksum,
+    InvalidMeta,
+    InvalidUlid,
+    InvalidMatcher,
+    InvalidQuery,
+    OutOfOrderSample,
+    OutOfBounds,
+}
+
+impl From<std::io::Error> for TSDBError {
+    fn from(_: std::io::Error) -> Self {
+        TSDBError::Io
+    }
 }
 
 pub type Result<T> = std::result::Result<T, TSDBError>;
 
+// label pairs of a series sorted by name
+pub type Labels = Vec<(String, String)>;
+
 // pub fn copy_bytes(buf: &[u8], size: usize, pos: usize) -> Vec<u8> {
 //     let mut ret = vec![0; size];
 //     ret[..].copy_from_slice(&buf[pos..pos + size]);
@@ -56,6 +73,7 @@ macro_rules! read {
     };
 }
 
+read!(read_u16, u16);
 read!(read_u32, u32);
 read!(read_u64, u64);
 
@@ -67,6 +85,37 @@ pub fn read_varint_i64(buf: &[u8], pos: usize) -> Result<(i64, usize)> {
 
 // get i64 from zigzag encoded u64
 // see: https://developers.google.com/protocol-buffers/docs/encoding#signed-ints
-fn zigzag_dec(u: u64) -> i64 {
+pub fn zigzag_dec(u: u64) -> i64 {
     (u >> 1) as i64 ^ -((u & 1) as i64)
 }
+
+// get zigzag encoded u64 from i64
+fn zigzag_enc(i: i64) -> u64 {
+    ((i << 1) ^ (i >> 63)) as u64
+}
+
+pub fn write_varint_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(encode::u32(n, &mut encode::u32_buffer()));
+}
+
+pub fn write_varint_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(encode::u64(n, &mut encode::u64_buffer()));
+}
+
+pub fn write_varint_i64(buf: &mut Vec<u8>, n: i64) {
+    write_varint_u64(buf, zigzag_enc(n));
+}
+
+// uvarint length prefixed string
+pub fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_varint_u64(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+pub fn write_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub fn write_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
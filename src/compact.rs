@@ -0,0 +1,590 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use crate::block::{BlockReader, BlockSeries, BlockWriter, RawChunk};
+use crate::common::*;
+use crate::datadir::Block;
+use crate::dedup::{dedup, DedupStrategy};
+use crate::index::ChunkMeta;
+use crate::labels;
+use crate::meta::{BlockCompaction, BlockDesc, GroupKey, MetaData, ThanosMeta};
+use crate::relabel::{relabel, RelabelConfig};
+use crate::ulid::Ulid;
+
+const THANOS_SOURCE_COMPACTOR: &str = "compactor";
+const THANOS_SOURCE_REWRITE: &str = "rewrite";
+
+// group key of a block without the ignored external labels
+fn group_key(meta: &MetaData, ignored: &[&str]) -> GroupKey {
+    let mut key = meta.group_key();
+    key.labels
+        .retain(|name, _| !ignored.contains(&name.as_str()));
+    key
+}
+
+// Sort blocks by time and make sure they can be merged, blocks of different
+// external labels or resolutions can not.
+fn sort_blocks<'a>(blocks: &[&'a Block], ignored: &[&str]) -> Result<Vec<&'a Block>> {
+    let mut blocks = blocks.to_vec();
+    blocks.sort_by_key(|b| (b.meta.min_time, b.meta.max_time, b.meta.ulid));
+
+    let first = match blocks.first() {
+        Some(b) => b,
+        None => {
+            println!("No blocks to compact.");
+            return Err(TSDBError::Default);
+        }
+    };
+
+    let group = group_key(&first.meta, ignored);
+    if let Some(b) = blocks.iter().find(|b| group_key(&b.meta, ignored) != group) {
+        println!(
+            "Block {} can not be compacted with block {}.",
+            b.meta.ulid, first.meta.ulid
+        );
+        return Err(TSDBError::InvalidMeta);
+    }
+
+    Ok(blocks)
+}
+
+// Aggregate chunks can only be copied as they are, so downsampled blocks can
+// not be cut or merged sample by sample.
+fn check_raw(block: &Block, action: &str) -> Result<()> {
+    if block.meta.group_key().resolution > 0 {
+        println!(
+            "Block {} is downsampled and can not be {}.",
+            block.meta.ulid, action
+        );
+        return Err(TSDBError::InvalidMeta);
+    }
+    Ok(())
+}
+
+fn raw_chunk(reader: &BlockReader, c: &ChunkMeta) -> Result<RawChunk> {
+    let (encoding, data) = reader.chunk(c.chunk_ref)?;
+
+    Ok(RawChunk {
+        mint: c.mint,
+        maxt: c.maxt,
+        encoding,
+        data: data.to_vec(),
+    })
+}
+
+// Chunks holding deleted samples are re-encoded, all others are copied.
+fn copy_series(
+    writer: &mut BlockWriter,
+    reader: &BlockReader,
+    labels: &Labels,
+    s: &BlockSeries,
+) -> Result<()> {
+    for c in s.chunks.iter() {
+        if reader.has_deletions(s.series_ref, c) {
+            writer.add_series(labels.clone(), &reader.samples(s.series_ref, c)?);
+        } else {
+            writer.add_chunk(labels.clone(), raw_chunk(reader, c)?);
+        }
+    }
+
+    Ok(())
+}
+
+// The new block replaces the block it was created from for the time range, so
+// it keeps the level and sources.
+fn set_replacement_meta(writer: &mut BlockWriter, meta: &MetaData, mint: i64, maxt: i64) {
+    writer.set_compaction(BlockCompaction {
+        level: meta.compaction.level,
+        sources: meta.compaction.sources.clone(),
+        parents: vec![BlockDesc {
+            ulid: meta.ulid,
+            min_time: meta.min_time,
+            max_time: meta.max_time,
+        }],
+        ..Default::default()
+    });
+    writer.set_time_range(mint, maxt);
+    if let Some(thanos) = thanos_meta(meta, &[], THANOS_SOURCE_REWRITE) {
+        writer.set_thanos(thanos);
+    }
+}
+
+fn thanos_meta(meta: &MetaData, ignored: &[&str], source: &str) -> Option<ThanosMeta> {
+    meta.thanos.as_ref().map(|thanos| ThanosMeta {
+        version: thanos.version,
+        labels: group_key(meta, ignored).labels,
+        downsample: thanos.downsample.clone(),
+        source: String::from(source),
+        ..Default::default()
+    })
+}
+
+// The new block covers the time range of the sorted blocks, which become its
+// parents. Its level is one above the highest level of its parents.
+fn set_meta(writer: &mut BlockWriter, blocks: &[&Block], ignored: &[&str]) {
+    let mut sources = BTreeSet::<Ulid>::new();
+    let mut compaction = BlockCompaction::default();
+    let mut min_time = i64::MAX;
+    let mut max_time = i64::MIN;
+
+    for block in blocks {
+        let meta = &block.meta;
+        min_time = min_time.min(meta.min_time);
+        max_time = max_time.max(meta.max_time);
+        compaction.level = compaction.level.max(meta.compaction.level);
+        sources.extend(meta.compaction.sources.iter());
+        compaction.parents.push(BlockDesc {
+            ulid: meta.ulid,
+            min_time: meta.min_time,
+            max_time: meta.max_time,
+        });
+    }
+
+    compaction.level = compaction.level.saturating_add(1);
+    compaction.sources = sources.into_iter().collect();
+    writer.set_compaction(compaction);
+    writer.set_time_range(min_time, max_time);
+
+    if let Some(thanos) = thanos_meta(&blocks[0].meta, ignored, THANOS_SOURCE_COMPACTOR) {
+        writer.set_thanos(thanos);
+    }
+}
+
+// Merge blocks into a new block in dir, like the Prometheus compactor does.
+// Series with the same labels are merged. Chunks that overlap other chunks of
+// a series or hold deleted samples are re-encoded, all other chunks are
+// copied as they are. The source blocks are left untouched. Downsampled
+// blocks must not overlap, as their chunks can not be merged.
+pub fn compact(dir: &Path, blocks: &[&Block]) -> Result<MetaData> {
+    let blocks = sort_blocks(blocks, &[])?;
+    if blocks[0].meta.group_key().resolution > 0 {
+        for w in blocks.windows(2) {
+            if w[1].meta.min_time < w[0].meta.max_time {
+                println!(
+                    "Downsampled blocks {} and {} overlap.",
+                    w[0].meta.ulid, w[1].meta.ulid
+                );
+                return Err(TSDBError::InvalidMeta);
+            }
+        }
+    }
+    let mut writer = BlockWriter::new(dir);
+
+    for block in blocks.iter() {
+        let reader = BlockReader::open(&block.dir)?;
+
+        for s in reader.series()? {
+            copy_series(&mut writer, &reader, &s.labels, &s)?;
+        }
+    }
+
+    set_meta(&mut writer, &blocks, &[]);
+    writer.write()
+}
+
+// Rewrite a block into a new block in dir with the relabel configs applied to
+// its series. Dropped series are left out and series ending up with the same
+// labels are merged. The new block replaces the old one, so it keeps its
+// level and sources, and deleted samples are removed.
+pub fn rewrite(dir: &Path, block: &Block, configs: &[RelabelConfig]) -> Result<MetaData> {
+    let reader = BlockReader::open(&block.dir)?;
+    let mut writer = BlockWriter::new(dir);
+
+    for s in reader.series()? {
+        if let Some(labels) = relabel(&s.labels, configs) {
+            copy_series(&mut writer, &reader, &labels, &s)?;
+        }
+    }
+
+    let meta = &block.meta;
+    set_replacement_meta(&mut writer, meta, meta.min_time, meta.max_time);
+    writer.write()
+}
+
+// Split a block into blocks aligned to multiples of range, e.g. 2h. Chunks
+// crossing a boundary are cut and re-encoded, all others are copied. Time
+// ranges without samples do not get a block.
+pub fn split(dir: &Path, block: &Block, range: i64) -> Result<Vec<MetaData>> {
+    if range <= 0 {
+        println!("Invalid range {}.", range);
+        return Err(TSDBError::Default);
+    }
+
+    check_raw(block, "split")?;
+
+    let meta = &block.meta;
+    let reader = BlockReader::open(&block.dir)?;
+    let series = reader.series()?;
+    let mut pieces = Vec::<MetaData>::new();
+
+    let mut start = meta.min_time - meta.min_time.rem_euclid(range);
+    while start < meta.max_time {
+        let mint = start.max(meta.min_time);
+        let maxt = (start + range).min(meta.max_time);
+        start += range;
+
+        let mut writer = BlockWriter::new(dir);
+        let mut empty = true;
+
+        for s in series.iter() {
+            for c in s.chunks.iter().filter(|c| c.mint < maxt && c.maxt >= mint) {
+                if mint <= c.mint && c.maxt < maxt && !reader.has_deletions(s.series_ref, c) {
+                    writer.add_chunk(s.labels.clone(), raw_chunk(&reader, c)?);
+                    empty = false;
+                    continue;
+                }
+
+                let mut samples = reader.samples(s.series_ref, c)?;
+                samples.retain(|(t, _)| mint <= *t && *t < maxt);
+                if !samples.is_empty() {
+                    writer.add_series(s.labels.clone(), &samples);
+                    empty = false;
+                }
+            }
+        }
+
+        if !empty {
+            set_replacement_meta(&mut writer, meta, mint, maxt);
+            pieces.push(writer.write()?);
+        }
+    }
+
+    Ok(pieces)
+}
+
+// Merge overlapping blocks of replicas, e.g. of HA Prometheus pairs, sample by
+// sample. The replica of a series is the value of the first replica label set
+// on the series or in the external labels of its block. Replica labels are
+// removed and the samples of all replicas of a series are deduplicated.
+pub fn compact_vertical(
+    dir: &Path,
+    blocks: &[&Block],
+    replica_labels: &[&str],
+    strategy: &DedupStrategy,
+) -> Result<MetaData> {
+    let blocks = sort_blocks(blocks, replica_labels)?;
+    for block in blocks.iter() {
+        check_raw(block, "deduplicated")?;
+    }
+
+    // replicas of a series in the order of their first block
+    let mut series = BTreeMap::<Labels, Vec<(String, Vec<(i64, f64)>)>>::new();
+    for block in blocks.iter() {
+        let reader = BlockReader::open(&block.dir)?;
+        let external = block.meta.thanos.as_ref().map(|t| &t.labels);
+
+        for s in reader.series()? {
+            let mut samples = Vec::<(i64, f64)>::new();
+            for c in s.chunks.iter() {
+                samples.extend(reader.samples(s.series_ref, c)?);
+            }
+
+            let replica = replica_labels
+                .iter()
+                .find_map(|name| match labels::get(&s.labels, name) {
+                    "" => external.and_then(|l| l.get(*name)).cloned(),
+                    v => Some(v.to_string()),
+                })
+                .unwrap_or_default();
+
+            let mut labels = s.labels;
+            labels.retain(|(name, _)| !replica_labels.contains(&name.as_str()));
+
+            let replicas = series.entry(labels).or_default();
+            match replicas.iter_mut().find(|(r, _)| *r == replica) {
+                Some((_, all)) => all.extend(samples),
+                None => replicas.push((replica, samples)),
+            }
+        }
+    }
+
+    let mut writer = BlockWriter::new(dir);
+    for (labels, mut replicas) in series {
+        // blocks of the same replica can overlap as well
+        for (_, samples) in replicas.iter_mut() {
+            samples.sort_by_key(|(t, _)| *t);
+            samples.dedup_by_key(|(t, _)| *t);
+        }
+        writer.add_series(labels, &dedup(strategy, replicas));
+    }
+
+    set_meta(&mut writer, &blocks, replica_labels);
+    writer.write()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunkenc::ENCODING_AGGR;
+    use crate::downsample::{downsample, RESOLUTION_5M};
+    use crate::index::{label_names, label_values, symbol_table, Index};
+    use crate::labels::{MatchType, Matcher};
+    use crate::relabel::Action;
+
+    fn labels(job: &str) -> Labels {
+        vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("job"), String::from(job)),
+        ]
+    }
+
+    fn write_block(dir: &Path, series: &[(&str, Vec<(i64, f64)>)]) -> Block {
+        let mut writer = BlockWriter::new(dir);
+        for (job, samples) in series {
+            writer.add_series(labels(job), samples);
+        }
+        let meta = writer.write().unwrap();
+        Block::open(&dir.join(meta.ulid.to_string())).unwrap()
+    }
+
+    fn read_samples(reader: &BlockReader) -> Vec<(Labels, Vec<(i64, f64)>)> {
+        let mut all = Vec::new();
+        for s in reader.series().unwrap() {
+            let mut samples = Vec::new();
+            for c in s.chunks.iter() {
+                samples.extend(reader.samples(s.series_ref, c).unwrap());
+            }
+            all.push((s.labels, samples));
+        }
+        all
+    }
+
+    #[test]
+    fn compact_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let range = |from: i64, to: i64| -> Vec<(i64, f64)> {
+            (from..to).map(|i| (i * 15000, i as f64)).collect()
+        };
+
+        let a = write_block(dir.path(), &[("a", range(0, 200)), ("b", range(0, 100))]);
+        // overlaps the end of block a
+        let b = write_block(dir.path(), &[("a", range(150, 300))]);
+        let mut c = write_block(
+            dir.path(),
+            &[("b", range(300, 400)), ("c", range(300, 400))],
+        );
+        let matcher = Matcher::new(MatchType::Equal, "job", "c").unwrap();
+        c.delete(&[matcher], 0, 349 * 15000).unwrap();
+
+        let meta = compact(dir.path(), &[&c, &a, &b]).unwrap();
+
+        assert_eq!(2, meta.compaction.level);
+        assert_eq!(0, meta.min_time);
+        assert_eq!(399 * 15000 + 1, meta.max_time);
+        let mut sources = vec![a.meta.ulid, b.meta.ulid, c.meta.ulid];
+        sources.sort();
+        assert_eq!(sources, meta.compaction.sources);
+        let parents: Vec<Ulid> = meta.compaction.parents.iter().map(|p| p.ulid).collect();
+        assert_eq!(vec![a.meta.ulid, b.meta.ulid, c.meta.ulid], parents);
+        assert_eq!(3, meta.stats.num_series);
+        assert_eq!(300 + 200 + 50, meta.stats.num_samples);
+
+        let reader = BlockReader::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        let mut b_samples = range(0, 100);
+        b_samples.extend(range(300, 400));
+        assert_eq!(
+            vec![
+                (labels("a"), range(0, 300)),
+                (labels("b"), b_samples),
+                (labels("c"), range(350, 400)),
+            ],
+            read_samples(&reader)
+        );
+
+        // compacting the compacted block again raises the level
+        let compacted = Block::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        let again = compact(dir.path(), &[&compacted]).unwrap();
+        assert_eq!(3, again.compaction.level);
+        assert_eq!(sources, again.compaction.sources);
+
+        // the level stops at the highest one
+        let mut top = Block::open(&dir.path().join(again.ulid.to_string())).unwrap();
+        top.meta.compaction.level = u8::MAX;
+        assert_eq!(
+            u8::MAX,
+            compact(dir.path(), &[&top]).unwrap().compaction.level
+        );
+    }
+
+    #[test]
+    fn rewrite_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let range = |from: i64, to: i64| -> Vec<(i64, f64)> {
+            (from..to).map(|i| (i * 15000, i as f64)).collect()
+        };
+        let block = write_block(
+            dir.path(),
+            &[
+                ("a", range(0, 100)),
+                ("b", range(100, 200)),
+                ("c", range(0, 50)),
+            ],
+        );
+
+        // drop c and merge a and b by dropping the job label
+        let mut drop = RelabelConfig::new(Action::Drop, "c").unwrap();
+        drop.source_labels = vec![String::from("job")];
+        let mut rename = RelabelConfig::new(Action::Replace, "(.*)").unwrap();
+        rename.source_labels = vec![String::from("job")];
+        rename.target_label = String::from("service");
+        rename.replacement = String::from("all");
+        let drop_job = RelabelConfig::new(Action::LabelDrop, "job").unwrap();
+
+        let meta = rewrite(dir.path(), &block, &[drop, rename, drop_job]).unwrap();
+        assert_eq!(1, meta.compaction.level);
+        assert_eq!(block.meta.compaction.sources, meta.compaction.sources);
+        assert_eq!(block.meta.ulid, meta.compaction.parents[0].ulid);
+        assert_eq!(
+            (block.meta.min_time, block.meta.max_time),
+            (meta.min_time, meta.max_time)
+        );
+        assert_eq!(1, meta.stats.num_series);
+        assert_eq!(200, meta.stats.num_samples);
+
+        let dir = dir.path().join(meta.ulid.to_string());
+        let reader = BlockReader::open(&dir).unwrap();
+        let labels = vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("service"), String::from("all")),
+        ];
+        assert_eq!(vec![(labels, range(0, 200))], read_samples(&reader));
+
+        // the symbol table only holds the remaining labels
+        let index = Index::open(&dir.join("index")).unwrap();
+        assert_eq!(4, symbol_table(&index).unwrap().count());
+        assert_eq!(vec!["__name__", "service"], label_names(&index).unwrap());
+        assert_eq!(vec!["all"], label_values(&index, "service").unwrap());
+    }
+
+    #[test]
+    fn split_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let hour = 3600 * 1000;
+        // 1h of samples, a gap of 3h and 6h of samples
+        let mut samples: Vec<(i64, f64)> = (60..120).map(|i| (i * 60000, i as f64)).collect();
+        samples.extend((240..600).map(|i| (i * 60000, i as f64)));
+        let block = write_block(
+            dir.path(),
+            &[("a", samples.clone()), ("b", samples.clone())],
+        );
+        let range = 2 * hour;
+
+        let pieces = split(dir.path(), &block, range).unwrap();
+        let ranges: Vec<(i64, i64)> = pieces.iter().map(|m| (m.min_time, m.max_time)).collect();
+        assert_eq!(
+            vec![
+                (hour, 2 * hour),
+                (4 * hour, 6 * hour),
+                (6 * hour, 8 * hour),
+                (8 * hour, 599 * 60000 + 1),
+            ],
+            ranges
+        );
+        assert!(pieces
+            .iter()
+            .all(|m| m.compaction.parents[0].ulid == block.meta.ulid));
+
+        let mut ulids: Vec<Ulid> = pieces.iter().map(|m| m.ulid).collect();
+        ulids.dedup();
+        assert_eq!(4, ulids.len());
+
+        let mut split_samples = Vec::<(i64, f64)>::new();
+        for m in pieces.iter() {
+            let reader = BlockReader::open(&dir.path().join(m.ulid.to_string())).unwrap();
+            let series = read_samples(&reader);
+            assert_eq!(2, series.len());
+            assert!(series[0]
+                .1
+                .iter()
+                .all(|(t, _)| m.min_time <= *t && *t < m.max_time));
+            split_samples.extend(series[0].1.iter());
+        }
+        assert_eq!(samples, split_samples);
+    }
+
+    #[test]
+    fn compact_replicas() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut blocks = Vec::<Block>::new();
+
+        // replica b scrapes 5s after a and misses a scrape
+        for (replica, offset) in [("a", 0), ("b", 5000)] {
+            let mut writer = BlockWriter::new(dir.path());
+            let samples: Vec<(i64, f64)> = (0..100)
+                .filter(|i| replica == "a" || *i != 50)
+                .map(|i| (i * 15000 + offset, i as f64))
+                .collect();
+            writer.add_series(labels("a"), &samples);
+            writer.set_thanos(ThanosMeta {
+                labels: BTreeMap::from([
+                    (String::from("cluster"), String::from("eu")),
+                    (String::from("replica"), String::from(replica)),
+                ]),
+                ..Default::default()
+            });
+            let meta = writer.write().unwrap();
+            blocks.push(Block::open(&dir.path().join(meta.ulid.to_string())).unwrap());
+        }
+        let blocks: Vec<&Block> = blocks.iter().collect();
+
+        // the replica label keeps the blocks apart
+        assert!(compact(dir.path(), &blocks).is_err());
+
+        let keep_first =
+            compact_vertical(dir.path(), &blocks, &["replica"], &DedupStrategy::KeepFirst).unwrap();
+        assert_eq!(199, keep_first.stats.num_samples);
+        assert_eq!(2, keep_first.compaction.level);
+        let thanos = keep_first.thanos.unwrap();
+        assert_eq!(1, thanos.labels.len());
+        assert_eq!(THANOS_SOURCE_COMPACTOR, thanos.source);
+
+        let penalty =
+            compact_vertical(dir.path(), &blocks, &["replica"], &DedupStrategy::Penalty).unwrap();
+        assert_eq!(100, penalty.stats.num_samples);
+
+        let prefer_b = DedupStrategy::PreferReplica(String::from("b"));
+        let meta = compact_vertical(dir.path(), &blocks, &["replica"], &prefer_b).unwrap();
+        let reader = BlockReader::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        let samples = &read_samples(&reader)[0].1;
+        assert_eq!(100, samples.len());
+        // the missed scrape is filled in from replica a
+        assert_eq!((50 * 15000, 50.0), samples[50]);
+        assert_eq!((51 * 15000 + 5000, 51.0), samples[51]);
+    }
+
+    #[test]
+    fn compact_downsampled() {
+        let raw_dir = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let range = |from: i64, to: i64| -> Vec<(i64, f64)> {
+            (from..to).map(|i| (i * 15000, i as f64)).collect()
+        };
+
+        let mut downsampled = Vec::<Block>::new();
+        for (from, to) in [(0, 480), (480, 960), (400, 500)] {
+            let raw = write_block(raw_dir.path(), &[("a", range(from, to))]);
+            let meta = downsample(dir.path(), &raw, RESOLUTION_5M).unwrap();
+            downsampled.push(Block::open(&dir.path().join(meta.ulid.to_string())).unwrap());
+        }
+        let (a, b, c) = (&downsampled[0], &downsampled[1], &downsampled[2]);
+
+        // the aggregate chunks of adjacent blocks are copied
+        let meta = compact(dir.path(), &[b, a]).unwrap();
+        assert_eq!(RESOLUTION_5M, meta.group_key().resolution);
+        assert_eq!((0, 959 * 15000 + 1), (meta.min_time, meta.max_time));
+        assert_eq!(48, meta.stats.num_samples);
+        let reader = BlockReader::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        let series = reader.series().unwrap();
+        assert_eq!(2, series[0].chunks.len());
+        for chunk in series[0].chunks.iter() {
+            assert_eq!(ENCODING_AGGR, reader.chunk(chunk.chunk_ref).unwrap().0);
+        }
+
+        // aggregates can not be merged or cut
+        assert!(compact(dir.path(), &[a, c]).is_err());
+        assert!(split(dir.path(), a, RESOLUTION_5M).is_err());
+        assert!(compact_vertical(dir.path(), &[a, b], &[], &DedupStrategy::KeepFirst).is_err());
+    }
+}
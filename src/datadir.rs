@@ -0,0 +1,387 @@
+use std::{
+    collections::BTreeMap,
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
+
+use crate::common::*;
+use crate::index::{series, symbol_table, Index};
+use crate::labels::{self, Matcher};
+use crate::meta::{GroupKey, MetaData};
+use crate::tombstones::{merge_tombstones, read_tombstones, write_tombstones};
+use crate::ulid::Ulid;
+use crate::wal::RefTombstone;
+
+const META_FILENAME: &str = "meta.json";
+const INDEX_FILENAME: &str = "index";
+const TOMBSTONES_FILENAME: &str = "tombstones";
+const CHUNKS_HEAD_DIR: &str = "chunks_head";
+pub const WAL_DIR: &str = "wal";
+const WBL_DIR: &str = "wbl";
+const LOCK_FILENAME: &str = "lock";
+
+#[derive(Debug)]
+pub struct Block {
+    pub dir: PathBuf,
+    pub meta: MetaData,
+}
+
+impl Block {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let meta = MetaData::load(&dir.join(META_FILENAME))?;
+
+        // the directory of a block is named after its ULID
+        let name = dir.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if name.parse::<Ulid>()? != meta.ulid {
+            println!("Block {} has ULID {} in its meta.json.", name, meta.ulid);
+            return Err(TSDBError::InvalidUlid);
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            meta,
+        })
+    }
+
+    pub fn tombstones(&self) -> Result<Vec<RefTombstone>> {
+        let path = self.dir.join(TOMBSTONES_FILENAME);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        read_tombstones(&path)
+    }
+
+    // Mark the samples between mint and maxt of all series matching the
+    // matchers as deleted, like the delete series API of Prometheus. The data
+    // is removed once the block is compacted. Returns the number of series
+    // that got a new tombstone.
+    pub fn delete(&mut self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<usize> {
+        // deleting all series is most likely a mistake
+        if matchers.is_empty() {
+            return Err(TSDBError::InvalidMatcher);
+        }
+        if mint > maxt {
+            println!("Invalid time range from {} to {}.", mint, maxt);
+            return Err(TSDBError::Default);
+        }
+
+        let index = Index::open(&self.dir.join(INDEX_FILENAME))?;
+        let mut sym = symbol_table(&index)?;
+        let mut tombstones = self.tombstones()?;
+        let mut deleted = 0;
+
+        for s in series(&index)? {
+            if !labels::matches(matchers, &s.labels(&mut sym)?) {
+                continue;
+            }
+            // only series with data in the time range are affected
+            if s.chunk_metas()
+                .iter()
+                .any(|c| c.mint <= maxt && mint <= c.maxt)
+            {
+                tombstones.push(RefTombstone {
+                    series_ref: s.series_ref,
+                    mint,
+                    maxt,
+                });
+                deleted += 1;
+            }
+        }
+
+        if deleted == 0 {
+            return Ok(0);
+        }
+
+        let tombstones = merge_tombstones(tombstones);
+        write_tombstones(&self.dir.join(TOMBSTONES_FILENAME), &tombstones)?;
+
+        self.meta.stats.num_tombstones = tombstones.len() as u64;
+        self.meta.write(&self.dir.join(META_FILENAME))?;
+
+        Ok(deleted)
+    }
+
+    fn overlaps(&self, other: &Block) -> bool {
+        // block time ranges are half open [minTime, maxTime)
+        self.meta.min_time < other.meta.max_time && other.meta.min_time < self.meta.max_time
+    }
+}
+
+// Layout of a Prometheus data directory:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/README.md
+#[derive(Debug)]
+pub struct DataDir {
+    pub dir: PathBuf,
+    // persisted blocks sorted by their time range
+    pub blocks: Vec<Block>,
+    pub chunks_head: Option<PathBuf>,
+    pub wal: Option<PathBuf>,
+    pub wbl: Option<PathBuf>,
+    // Prometheus removes the lock file on shutdown, so it is either running or
+    // did not shut down cleanly
+    pub locked: bool,
+}
+
+impl DataDir {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let mut blocks = Vec::<Block>::new();
+        let mut chunks_head = None;
+        let mut wal = None;
+        let mut wbl = None;
+        let mut locked = false;
+
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+
+            if entry.file_type()?.is_file() {
+                if name == LOCK_FILENAME {
+                    locked = true;
+                }
+                continue;
+            }
+
+            match name {
+                CHUNKS_HEAD_DIR => chunks_head = Some(path),
+                WAL_DIR => wal = Some(path),
+                WBL_DIR => wbl = Some(path),
+                // blocks that are being written or deleted by a running
+                // Prometheus have a suffix and are not valid ULIDs
+                _ if name.parse::<Ulid>().is_ok() => blocks.push(Block::open(&path)?),
+                _ => {}
+            }
+        }
+
+        blocks.sort_by_key(|b| (b.meta.min_time, b.meta.max_time));
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            blocks,
+            chunks_head,
+            wal,
+            wbl,
+            locked,
+        })
+    }
+
+    // blocks grouped by their external labels and resolution
+    pub fn groups(&self) -> BTreeMap<GroupKey, Vec<&Block>> {
+        let mut groups = BTreeMap::<GroupKey, Vec<&Block>>::new();
+
+        for b in &self.blocks {
+            groups.entry(b.meta.group_key()).or_default().push(b);
+        }
+
+        groups
+    }
+
+    // time range covered by all blocks
+    pub fn time_range(&self) -> Option<(i64, i64)> {
+        let min = self.blocks.iter().map(|b| b.meta.min_time).min()?;
+        let max = self.blocks.iter().map(|b| b.meta.max_time).max()?;
+
+        Some((min, max))
+    }
+
+    // pairs of blocks with overlapping time ranges
+    pub fn overlaps(&self) -> Vec<(&Block, &Block)> {
+        let mut overlaps = Vec::<(&Block, &Block)>::new();
+
+        for (i, a) in self.blocks.iter().enumerate() {
+            // blocks are sorted by min time, so only later blocks can overlap
+            for b in self.blocks[i + 1..].iter() {
+                if b.meta.min_time >= a.meta.max_time {
+                    break;
+                }
+                if a.overlaps(b) {
+                    overlaps.push((a, b));
+                }
+            }
+        }
+
+        overlaps
+    }
+
+    // time ranges between the blocks that are not covered by any block
+    pub fn gaps(&self) -> Vec<(i64, i64)> {
+        let mut gaps = Vec::<(i64, i64)>::new();
+        let mut max = match self.blocks.first() {
+            Some(b) => b.meta.max_time,
+            None => return gaps,
+        };
+
+        for b in self.blocks.iter().skip(1) {
+            if b.meta.min_time > max {
+                gaps.push((max, b.meta.min_time));
+            }
+            max = max.max(b.meta.max_time);
+        }
+
+        gaps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir, write};
+
+    fn write_block(dir: &Path, ulid: &str, min_time: i64, max_time: i64) {
+        let block = dir.join(ulid);
+        create_dir(&block).unwrap();
+        write(
+            block.join(META_FILENAME),
+            format!(
+                r#"{{
+  "version": 1,
+  "ulid": "{ulid}",
+  "minTime": {min_time},
+  "maxTime": {max_time},
+  "stats": {{ "numSamples": 1, "numSeries": 1, "numChunks": 1 }},
+  "compaction": {{ "level": 1, "sources": ["{ulid}"] }}
+}}"#
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn open_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        write_block(dir.path(), "01G0P9P6T0VSA5Z484BRGH2N68", 0, 7200);
+        write_block(dir.path(), "01G0P9P6T0VSA5Z484BRGH2N69", 7000, 14400);
+        write_block(dir.path(), "01G0P9P6T0VSA5Z484BRGH2N70", 21600, 28800);
+        write_block(
+            dir.path(),
+            "01G0P9P6T0VSA5Z484BRGH2N71.tmp-for-creation",
+            0,
+            1,
+        );
+        create_dir(dir.path().join(WAL_DIR)).unwrap();
+        create_dir(dir.path().join(CHUNKS_HEAD_DIR)).unwrap();
+        write(dir.path().join(LOCK_FILENAME), "").unwrap();
+
+        let data_dir = DataDir::open(dir.path()).unwrap();
+
+        assert_eq!(3, data_dir.blocks.len());
+        assert!(data_dir.wal.is_some());
+        assert!(data_dir.chunks_head.is_some());
+        assert!(data_dir.wbl.is_none());
+        assert!(data_dir.locked);
+        assert_eq!(Some((0, 28800)), data_dir.time_range());
+        assert_eq!(vec![(14400, 21600)], data_dir.gaps());
+        assert_eq!(3, data_dir.groups()[&GroupKey::default()].len());
+
+        let overlaps = data_dir.overlaps();
+        assert_eq!(1, overlaps.len());
+        assert_eq!(
+            "01G0P9P6T0VSA5Z484BRGH2N68",
+            overlaps[0].0.meta.ulid.to_string()
+        );
+        assert_eq!(
+            "01G0P9P6T0VSA5Z484BRGH2N69",
+            overlaps[0].1.meta.ulid.to_string()
+        );
+    }
+
+    #[test]
+    fn corrupt_meta_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = dir.path().join("01G0P9P6T0VSA5Z484BRGH2N68");
+        create_dir(&block).unwrap();
+        write(block.join(META_FILENAME), "{").unwrap();
+
+        assert!(matches!(
+            DataDir::open(dir.path()),
+            Err(TSDBError::InvalidMeta)
+        ));
+    }
+
+    #[test]
+    fn delete_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = crate::block::BlockWriter::new(dir.path());
+        let samples: Vec<(i64, f64)> = (0..100).map(|i| (i * 1000, i as f64)).collect();
+        for job in ["a", "b", "c"] {
+            writer.add_series(
+                vec![
+                    (String::from("__name__"), String::from("up")),
+                    (String::from("job"), String::from(job)),
+                ],
+                &samples,
+            );
+        }
+        let meta = writer.write().unwrap();
+        let mut block = Block::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+
+        let m = |t, n, v| Matcher::new(t, n, v).unwrap();
+        use crate::labels::MatchType::*;
+
+        assert!(block.delete(&[], 0, 1000).is_err());
+        assert!(block.delete(&[m(Equal, "job", "a")], 1000, 0).is_err());
+        // no data in the time range
+        assert_eq!(
+            0,
+            block
+                .delete(&[m(Equal, "job", "a")], 200000, 300000)
+                .unwrap()
+        );
+        assert_eq!(
+            2,
+            block
+                .delete(&[m(Regex, "job", "a|b")], 10000, 20000)
+                .unwrap()
+        );
+        assert_eq!(
+            1,
+            block.delete(&[m(Equal, "job", "a")], 15000, 30000).unwrap()
+        );
+
+        let tombstones = block.tombstones().unwrap();
+        assert_eq!(2, tombstones.len());
+        assert_eq!((10000, 30000), (tombstones[0].mint, tombstones[0].maxt));
+        assert_eq!((10000, 20000), (tombstones[1].mint, tombstones[1].maxt));
+
+        let reopened = Block::open(&block.dir).unwrap();
+        assert_eq!(2, reopened.meta.stats.num_tombstones);
+    }
+
+    #[test]
+    fn misnamed_block() {
+        let dir = tempfile::tempdir().unwrap();
+        write_block(dir.path(), "01G0P9P6T0VSA5Z484BRGH2N68", 0, 7200);
+        std::fs::rename(
+            dir.path().join("01G0P9P6T0VSA5Z484BRGH2N68"),
+            dir.path().join("01G0P9P6T0VSA5Z484BRGH2N69"),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            DataDir::open(dir.path()),
+            Err(TSDBError::InvalidUlid)
+        ));
+    }
+
+    #[test]
+    fn invalid_ulid_in_meta_data() {
+        let dir = tempfile::tempdir().unwrap();
+        write_block(dir.path(), "01G0P9P6T0VSA5Z484BRGH2N68", 0, 7200);
+        let meta = dir
+            .path()
+            .join("01G0P9P6T0VSA5Z484BRGH2N68")
+            .join(META_FILENAME);
+        let content = std::fs::read_to_string(&meta).unwrap();
+        write(
+            &meta,
+            content.replace("\"ulid\": \"01G0", "\"ulid\": \"U1G0"),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            DataDir::open(dir.path()),
+            Err(TSDBError::InvalidMeta)
+        ));
+    }
+}
@@ -0,0 +1,217 @@
+// Thanos assumes samples to be at least 5s apart until it knows better
+const INITIAL_PENALTY: i64 = 5000;
+
+// How samples of replicas of a series, e.g. scraped by HA Prometheus pairs,
+// are merged into one series.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DedupStrategy {
+    // merge sample by sample, for equal timestamps the first replica wins
+    KeepFirst,
+    // use the samples of the named replica and fill its gaps from the others
+    PreferReplica(String),
+    // switch between replicas with the penalty based algorithm of Thanos
+    Penalty,
+}
+
+// Deduplicate the sorted samples of the replicas of a series. Replicas are
+// given in order of their first block and merged pairwise.
+pub fn dedup(
+    strategy: &DedupStrategy,
+    mut replicas: Vec<(String, Vec<(i64, f64)>)>,
+) -> Vec<(i64, f64)> {
+    if let DedupStrategy::PreferReplica(name) = strategy {
+        // the sort is stable, so the other replicas keep their order
+        replicas.sort_by_key(|(r, _)| r != name);
+    }
+
+    let mut samples = replicas.into_iter().map(|(_, s)| s);
+    let first = samples.next().unwrap_or_default();
+    samples.fold(first, |a, b| match strategy {
+        DedupStrategy::KeepFirst => merge(&a, &b),
+        DedupStrategy::PreferReplica(_) => fill_gaps(&a, &b),
+        DedupStrategy::Penalty => penalty(&a, &b),
+    })
+}
+
+fn merge(a: &[(i64, f64)], b: &[(i64, f64)]) -> Vec<(i64, f64)> {
+    let mut merged = Vec::<(i64, f64)>::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        match (a.get(i), b.get(j)) {
+            (Some(sa), Some(sb)) if sb.0 < sa.0 => {
+                merged.push(*sb);
+                j += 1;
+            }
+            (Some(sa), Some(sb)) => {
+                if sa.0 == sb.0 {
+                    j += 1;
+                }
+                merged.push(*sa);
+                i += 1;
+            }
+            (Some(sa), None) => {
+                merged.push(*sa);
+                i += 1;
+            }
+            (None, Some(sb)) => {
+                merged.push(*sb);
+                j += 1;
+            }
+            (None, None) => break,
+        }
+    }
+
+    merged
+}
+
+// Samples of b only fill gaps of a, where a misses at least one scrape. The
+// scrape interval is estimated by the distance of a sample to its neighbours
+// and samples of b have to be half an interval away from the ones of a.
+fn fill_gaps(a: &[(i64, f64)], b: &[(i64, f64)]) -> Vec<(i64, f64)> {
+    if a.is_empty() {
+        return b.to_vec();
+    }
+
+    let interval = |i: usize| {
+        let prev = i.checked_sub(1).map(|p| a[i].0 - a[p].0);
+        let next = a.get(i + 1).map(|n| n.0 - a[i].0);
+        match (prev, next) {
+            (Some(p), Some(n)) => p.min(n),
+            (Some(d), None) | (None, Some(d)) => d,
+            (None, None) => INITIAL_PENALTY,
+        }
+    };
+
+    let mut filled = Vec::<(i64, f64)>::with_capacity(a.len());
+    let mut j = 0;
+
+    // before the first sample of a
+    while j < b.len() && b[j].0 < a[0].0 - interval(0) / 2 {
+        filled.push(b[j]);
+        j += 1;
+    }
+
+    for (i, sample) in a.iter().enumerate() {
+        filled.push(*sample);
+
+        let d = interval(i);
+        while j < b.len() && b[j].0 <= sample.0 + d / 2 {
+            j += 1;
+        }
+
+        match a.get(i + 1) {
+            Some(next) if 2 * (next.0 - sample.0) > 3 * d => {
+                while j < b.len() && b[j].0 < next.0 - d / 2 {
+                    filled.push(b[j]);
+                    j += 1;
+                }
+            }
+            Some(_) => {}
+            // after the last sample of a
+            None => filled.extend_from_slice(&b[j..]),
+        }
+    }
+
+    filled
+}
+
+// NOTE: Port of the penalty based deduplication of Thanos:
+// https://github.com/thanos-io/thanos/blob/main/pkg/dedup/iter.go
+//
+// The replica with the next sample is picked. The other one is only used
+// again after a gap of twice the last sample distance, which avoids switching
+// back and forth between replicas that scrape with an offset.
+fn penalty(a: &[(i64, f64)], b: &[(i64, f64)]) -> Vec<(i64, f64)> {
+    let mut deduped = Vec::<(i64, f64)>::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (0, 0);
+    let (mut pen_a, mut pen_b) = (0, 0);
+    let mut last_t: Option<i64> = None;
+
+    loop {
+        // advance both replicas past the last timestamp plus their penalty
+        if let Some(t) = last_t {
+            i += a[i..].partition_point(|s| s.0 < t + 1 + pen_a);
+            j += b[j..].partition_point(|s| s.0 < t + 1 + pen_b);
+        }
+
+        let use_a = match (a.get(i), b.get(j)) {
+            (None, None) => break,
+            (Some(_), None) => {
+                pen_a = 0;
+                true
+            }
+            (None, Some(_)) => {
+                pen_b = 0;
+                false
+            }
+            (Some(sa), Some(sb)) if sa.0 <= sb.0 => {
+                pen_b = last_t.map_or(INITIAL_PENALTY, |t| 2 * (sa.0 - t));
+                pen_a = 0;
+                true
+            }
+            (Some(_), Some(sb)) => {
+                pen_a = last_t.map_or(INITIAL_PENALTY, |t| 2 * (sb.0 - t));
+                pen_b = 0;
+                false
+            }
+        };
+
+        let sample = if use_a { a[i] } else { b[j] };
+        last_t = Some(sample.0);
+        deduped.push(sample);
+    }
+
+    deduped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn samples(ts: &[i64], v: f64) -> Vec<(i64, f64)> {
+        ts.iter().map(|t| (*t, v)).collect()
+    }
+
+    #[test]
+    fn dedup_replicas() {
+        // replica b scrapes 2s after a and a misses two scrapes
+        let a = samples(&[0, 10000, 20000, 50000, 60000], 1.0);
+        let b = samples(
+            &[2000, 12000, 22000, 32000, 42000, 52000, 62000, 72000],
+            2.0,
+        );
+        let replicas = vec![(String::from("a"), a), (String::from("b"), b)];
+
+        let keep_first = dedup(&DedupStrategy::KeepFirst, replicas.clone());
+        assert_eq!(13, keep_first.len());
+
+        let expected = vec![
+            (0, 1.0),
+            (10000, 1.0),
+            (20000, 1.0),
+            (32000, 2.0),
+            (42000, 2.0),
+            (50000, 1.0),
+            (60000, 1.0),
+            (72000, 2.0),
+        ];
+        let prefer_a = DedupStrategy::PreferReplica(String::from("a"));
+        assert_eq!(expected, dedup(&prefer_a, replicas.clone()));
+
+        let prefer_b = DedupStrategy::PreferReplica(String::from("b"));
+        assert_eq!(8, dedup(&prefer_b, replicas.clone()).len());
+
+        // once a misses a scrape b is used until it stops
+        let expected = vec![
+            (0, 1.0),
+            (10000, 1.0),
+            (20000, 1.0),
+            (42000, 2.0),
+            (52000, 2.0),
+            (62000, 2.0),
+            (72000, 2.0),
+        ];
+        assert_eq!(expected, dedup(&DedupStrategy::Penalty, replicas));
+    }
+}
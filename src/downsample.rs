@@ -0,0 +1,478 @@
+use std::path::Path;
+
+use crate::block::{BlockReader, BlockWriter, RawChunk};
+use crate::chunkenc::{
+    decode_aggr_chunk, decode_xor_chunk, encode_aggr_chunk, AggrType, XorChunk, ENCODING_AGGR,
+    NUM_AGGR_TYPES,
+};
+use crate::common::*;
+use crate::datadir::Block;
+use crate::meta::{BlockCompaction, BlockDesc, MetaData, ThanosDownsample, ThanosMeta};
+
+pub const RESOLUTION_5M: i64 = 5 * 60 * 1000;
+pub const RESOLUTION_1H: i64 = 60 * 60 * 1000;
+// raw data is assumed to be scraped every minute to size chunks
+const RAW_RESOLUTION: i64 = 60 * 1000;
+const MAX_SAMPLES_PER_CHUNK: usize = 140;
+// bit pattern of the NaN Prometheus uses as staleness marker
+const STALE_NAN: u64 = 0x7ff0000000000002;
+const THANOS_SOURCE_COMPACTOR: &str = "compactor";
+
+// end of the window a timestamp belongs to
+fn current_window(t: i64, resolution: i64) -> i64 {
+    t - t.rem_euclid(resolution) + resolution - 1
+}
+
+// Increase the number of chunks until there are less than 140 samples per
+// chunk on average, taking into account how much of the time range is
+// covered by samples.
+fn target_chunk_count(mint: i64, maxt: i64, in_res: i64, out_res: i64, count: usize) -> usize {
+    let max_samples = ((maxt - mint) / out_res) as f64;
+    let fullness = (count as f64 / ((maxt - mint) as f64 / in_res as f64)).min(1.0);
+    let expected = (max_samples * fullness) as usize + 1;
+
+    let mut n = 1;
+    while expected / n > MAX_SAMPLES_PER_CHUNK {
+        n += 1;
+    }
+    n
+}
+
+// Counter tracks the total increase of a counter since the first sample,
+// compensating for resets.
+#[derive(Debug, Default)]
+struct Aggregator {
+    total: usize,
+    count: usize,
+    sum: f64,
+    min: f64,
+    max: f64,
+    counter: f64,
+    last: f64,
+}
+
+impl Aggregator {
+    // start a new window, the counter carries over
+    fn reset(&mut self) {
+        self.count = 0;
+        self.sum = 0.0;
+        self.min = f64::INFINITY;
+        self.max = f64::NEG_INFINITY;
+    }
+
+    fn add(&mut self, v: f64) {
+        if self.total == 0 {
+            self.counter = v;
+        } else if v < self.last {
+            self.counter += v;
+        } else {
+            self.counter += v - self.last;
+        }
+        self.last = v;
+
+        self.sum += v;
+        self.count += 1;
+        self.total += 1;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+}
+
+// Aggregate the samples per window and call add at the end of each window.
+// Window ends are limited to the last sample, so the following batch does not
+// overlap. Returns the timestamp of the last window, or None if the batch
+// holds stale markers only.
+fn downsample_batch(
+    samples: &[(i64, f64)],
+    resolution: i64,
+    mut add: impl FnMut(i64, &Aggregator),
+) -> Option<i64> {
+    let mut aggr = Aggregator::default();
+    let mut next_t = None;
+    let last_t = samples[samples.len() - 1].0;
+
+    for (t, v) in samples {
+        if v.to_bits() == STALE_NAN {
+            continue;
+        }
+        if next_t.is_none_or(|next_t| *t > next_t) {
+            if let Some(next_t) = next_t {
+                add(next_t, &aggr);
+            }
+            aggr.reset();
+            next_t = Some(current_window(*t, resolution).min(last_t));
+        }
+        aggr.add(*v);
+    }
+    if let Some(next_t) = next_t {
+        add(next_t, &aggr);
+    }
+
+    next_t
+}
+
+#[derive(Debug, Default)]
+struct AggrChunkBuilder {
+    mint: i64,
+    maxt: i64,
+    chunks: [Option<XorChunk>; NUM_AGGR_TYPES],
+}
+
+impl AggrChunkBuilder {
+    fn new() -> Self {
+        Self {
+            mint: i64::MAX,
+            maxt: i64::MIN,
+            ..Default::default()
+        }
+    }
+
+    fn append(&mut self, aggr: AggrType, t: i64, v: f64) {
+        self.mint = self.mint.min(t);
+        self.maxt = self.maxt.max(t);
+        self.chunks[aggr as usize]
+            .get_or_insert_with(XorChunk::new)
+            .append(t, v);
+    }
+
+    // the first and last raw values of the counter are not part of the time
+    // range of the chunk
+    fn append_raw(&mut self, t: i64, v: f64) {
+        self.chunks[AggrType::Counter as usize]
+            .get_or_insert_with(XorChunk::new)
+            .append(t, v);
+    }
+
+    fn encode(self) -> RawChunk {
+        RawChunk {
+            mint: self.mint,
+            maxt: self.maxt,
+            encoding: ENCODING_AGGR,
+            data: encode_aggr_chunk(&self.chunks),
+        }
+    }
+}
+
+// NOTE: Port of the downsampling of raw data in Thanos:
+// https://github.com/thanos-io/thanos/blob/main/pkg/compact/downsample/downsample.go
+//
+// The counter aggregate starts with the first and ends with the last raw
+// value of a chunk, so counter resets between chunks can be detected.
+fn downsample_raw(samples: &[(i64, f64)], resolution: i64) -> Vec<RawChunk> {
+    if samples.is_empty() {
+        return Vec::new();
+    }
+
+    let num_chunks = target_chunk_count(
+        samples[0].0,
+        samples[samples.len() - 1].0,
+        RAW_RESOLUTION,
+        resolution,
+        samples.len(),
+    );
+    let batch_size = samples.len() / num_chunks + 1;
+
+    let mut chunks = Vec::<RawChunk>::with_capacity(num_chunks);
+    let mut samples = samples;
+    while !samples.is_empty() {
+        // a batch always ends with a complete window
+        let mut j = batch_size.min(samples.len());
+        let window = current_window(samples[j - 1].0, resolution);
+        while j < samples.len() && samples[j].0 <= window {
+            j += 1;
+        }
+        let (batch, rest) = samples.split_at(j);
+        samples = rest;
+
+        let mut builder = AggrChunkBuilder::new();
+        builder.append_raw(batch[0].0, batch[0].1);
+        let last_t = downsample_batch(batch, resolution, |t, a| {
+            builder.append(AggrType::Count, t, a.count as f64);
+            builder.append(AggrType::Sum, t, a.sum);
+            builder.append(AggrType::Min, t, a.min);
+            builder.append(AggrType::Max, t, a.max);
+            builder.append(AggrType::Counter, t, a.counter);
+        });
+        // a batch of stale markers only has no chunk
+        if let Some(last_t) = last_t {
+            builder.append_raw(last_t, batch[batch.len() - 1].1);
+            chunks.push(builder.encode());
+        }
+    }
+
+    chunks
+}
+
+// Apply counter resets across the counter aggregates of chunks. Samples with
+// the timestamp of their predecessor hold the last raw value of a chunk and
+// only serve to detect resets. Returns the samples and the last raw value.
+fn apply_counter_resets(chunks: &[Vec<(i64, f64)>]) -> (Vec<(i64, f64)>, f64) {
+    let mut samples = Vec::<(i64, f64)>::new();
+    let (mut last_t, mut last_v, mut total) = (i64::MIN, 0.0, 0.0);
+
+    for (t, v) in chunks.iter().flatten() {
+        if v.is_nan() {
+            continue;
+        }
+        if samples.is_empty() {
+            total = *v;
+        } else if *t > last_t {
+            total += if *v >= last_v { v - last_v } else { *v };
+        } else {
+            // the series can not go back in time
+            if *t == last_t {
+                last_v = *v;
+            }
+            continue;
+        }
+        last_t = *t;
+        last_v = *v;
+        samples.push((*t, total));
+    }
+
+    (samples, last_v)
+}
+
+// Downsample a batch of aggregate chunks. Counts and sums are summed up, the
+// minimum and maximum kept and counters taken from the end of each window.
+fn downsample_aggr_batch(
+    chunks: &[[Vec<(i64, f64)>; NUM_AGGR_TYPES]],
+    resolution: i64,
+) -> RawChunk {
+    let mut builder = AggrChunkBuilder::new();
+
+    for (aggr, f) in [
+        (
+            AggrType::Count,
+            (|a: &Aggregator| a.sum) as fn(&Aggregator) -> f64,
+        ),
+        (AggrType::Sum, |a| a.sum),
+        (AggrType::Min, |a| a.min),
+        (AggrType::Max, |a| a.max),
+    ] {
+        let samples: Vec<(i64, f64)> = chunks
+            .iter()
+            .flat_map(|c| c[aggr as usize].iter().copied())
+            .collect();
+        if !samples.is_empty() {
+            downsample_batch(&samples, resolution, |t, a| builder.append(aggr, t, f(a)));
+        }
+    }
+
+    let counters: Vec<Vec<(i64, f64)>> = chunks
+        .iter()
+        .map(|c| c[AggrType::Counter as usize].clone())
+        .collect();
+    let (samples, last_v) = apply_counter_resets(&counters);
+    if !samples.is_empty() {
+        builder.append_raw(samples[0].0, samples[0].1);
+        let last_t = downsample_batch(&samples, resolution, |t, a| {
+            builder.append(AggrType::Counter, t, a.last)
+        });
+        if let Some(last_t) = last_t {
+            builder.append_raw(last_t, last_v);
+        }
+    }
+
+    builder.encode()
+}
+
+fn downsample_aggr(
+    chunks: &[[Vec<(i64, f64)>; NUM_AGGR_TYPES]],
+    mint: i64,
+    maxt: i64,
+    in_res: i64,
+    out_res: i64,
+) -> Vec<RawChunk> {
+    let count: usize = chunks
+        .iter()
+        .map(|c| c[AggrType::Count as usize].len())
+        .sum();
+    let num_chunks = target_chunk_count(mint, maxt, in_res, out_res, count);
+    let batch_size = (chunks.len() / num_chunks).max(1);
+
+    chunks
+        .chunks(batch_size)
+        .map(|batch| downsample_aggr_batch(batch, out_res))
+        .collect()
+}
+
+// Downsample a block into a new block in dir with the count, sum, min, max
+// and counter aggregates of each series per window of the resolution, like
+// the Thanos compactor does. Raw blocks and already downsampled blocks of a
+// lower resolution can be downsampled.
+pub fn downsample(dir: &Path, block: &Block, resolution: i64) -> Result<MetaData> {
+    let meta = &block.meta;
+    let from = meta.group_key().resolution;
+    if resolution <= from {
+        println!(
+            "Block {} has a resolution of {}ms already.",
+            meta.ulid, from
+        );
+        return Err(TSDBError::InvalidMeta);
+    }
+
+    let reader = BlockReader::open(&block.dir)?;
+    let mut writer = BlockWriter::new(dir);
+
+    for s in reader.series()? {
+        let chunks = if from == 0 {
+            let mut samples = Vec::<(i64, f64)>::new();
+            for c in s.chunks.iter() {
+                samples.extend(reader.samples(s.series_ref, c)?);
+            }
+            downsample_raw(&samples, resolution)
+        } else {
+            let mut aggrs = Vec::<[Vec<(i64, f64)>; NUM_AGGR_TYPES]>::new();
+            for c in s.chunks.iter() {
+                let (encoding, data) = reader.chunk(c.chunk_ref)?;
+                if encoding != ENCODING_AGGR {
+                    println!("Block {} holds raw chunks.", meta.ulid);
+                    return Err(TSDBError::Default);
+                }
+                let mut aggr: [Vec<(i64, f64)>; NUM_AGGR_TYPES] = Default::default();
+                for (samples, data) in aggr.iter_mut().zip(decode_aggr_chunk(data)?) {
+                    if let Some(data) = data {
+                        *samples = decode_xor_chunk(data)?;
+                    }
+                }
+                aggrs.push(aggr);
+            }
+            match (s.chunks.first(), s.chunks.last()) {
+                (Some(first), Some(last)) => {
+                    downsample_aggr(&aggrs, first.mint, last.maxt, from, resolution)
+                }
+                _ => Vec::new(),
+            }
+        };
+
+        for c in chunks {
+            writer.add_chunk(s.labels.clone(), c);
+        }
+    }
+
+    // the downsampled block replaces the source block at its resolution
+    writer.set_compaction(BlockCompaction {
+        level: meta.compaction.level,
+        sources: meta.compaction.sources.clone(),
+        parents: vec![BlockDesc {
+            ulid: meta.ulid,
+            min_time: meta.min_time,
+            max_time: meta.max_time,
+        }],
+        ..Default::default()
+    });
+    writer.set_time_range(meta.min_time, meta.max_time);
+
+    let thanos = meta.thanos.clone().unwrap_or_default();
+    writer.set_thanos(ThanosMeta {
+        version: thanos.version.max(1),
+        labels: thanos.labels,
+        downsample: ThanosDownsample { resolution },
+        source: String::from(THANOS_SOURCE_COMPACTOR),
+        ..Default::default()
+    });
+
+    writer.write()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn aggregates(reader: &BlockReader) -> Vec<[Vec<(i64, f64)>; NUM_AGGR_TYPES]> {
+        let mut all = Vec::new();
+        for s in reader.series().unwrap() {
+            for c in s.chunks {
+                let (encoding, data) = reader.chunk(c.chunk_ref).unwrap();
+                assert_eq!(ENCODING_AGGR, encoding);
+                let mut aggr: [Vec<(i64, f64)>; NUM_AGGR_TYPES] = Default::default();
+                for (samples, data) in aggr.iter_mut().zip(decode_aggr_chunk(data).unwrap()) {
+                    *samples = decode_xor_chunk(data.unwrap()).unwrap();
+                }
+                all.push(aggr);
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn downsample_counter() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // a counter increasing by 1 every 15s over 2h with a reset after 1h
+        let samples: Vec<(i64, f64)> = (0..480)
+            .map(|i| (i * 15000, if i < 240 { i } else { i - 240 } as f64))
+            .collect();
+        let mut writer = BlockWriter::new(dir.path());
+        writer.add_series(
+            vec![(String::from("__name__"), String::from("c"))],
+            &samples,
+        );
+        let meta = writer.write().unwrap();
+        let raw = Block::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+
+        let meta = downsample(dir.path(), &raw, RESOLUTION_5M).unwrap();
+        assert_eq!(RESOLUTION_5M, meta.group_key().resolution);
+        assert_eq!(
+            (raw.meta.min_time, raw.meta.max_time),
+            (meta.min_time, meta.max_time)
+        );
+        let block = Block::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        assert!(downsample(dir.path(), &block, RESOLUTION_5M).is_err());
+
+        let reader = BlockReader::open(&block.dir).unwrap();
+        let chunks = aggregates(&reader);
+        assert_eq!(1, chunks.len());
+        let [count, sum, min, max, counter] = &chunks[0];
+        assert_eq!(24, count.len());
+        assert!(count.iter().all(|(_, v)| *v == 20.0));
+        assert_eq!((RESOLUTION_5M - 1, 190.0), sum[0]);
+        assert_eq!((RESOLUTION_5M * 13 - 1, 0.0), min[12]);
+        assert_eq!((RESOLUTION_5M * 12 - 1, 239.0), max[11]);
+        // first and last raw value and the total increase per window
+        assert_eq!(26, counter.len());
+        assert_eq!((0, 0.0), counter[0]);
+        // the last window ends with the last sample
+        assert_eq!((479 * 15000, 478.0), counter[24]);
+        assert_eq!((479 * 15000, 239.0), counter[25]);
+
+        let meta = downsample(dir.path(), &block, RESOLUTION_1H).unwrap();
+        assert_eq!(RESOLUTION_1H, meta.group_key().resolution);
+        assert_eq!(2, meta.stats.num_samples);
+
+        let reader = BlockReader::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        let chunks = aggregates(&reader);
+        let [count, sum, min, max, counter] = &chunks[0];
+        assert_eq!(
+            vec![(RESOLUTION_1H - 1, 240.0), (479 * 15000, 240.0)],
+            *count
+        );
+        assert_eq!((RESOLUTION_1H - 1, 239.0 * 120.0), sum[0]);
+        assert_eq!(vec![(RESOLUTION_1H - 1, 0.0), (479 * 15000, 0.0)], *min);
+        assert_eq!((479 * 15000, 239.0), max[1]);
+        assert_eq!(
+            vec![
+                (0, 0.0),
+                (RESOLUTION_1H - 1, 239.0),
+                (479 * 15000, 478.0),
+                (479 * 15000, 239.0),
+            ],
+            *counter
+        );
+    }
+
+    #[test]
+    fn downsample_stale() {
+        let stale = f64::from_bits(STALE_NAN);
+        let samples: Vec<(i64, f64)> = (0..40).map(|i| (i * 15000, stale)).collect();
+
+        let mut windows = 0;
+        assert_eq!(
+            None,
+            downsample_batch(&samples, RESOLUTION_5M, |_, _| windows += 1)
+        );
+        assert_eq!(0, windows);
+        assert!(downsample_raw(&samples, RESOLUTION_5M).is_empty());
+    }
+}
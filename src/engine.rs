@@ -0,0 +1,1305 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use regex::Regex;
+
+use crate::common::*;
+use crate::labels::{MatchType, METRIC_NAME};
+use crate::promql::*;
+use crate::querier::Querier;
+
+const DEFAULT_LOOKBACK_DELTA: i64 = 5 * 60 * 1000;
+// Prometheus marks series that disappeared with this NaN
+const STALE_NAN: u64 = 0x7ff0000000000002;
+const BUCKET_LABEL: &str = "le";
+// the times Prometheus accepts in queries, in milliseconds
+const MIN_TIME: i64 = (i64::MIN / 1000 + 62135596801) * 1000;
+const MAX_TIME: i64 = (i64::MAX / 1000 - 62135596801) * 1000;
+
+// A sample of an instant vector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Labels,
+    pub t: i64,
+    pub v: f64,
+}
+
+// A series of a range vector or of the result of a range query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeSeries {
+    pub labels: Labels,
+    pub samples: Vec<(i64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(i64, f64),
+    String(i64, String),
+    Vector(Vec<Sample>),
+    Matrix(Vec<RangeSeries>),
+}
+
+// format a sample value like Prometheus does
+pub fn format_value(v: f64) -> String {
+    match v {
+        v if v.is_nan() => String::from("NaN"),
+        v if v == f64::INFINITY => String::from("+Inf"),
+        v if v == f64::NEG_INFINITY => String::from("-Inf"),
+        v => v.to_string(),
+    }
+}
+
+fn is_stale(v: f64) -> bool {
+    v.to_bits() == STALE_NAN
+}
+
+fn drop_name(labels: &mut Labels) {
+    labels.retain(|(n, _)| n != METRIC_NAME);
+}
+
+fn set_label(labels: &mut Labels, name: &str, value: &str) {
+    labels.retain(|(n, _)| n != name);
+    // empty labels are the same as labels that are not set
+    if !value.is_empty() {
+        labels.push((name.to_string(), value.to_string()));
+        labels.sort();
+    }
+}
+
+// Evaluates PromQL queries over a querier, e.g. a single block or all blocks
+// of a data directory.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    // how far back instant vector selectors look for a sample, in milliseconds
+    pub lookback_delta: i64,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self {
+            lookback_delta: DEFAULT_LOOKBACK_DELTA,
+        }
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // evaluate a query at time t in milliseconds
+    pub fn query(&self, querier: &dyn Querier, query: &str, t: i64) -> Result<Value> {
+        if !(MIN_TIME..=MAX_TIME).contains(&t) {
+            return error("time out of range");
+        }
+        let expr = parse(query)?;
+        let mut ev = Evaluator::new(self.lookback_delta, t, t);
+        ev.preload(querier, &expr)?;
+
+        let value = ev.eval(&expr, t)?;
+        if let Value::Vector(v) = &value {
+            let mut seen = HashSet::<&Labels>::with_capacity(v.len());
+            if !v.iter().all(|s| seen.insert(&s.labels)) {
+                return error("vector cannot contain metrics with the same labelset");
+            }
+        }
+
+        Ok(value)
+    }
+
+    // evaluate a query at every step from start to end, both inclusive
+    pub fn query_range(
+        &self,
+        querier: &dyn Querier,
+        query: &str,
+        start: i64,
+        end: i64,
+        step: i64,
+    ) -> Result<Vec<RangeSeries>> {
+        if step <= 0 || end < start {
+            return error("invalid range or step");
+        }
+        if start < MIN_TIME || end > MAX_TIME {
+            return error("time out of range");
+        }
+        let expr = parse(query)?;
+        let mut ev = Evaluator::new(self.lookback_delta, start, end);
+        ev.preload(querier, &expr)?;
+
+        let mut series = BTreeMap::<Labels, Vec<(i64, f64)>>::new();
+        let mut t = start;
+        while t <= end {
+            match ev.eval(&expr, t)? {
+                Value::Scalar(_, v) => series.entry(Vec::new()).or_default().push((t, v)),
+                Value::Vector(vector) => {
+                    for s in vector {
+                        let samples = series.entry(s.labels).or_default();
+                        if samples.last().is_some_and(|(last, _)| *last == t) {
+                            return error("vector cannot contain metrics with the same labelset");
+                        }
+                        samples.push((t, s.v));
+                    }
+                }
+                _ => return error("range queries must return a scalar or instant vector"),
+            }
+            t = match t.checked_add(step) {
+                Some(t) => t,
+                None => break,
+            };
+        }
+
+        Ok(series
+            .into_iter()
+            .map(|(labels, samples)| RangeSeries { labels, samples })
+            .collect())
+    }
+}
+
+struct Evaluator {
+    lookback_delta: i64,
+    start: i64,
+    end: i64,
+    // samples of every selector of the query, keyed by its address
+    data: HashMap<*const VectorSelector, Vec<RangeSeries>>,
+}
+
+impl Evaluator {
+    fn new(lookback_delta: i64, start: i64, end: i64) -> Self {
+        Self {
+            lookback_delta,
+            start,
+            end,
+            data: HashMap::new(),
+        }
+    }
+
+    fn at_time(&self, vs: &VectorSelector, t: i64) -> i64 {
+        let t = match vs.at {
+            Some(At::Time(at)) => at,
+            Some(At::Start) => self.start,
+            Some(At::End) => self.end,
+            None => t,
+        };
+        t.saturating_sub(vs.offset)
+    }
+
+    // Select the samples of all selectors once for the whole query range, so
+    // chunks are not decoded again for every step.
+    fn preload(&mut self, querier: &dyn Querier, expr: &Expr) -> Result<()> {
+        match expr {
+            Expr::VectorSelector(vs) => self.load(querier, vs, self.lookback_delta),
+            Expr::MatrixSelector(vs, range) => self.load(querier, vs, *range),
+            Expr::Paren(e) | Expr::Unary(e) => self.preload(querier, e),
+            Expr::Call(_, args) => {
+                for a in args {
+                    self.preload(querier, a)?;
+                }
+                Ok(())
+            }
+            Expr::Aggregate { expr, param, .. } => {
+                self.preload(querier, expr)?;
+                match param {
+                    Some(p) => self.preload(querier, p),
+                    None => Ok(()),
+                }
+            }
+            Expr::Binary { lhs, rhs, .. } => {
+                self.preload(querier, lhs)?;
+                self.preload(querier, rhs)
+            }
+            Expr::Number(_) | Expr::String(_) => Ok(()),
+        }
+    }
+
+    fn load(&mut self, querier: &dyn Querier, vs: &VectorSelector, window: i64) -> Result<()> {
+        // windows are open on the left
+        let mint = self
+            .at_time(vs, self.start)
+            .saturating_sub(window)
+            .saturating_add(1);
+        let maxt = self.at_time(vs, self.end);
+
+        let mut all = Vec::<RangeSeries>::new();
+        for s in querier.select(&vs.matchers, mint, maxt)? {
+            let labels = s.labels.clone();
+            all.push(RangeSeries {
+                labels,
+                samples: s.collect(),
+            });
+        }
+        self.data.insert(vs as *const VectorSelector, all);
+
+        Ok(())
+    }
+
+    fn series(&self, vs: &VectorSelector) -> &[RangeSeries] {
+        self.data
+            .get(&(vs as *const VectorSelector))
+            .map_or(&[], |d| d.as_slice())
+    }
+
+    // latest sample within the lookback delta for every series, with the time
+    // of the sample
+    fn select_vector(&self, vs: &VectorSelector, t: i64) -> Vec<Sample> {
+        let ts = self.at_time(vs, t);
+        let mut vector = Vec::<Sample>::new();
+        for s in self.series(vs) {
+            let n = s.samples.partition_point(|(st, _)| *st <= ts);
+            if n == 0 {
+                continue;
+            }
+            let (st, v) = s.samples[n - 1];
+            if st > ts.saturating_sub(self.lookback_delta) && !is_stale(v) {
+                vector.push(Sample {
+                    labels: s.labels.clone(),
+                    t: st,
+                    v,
+                });
+            }
+        }
+        vector
+    }
+
+    // samples within the range for every series with the bounds of the range
+    fn select_matrix(&self, expr: &Expr, t: i64) -> Result<(Vec<RangeSeries>, i64, i64)> {
+        let (vs, range) = match expr {
+            Expr::MatrixSelector(vs, range) => (vs, *range),
+            Expr::Paren(e) => return self.select_matrix(e, t),
+            _ => return error("expected range vector"),
+        };
+
+        let end = self.at_time(vs, t);
+        let start = end.saturating_sub(range);
+        let mut matrix = Vec::<RangeSeries>::new();
+        for s in self.series(vs) {
+            let from = s.samples.partition_point(|(st, _)| *st <= start);
+            let to = s.samples.partition_point(|(st, _)| *st <= end);
+            let samples: Vec<(i64, f64)> = s.samples[from..to]
+                .iter()
+                .filter(|(_, v)| !is_stale(*v))
+                .copied()
+                .collect();
+            if !samples.is_empty() {
+                matrix.push(RangeSeries {
+                    labels: s.labels.clone(),
+                    samples,
+                });
+            }
+        }
+
+        Ok((matrix, start, end))
+    }
+
+    fn eval(&self, expr: &Expr, t: i64) -> Result<Value> {
+        match expr {
+            Expr::Number(n) => Ok(Value::Scalar(t, *n)),
+            Expr::String(s) => Ok(Value::String(t, s.clone())),
+            Expr::Paren(e) => self.eval(e, t),
+            Expr::Unary(e) => match self.eval(e, t)? {
+                Value::Scalar(_, v) => Ok(Value::Scalar(t, -v)),
+                Value::Vector(mut vector) => {
+                    for s in vector.iter_mut() {
+                        drop_name(&mut s.labels);
+                        s.v = -s.v;
+                    }
+                    Ok(Value::Vector(vector))
+                }
+                _ => error("unary minus expects a scalar or instant vector"),
+            },
+            Expr::VectorSelector(vs) => {
+                let mut vector = self.select_vector(vs, t);
+                for s in vector.iter_mut() {
+                    s.t = t;
+                }
+                Ok(Value::Vector(vector))
+            }
+            Expr::MatrixSelector(..) => Ok(Value::Matrix(self.select_matrix(expr, t)?.0)),
+            Expr::Call(name, args) => self.call(name, args, t),
+            Expr::Aggregate {
+                op,
+                expr,
+                param,
+                grouping,
+                without,
+            } => self.aggregate(*op, expr, param.as_deref(), grouping, *without, t),
+            Expr::Binary {
+                op,
+                lhs,
+                rhs,
+                return_bool,
+                matching,
+            } => self.binary(*op, lhs, rhs, *return_bool, matching, t),
+        }
+    }
+
+    fn vector(&self, expr: &Expr, t: i64) -> Result<Vec<Sample>> {
+        match self.eval(expr, t)? {
+            Value::Vector(v) => Ok(v),
+            _ => error("expected instant vector"),
+        }
+    }
+
+    fn scalar(&self, expr: &Expr, t: i64) -> Result<f64> {
+        match self.eval(expr, t)? {
+            Value::Scalar(_, v) => Ok(v),
+            _ => error("expected scalar"),
+        }
+    }
+
+    fn string(&self, expr: &Expr, t: i64) -> Result<String> {
+        match self.eval(expr, t)? {
+            Value::String(_, s) => Ok(s),
+            _ => error("expected string"),
+        }
+    }
+
+    // apply a function to the samples of every series of a range vector
+    fn over_range(
+        &self,
+        arg: &Expr,
+        t: i64,
+        keep_name: bool,
+        f: impl Fn(&[(i64, f64)], i64, i64) -> Option<f64>,
+    ) -> Result<Value> {
+        let (matrix, start, end) = self.select_matrix(arg, t)?;
+        let mut vector = Vec::<Sample>::with_capacity(matrix.len());
+        for mut s in matrix {
+            if let Some(v) = f(&s.samples, start, end) {
+                if !keep_name {
+                    drop_name(&mut s.labels);
+                }
+                vector.push(Sample {
+                    labels: s.labels,
+                    t,
+                    v,
+                });
+            }
+        }
+        Ok(Value::Vector(vector))
+    }
+
+    // apply a function to the value of every sample of an instant vector
+    fn map(&self, arg: &Expr, t: i64, f: impl Fn(f64) -> f64) -> Result<Value> {
+        let mut vector = self.vector(arg, t)?;
+        for s in vector.iter_mut() {
+            drop_name(&mut s.labels);
+            s.v = f(s.v);
+        }
+        Ok(Value::Vector(vector))
+    }
+
+    // NOTE: Functions as documented by Prometheus:
+    // https://prometheus.io/docs/prometheus/latest/querying/functions/
+    fn call(&self, name: &str, args: &[Expr], t: i64) -> Result<Value> {
+        let (min_args, max_args) = match name {
+            "time" => (0, 0),
+            "round" => (1, 2),
+            "label_join" => (3, usize::MAX),
+            "label_replace" => (5, 5),
+            "clamp" => (3, 3),
+            "quantile_over_time" | "predict_linear" | "histogram_quantile" | "clamp_min"
+            | "clamp_max" => (2, 2),
+            _ => (1, 1),
+        };
+        if args.len() < min_args || args.len() > max_args {
+            return error(&format!("wrong number of arguments for {}", name));
+        }
+
+        match name {
+            "rate" => self.over_range(&args[0], t, false, |s, start, end| {
+                extrapolated_rate(s, start, end, true, true)
+            }),
+            "increase" => self.over_range(&args[0], t, false, |s, start, end| {
+                extrapolated_rate(s, start, end, true, false)
+            }),
+            "delta" => self.over_range(&args[0], t, false, |s, start, end| {
+                extrapolated_rate(s, start, end, false, false)
+            }),
+            "irate" | "idelta" => self.over_range(&args[0], t, false, |s, _, _| {
+                let (prev, last) = match s {
+                    [.., prev, last] => (prev, last),
+                    _ => return None,
+                };
+                if name == "idelta" {
+                    return Some(last.1 - prev.1);
+                }
+                let increase = if last.1 < prev.1 {
+                    last.1
+                } else {
+                    last.1 - prev.1
+                };
+                Some(increase / ((last.0 - prev.0) as f64 / 1000.0))
+            }),
+            "deriv" => self.over_range(&args[0], t, false, |s, _, _| {
+                (s.len() >= 2).then(|| linear_regression(s, s[0].0).0)
+            }),
+            "predict_linear" => {
+                let duration = self.scalar(&args[1], t)?;
+                self.over_range(&args[0], t, false, |s, _, end| {
+                    let (slope, intercept) = linear_regression(s, end);
+                    (s.len() >= 2).then_some(intercept + slope * duration)
+                })
+            }
+            "changes" | "resets" => self.over_range(&args[0], t, false, |s, _, _| {
+                let n = s
+                    .windows(2)
+                    .filter(|w| match name {
+                        "changes" => w[0].1 != w[1].1 && !(w[0].1.is_nan() && w[1].1.is_nan()),
+                        _ => w[1].1 < w[0].1,
+                    })
+                    .count();
+                Some(n as f64)
+            }),
+            "avg_over_time" => self.over_range(&args[0], t, false, |s, _, _| {
+                Some(s.iter().map(|(_, v)| v).sum::<f64>() / s.len() as f64)
+            }),
+            "sum_over_time" => self.over_range(&args[0], t, false, |s, _, _| {
+                Some(s.iter().map(|(_, v)| v).sum::<f64>())
+            }),
+            "min_over_time" => self.over_range(&args[0], t, false, |s, _, _| {
+                s.iter().map(|(_, v)| *v).reduce(min)
+            }),
+            "max_over_time" => self.over_range(&args[0], t, false, |s, _, _| {
+                s.iter().map(|(_, v)| *v).reduce(max)
+            }),
+            "count_over_time" => {
+                self.over_range(&args[0], t, false, |s, _, _| Some(s.len() as f64))
+            }
+            "present_over_time" => self.over_range(&args[0], t, false, |_, _, _| Some(1.0)),
+            "last_over_time" => self.over_range(&args[0], t, true, |s, _, _| s.last().map(|l| l.1)),
+            "stddev_over_time" | "stdvar_over_time" => {
+                self.over_range(&args[0], t, false, |s, _, _| {
+                    let values: Vec<f64> = s.iter().map(|(_, v)| *v).collect();
+                    let var = variance(&values);
+                    Some(if name == "stddev_over_time" {
+                        var.sqrt()
+                    } else {
+                        var
+                    })
+                })
+            }
+            "quantile_over_time" => {
+                let q = self.scalar(&args[0], t)?;
+                self.over_range(&args[1], t, false, |s, _, _| {
+                    let values: Vec<f64> = s.iter().map(|(_, v)| *v).collect();
+                    Some(quantile(q, values))
+                })
+            }
+            "abs" => self.map(&args[0], t, f64::abs),
+            "ceil" => self.map(&args[0], t, f64::ceil),
+            "floor" => self.map(&args[0], t, f64::floor),
+            "exp" => self.map(&args[0], t, f64::exp),
+            "sqrt" => self.map(&args[0], t, f64::sqrt),
+            "ln" => self.map(&args[0], t, f64::ln),
+            "log2" => self.map(&args[0], t, f64::log2),
+            "log10" => self.map(&args[0], t, f64::log10),
+            "sgn" => self.map(&args[0], t, |v| {
+                if v == 0.0 || v.is_nan() {
+                    v
+                } else {
+                    v.signum()
+                }
+            }),
+            "round" => {
+                let to_nearest = match args.get(1) {
+                    Some(e) => self.scalar(e, t)?,
+                    None => 1.0,
+                };
+                let inverse = 1.0 / to_nearest;
+                self.map(&args[0], t, |v| (v * inverse + 0.5).floor() / inverse)
+            }
+            "clamp" | "clamp_min" | "clamp_max" => {
+                let (lo, hi) = match name {
+                    "clamp" => (self.scalar(&args[1], t)?, self.scalar(&args[2], t)?),
+                    "clamp_min" => (self.scalar(&args[1], t)?, f64::INFINITY),
+                    _ => (f64::NEG_INFINITY, self.scalar(&args[1], t)?),
+                };
+                if lo > hi {
+                    return Ok(Value::Vector(Vec::new()));
+                }
+                // NaN values or bounds give NaN like math.Max and math.Min of Go
+                self.map(&args[0], t, |v| {
+                    if v.is_nan() || lo.is_nan() || hi.is_nan() {
+                        f64::NAN
+                    } else {
+                        v.clamp(lo, hi)
+                    }
+                })
+            }
+            "histogram_quantile" => {
+                let q = self.scalar(&args[0], t)?;
+                let vector = self.vector(&args[1], t)?;
+                Ok(Value::Vector(histogram_quantile(q, vector, t)))
+            }
+            "time" => Ok(Value::Scalar(t, t as f64 / 1000.0)),
+            "timestamp" => {
+                let mut vector = match &args[0] {
+                    Expr::VectorSelector(vs) => self.select_vector(vs, t),
+                    e => self.vector(e, t)?,
+                };
+                for s in vector.iter_mut() {
+                    drop_name(&mut s.labels);
+                    s.v = s.t as f64 / 1000.0;
+                    s.t = t;
+                }
+                Ok(Value::Vector(vector))
+            }
+            "scalar" => match self.vector(&args[0], t)?.as_slice() {
+                [s] => Ok(Value::Scalar(t, s.v)),
+                _ => Ok(Value::Scalar(t, f64::NAN)),
+            },
+            "vector" => Ok(Value::Vector(vec![Sample {
+                labels: Vec::new(),
+                t,
+                v: self.scalar(&args[0], t)?,
+            }])),
+            "sort" | "sort_desc" => {
+                let mut vector = self.vector(&args[0], t)?;
+                // NaN sorts last in both directions
+                vector.sort_by(|a, b| match (a.v.is_nan(), b.v.is_nan()) {
+                    (false, false) if name == "sort" => a.v.total_cmp(&b.v),
+                    (false, false) => b.v.total_cmp(&a.v),
+                    (a, b) => a.cmp(&b),
+                });
+                Ok(Value::Vector(vector))
+            }
+            "label_replace" => {
+                let dst = self.string(&args[1], t)?;
+                let replacement = self.string(&args[2], t)?;
+                let src = self.string(&args[3], t)?;
+                let regex = self.string(&args[4], t)?;
+                let re = match Regex::new(&format!("^(?:{})$", regex)) {
+                    Ok(re) => re,
+                    Err(_) => return error(&format!("invalid regular expression {:?}", regex)),
+                };
+
+                let mut vector = self.vector(&args[0], t)?;
+                for s in vector.iter_mut() {
+                    let value = crate::labels::get(&s.labels, &src).to_string();
+                    if let Some(caps) = re.captures(&value) {
+                        let mut replaced = String::new();
+                        caps.expand(&replacement, &mut replaced);
+                        set_label(&mut s.labels, &dst, &replaced);
+                    }
+                }
+                Ok(Value::Vector(vector))
+            }
+            "label_join" => {
+                let dst = self.string(&args[1], t)?;
+                let separator = self.string(&args[2], t)?;
+                let mut src = Vec::<String>::with_capacity(args.len() - 3);
+                for a in args[3..].iter() {
+                    src.push(self.string(a, t)?);
+                }
+
+                let mut vector = self.vector(&args[0], t)?;
+                for s in vector.iter_mut() {
+                    let joined = src
+                        .iter()
+                        .map(|name| crate::labels::get(&s.labels, name))
+                        .collect::<Vec<&str>>()
+                        .join(&separator);
+                    set_label(&mut s.labels, &dst, &joined);
+                }
+                Ok(Value::Vector(vector))
+            }
+            "absent" | "absent_over_time" => {
+                let (present, vs) = match &args[0] {
+                    Expr::VectorSelector(vs) if name == "absent" => {
+                        (!self.select_vector(vs, t).is_empty(), Some(vs))
+                    }
+                    Expr::MatrixSelector(vs, _) if name == "absent_over_time" => {
+                        (!self.select_matrix(&args[0], t)?.0.is_empty(), Some(vs))
+                    }
+                    e if name == "absent" => (!self.vector(e, t)?.is_empty(), None),
+                    _ => return error("absent_over_time expects a range vector selector"),
+                };
+                if present {
+                    return Ok(Value::Vector(Vec::new()));
+                }
+
+                // labels of the equality matchers, the metric name is dropped
+                let mut labels = Labels::new();
+                for m in vs.map_or(&[][..], |vs| vs.matchers.as_slice()) {
+                    if m.match_type == MatchType::Equal && m.name != METRIC_NAME {
+                        set_label(&mut labels, &m.name, &m.value);
+                    }
+                }
+                Ok(Value::Vector(vec![Sample { labels, t, v: 1.0 }]))
+            }
+            _ => error(&format!("unknown function {}", name)),
+        }
+    }
+
+    fn aggregate(
+        &self,
+        op: AggrOp,
+        expr: &Expr,
+        param: Option<&Expr>,
+        grouping: &[String],
+        without: bool,
+        t: i64,
+    ) -> Result<Value> {
+        let (k, label) = match (op, param) {
+            (AggrOp::CountValues, Some(p)) => (0.0, self.string(p, t)?),
+            (_, Some(p)) => (self.scalar(p, t)?, String::new()),
+            _ => (0.0, String::new()),
+        };
+
+        let mut groups = BTreeMap::<Labels, Vec<Sample>>::new();
+        for s in self.vector(expr, t)? {
+            let key: Labels = s
+                .labels
+                .iter()
+                .filter(|(n, _)| {
+                    if without {
+                        n != METRIC_NAME && !grouping.contains(n)
+                    } else {
+                        grouping.contains(n)
+                    }
+                })
+                .cloned()
+                .collect();
+            groups.entry(key).or_default().push(s);
+        }
+
+        let mut vector = Vec::<Sample>::with_capacity(groups.len());
+        for (labels, mut samples) in groups {
+            let values: Vec<f64> = samples.iter().map(|s| s.v).collect();
+            let v = match op {
+                AggrOp::Sum => values.iter().sum(),
+                AggrOp::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                AggrOp::Count => values.len() as f64,
+                AggrOp::Group => 1.0,
+                AggrOp::Min => values.iter().copied().reduce(min).unwrap_or(f64::NAN),
+                AggrOp::Max => values.iter().copied().reduce(max).unwrap_or(f64::NAN),
+                AggrOp::Stdvar => variance(&values),
+                AggrOp::Stddev => variance(&values).sqrt(),
+                AggrOp::Quantile => quantile(k, values),
+                AggrOp::Topk | AggrOp::Bottomk => {
+                    // the samples keep their labels, NaN sorts last
+                    samples.sort_by(|a, b| match (a.v.is_nan(), b.v.is_nan()) {
+                        (false, false) if op == AggrOp::Topk => b.v.total_cmp(&a.v),
+                        (false, false) => a.v.total_cmp(&b.v),
+                        (a, b) => a.cmp(&b),
+                    });
+                    samples.truncate(k.max(0.0) as usize);
+                    for s in samples.iter_mut() {
+                        s.t = t;
+                    }
+                    vector.extend(samples);
+                    continue;
+                }
+                AggrOp::CountValues => {
+                    let mut counts = BTreeMap::<String, usize>::new();
+                    for v in values {
+                        *counts.entry(format_value(v)).or_default() += 1;
+                    }
+                    for (value, count) in counts {
+                        let mut labels = labels.clone();
+                        set_label(&mut labels, &label, &value);
+                        vector.push(Sample {
+                            labels,
+                            t,
+                            v: count as f64,
+                        });
+                    }
+                    continue;
+                }
+            };
+            vector.push(Sample { labels, t, v });
+        }
+
+        Ok(Value::Vector(vector))
+    }
+
+    fn binary(
+        &self,
+        op: BinOp,
+        lhs: &Expr,
+        rhs: &Expr,
+        return_bool: bool,
+        matching: &VectorMatching,
+        t: i64,
+    ) -> Result<Value> {
+        match (self.eval(lhs, t)?, self.eval(rhs, t)?) {
+            (Value::Scalar(_, a), Value::Scalar(_, b)) => {
+                if op.is_set() {
+                    return error("set operations are only allowed between instant vectors");
+                }
+                if op.is_comparison() && !return_bool {
+                    return error("comparisons between scalars must use bool modifier");
+                }
+                let (v, keep) = apply(op, a, b);
+                Ok(Value::Scalar(
+                    t,
+                    if return_bool { keep as u8 as f64 } else { v },
+                ))
+            }
+            (Value::Vector(vector), Value::Scalar(_, s)) => {
+                vector_scalar(op, vector, s, false, return_bool)
+            }
+            (Value::Scalar(_, s), Value::Vector(vector)) => {
+                vector_scalar(op, vector, s, true, return_bool)
+            }
+            (Value::Vector(a), Value::Vector(b)) if op.is_set() => {
+                Ok(Value::Vector(set_operation(op, a, b, matching)))
+            }
+            (Value::Vector(a), Value::Vector(b)) => {
+                vector_vector(op, a, b, return_bool, matching).map(Value::Vector)
+            }
+            _ => error("binary operations expect scalars or instant vectors"),
+        }
+    }
+}
+
+// min and max where NaN only wins over NaN
+fn min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b < a {
+        b
+    } else {
+        a
+    }
+}
+
+fn max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b > a {
+        b
+    } else {
+        a
+    }
+}
+
+fn variance(values: &[f64]) -> f64 {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n
+}
+
+// the φ-quantile with linear interpolation between the closest ranks
+fn quantile(q: f64, mut values: Vec<f64>) -> f64 {
+    if values.is_empty() || q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+
+    let rank = q * (values.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = (lower + 1).min(values.len() - 1);
+    let weight = rank - rank.floor();
+    values[lower] * (1.0 - weight) + values[upper] * weight
+}
+
+// least squares fit of the samples, times in seconds relative to the
+// intercept time
+fn linear_regression(samples: &[(i64, f64)], intercept_time: i64) -> (f64, f64) {
+    let n = samples.len() as f64;
+    let (mut sum_x, mut sum_y, mut sum_xy, mut sum_x2) = (0.0, 0.0, 0.0, 0.0);
+    for (t, v) in samples {
+        let x = (t - intercept_time) as f64 / 1000.0;
+        sum_x += x;
+        sum_y += v;
+        sum_xy += x * v;
+        sum_x2 += x * x;
+    }
+
+    let cov = sum_xy - sum_x * sum_y / n;
+    let var = sum_x2 - sum_x * sum_x / n;
+    if var == 0.0 {
+        return (0.0, sum_y / n);
+    }
+    let slope = cov / var;
+    (slope, sum_y / n - slope * sum_x / n)
+}
+
+// NOTE: Port of the extrapolation of rate, increase and delta in Prometheus:
+// https://github.com/prometheus/prometheus/blob/main/promql/functions.go
+//
+// The increase between the first and the last sample is extrapolated to the
+// bounds of the range, unless they are further away than 1.1 times the
+// average sample distance. Counters are not extrapolated below zero.
+fn extrapolated_rate(
+    samples: &[(i64, f64)],
+    range_start: i64,
+    range_end: i64,
+    is_counter: bool,
+    is_rate: bool,
+) -> Option<f64> {
+    let (first, last) = match samples {
+        [first, .., last] => (first, last),
+        _ => return None,
+    };
+
+    let mut result = last.1 - first.1;
+    if is_counter {
+        for w in samples.windows(2) {
+            if w[1].1 < w[0].1 {
+                result += w[0].1;
+            }
+        }
+    }
+
+    let mut to_start = (first.0 - range_start) as f64 / 1000.0;
+    let to_end = (range_end - last.0) as f64 / 1000.0;
+    let sampled = (last.0 - first.0) as f64 / 1000.0;
+    let average = sampled / (samples.len() - 1) as f64;
+
+    if is_counter && result > 0.0 && first.1 >= 0.0 {
+        let to_zero = sampled * (first.1 / result);
+        if to_zero < to_start {
+            to_start = to_zero;
+        }
+    }
+
+    let threshold = average * 1.1;
+    let mut interval = sampled;
+    interval += if to_start < threshold {
+        to_start
+    } else {
+        average / 2.0
+    };
+    interval += if to_end < threshold {
+        to_end
+    } else {
+        average / 2.0
+    };
+
+    let mut factor = interval / sampled;
+    if is_rate {
+        factor /= (range_end - range_start) as f64 / 1000.0;
+    }
+
+    Some(result * factor)
+}
+
+// result of an operation and if comparisons are true
+fn apply(op: BinOp, a: f64, b: f64) -> (f64, bool) {
+    match op {
+        BinOp::Add => (a + b, true),
+        BinOp::Sub => (a - b, true),
+        BinOp::Mul => (a * b, true),
+        BinOp::Div => (a / b, true),
+        BinOp::Mod => (a % b, true),
+        BinOp::Pow => (a.powf(b), true),
+        BinOp::Atan2 => (a.atan2(b), true),
+        BinOp::Eql => (a, a == b),
+        BinOp::Neq => (a, a != b),
+        BinOp::Lss => (a, a < b),
+        BinOp::Gtr => (a, a > b),
+        BinOp::Lte => (a, a <= b),
+        BinOp::Gte => (a, a >= b),
+        BinOp::And | BinOp::Or | BinOp::Unless => (a, false),
+    }
+}
+
+// The vector keeps its values for comparisons without bool, even if the
+// scalar is the left operand. Other operations drop the metric name.
+fn vector_scalar(
+    op: BinOp,
+    mut vector: Vec<Sample>,
+    scalar: f64,
+    swapped: bool,
+    return_bool: bool,
+) -> Result<Value> {
+    if op.is_set() {
+        return error("set operations are only allowed between instant vectors");
+    }
+
+    let filter = op.is_comparison() && !return_bool;
+    vector.retain_mut(|s| {
+        let (v, keep) = if swapped {
+            apply(op, scalar, s.v)
+        } else {
+            apply(op, s.v, scalar)
+        };
+        if filter {
+            return keep;
+        }
+        drop_name(&mut s.labels);
+        s.v = if return_bool { keep as u8 as f64 } else { v };
+        true
+    });
+
+    Ok(Value::Vector(vector))
+}
+
+// labels used to match samples of both sides
+fn signature(labels: &Labels, matching: &VectorMatching) -> Labels {
+    labels
+        .iter()
+        .filter(|(n, _)| {
+            if matching.on {
+                matching.labels.contains(n)
+            } else {
+                n != METRIC_NAME && !matching.labels.contains(n)
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+fn set_operation(
+    op: BinOp,
+    lhs: Vec<Sample>,
+    rhs: Vec<Sample>,
+    matching: &VectorMatching,
+) -> Vec<Sample> {
+    let rhs_sigs: HashSet<Labels> = rhs.iter().map(|s| signature(&s.labels, matching)).collect();
+    match op {
+        BinOp::And => lhs
+            .into_iter()
+            .filter(|s| rhs_sigs.contains(&signature(&s.labels, matching)))
+            .collect(),
+        BinOp::Unless => lhs
+            .into_iter()
+            .filter(|s| !rhs_sigs.contains(&signature(&s.labels, matching)))
+            .collect(),
+        _ => {
+            let lhs_sigs: HashSet<Labels> =
+                lhs.iter().map(|s| signature(&s.labels, matching)).collect();
+            let mut result = lhs;
+            result.extend(
+                rhs.into_iter()
+                    .filter(|s| !lhs_sigs.contains(&signature(&s.labels, matching))),
+            );
+            result
+        }
+    }
+}
+
+// NOTE: Vector matching as documented by Prometheus:
+// https://prometheus.io/docs/prometheus/latest/querying/operators/#vector-matching
+//
+// Every sample of the many side has to match exactly one sample of the one
+// side. For one-to-one matching both sides are the one side.
+fn vector_vector(
+    op: BinOp,
+    lhs: Vec<Sample>,
+    rhs: Vec<Sample>,
+    return_bool: bool,
+    matching: &VectorMatching,
+) -> Result<Vec<Sample>> {
+    let swapped = matching.card == Cardinality::OneToMany;
+    let (many, one) = if swapped { (rhs, lhs) } else { (lhs, rhs) };
+
+    let mut by_sig = HashMap::<Labels, &Sample>::with_capacity(one.len());
+    for s in one.iter() {
+        if by_sig.insert(signature(&s.labels, matching), s).is_some() {
+            return error(
+                "many-to-many matching not allowed: found duplicate series on the one side",
+            );
+        }
+    }
+
+    let mut matched = HashSet::<Labels>::new();
+    let mut result = Vec::<Sample>::new();
+    for s in many {
+        let sig = signature(&s.labels, matching);
+        let o = match by_sig.get(&sig) {
+            Some(o) => o,
+            None => continue,
+        };
+        if matching.card == Cardinality::OneToOne && !matched.insert(sig) {
+            return error(
+                "many-to-many matching not allowed: found duplicate series on the left side",
+            );
+        }
+
+        let (a, b) = if swapped { (o.v, s.v) } else { (s.v, o.v) };
+        let (v, keep) = apply(op, a, b);
+        if op.is_comparison() && !return_bool && !keep {
+            continue;
+        }
+
+        let mut labels = s.labels;
+        if !op.is_comparison() || return_bool {
+            drop_name(&mut labels);
+        }
+        if matching.card == Cardinality::OneToOne {
+            labels = if matching.on {
+                signature(&labels, matching)
+            } else {
+                labels
+                    .into_iter()
+                    .filter(|(n, _)| !matching.labels.contains(n))
+                    .collect()
+            };
+        }
+        for name in matching.include.iter() {
+            set_label(&mut labels, name, crate::labels::get(&o.labels, name));
+        }
+
+        result.push(Sample {
+            labels,
+            t: s.t,
+            v: if return_bool { keep as u8 as f64 } else { v },
+        });
+    }
+
+    let mut seen = HashSet::<&Labels>::with_capacity(result.len());
+    if !result.iter().all(|s| seen.insert(&s.labels)) {
+        return error("multiple matches for labels: grouping labels must ensure unique matches");
+    }
+
+    Ok(result)
+}
+
+// NOTE: Port of the bucket interpolation of histogram_quantile in Prometheus:
+// https://github.com/prometheus/prometheus/blob/main/promql/quantile.go
+//
+// Buckets of a histogram are the series that only differ in the le label.
+// The quantile is interpolated linearly within the bucket it falls into.
+fn histogram_quantile(q: f64, vector: Vec<Sample>, t: i64) -> Vec<Sample> {
+    let mut histograms = BTreeMap::<Labels, Vec<(f64, f64)>>::new();
+    for mut s in vector {
+        let upper = match crate::labels::get(&s.labels, BUCKET_LABEL) {
+            "+Inf" | "Inf" | "inf" => f64::INFINITY,
+            le => match le.parse::<f64>() {
+                Ok(upper) => upper,
+                Err(_) => continue,
+            },
+        };
+        drop_name(&mut s.labels);
+        s.labels.retain(|(n, _)| n != BUCKET_LABEL);
+        histograms.entry(s.labels).or_default().push((upper, s.v));
+    }
+
+    histograms
+        .into_iter()
+        .map(|(labels, buckets)| Sample {
+            labels,
+            t,
+            v: bucket_quantile(q, buckets),
+        })
+        .collect()
+}
+
+fn bucket_quantile(q: f64, mut buckets: Vec<(f64, f64)>) -> f64 {
+    if q.is_nan() {
+        return f64::NAN;
+    }
+    if q < 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if q > 1.0 {
+        return f64::INFINITY;
+    }
+
+    buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if buckets.last().map(|b| b.0) != Some(f64::INFINITY) {
+        return f64::NAN;
+    }
+    // merge buckets with the same bound and make the counts monotonic
+    buckets.dedup_by(|b, a| {
+        let same = a.0 == b.0;
+        if same {
+            a.1 += b.1;
+        }
+        same
+    });
+    for i in 1..buckets.len() {
+        buckets[i].1 = buckets[i].1.max(buckets[i - 1].1);
+    }
+    if buckets.len() < 2 {
+        return f64::NAN;
+    }
+
+    let observations = buckets[buckets.len() - 1].1;
+    if observations == 0.0 {
+        return f64::NAN;
+    }
+    let mut rank = q * observations;
+    let b = buckets.partition_point(|(_, count)| *count < rank);
+
+    if b == buckets.len() - 1 {
+        return buckets[buckets.len() - 2].0;
+    }
+    if b == 0 && buckets[0].0 <= 0.0 {
+        return buckets[0].0;
+    }
+
+    let (mut start, end, mut count) = (0.0, buckets[b].0, buckets[b].1);
+    if b > 0 {
+        start = buckets[b - 1].0;
+        count -= buckets[b - 1].1;
+        rank -= buckets[b - 1].1;
+    }
+    start + (end - start) * (rank / count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::BlockWriter;
+    use crate::querier::BlockQuerier;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        let mut l: Labels = pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        l.sort();
+        l
+    }
+
+    fn assert_vector(expected: &[(Labels, f64)], value: Value) {
+        let vector = match value {
+            Value::Vector(v) => v,
+            v => panic!("expected vector, got {:?}", v),
+        };
+        assert_eq!(expected.len(), vector.len(), "{:?}", vector);
+        for ((labels, v), s) in expected.iter().zip(vector.iter()) {
+            assert_eq!(*labels, s.labels);
+            assert!((v - s.v).abs() < 1e-9, "expected {}, got {}", v, s.v);
+        }
+    }
+
+    #[test]
+    fn evaluate_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        // an hour of samples every 15s
+        let range = |f: &dyn Fn(i64) -> f64| -> Vec<(i64, f64)> {
+            (0..240).map(|i| (i * 15000, f(i))).collect()
+        };
+
+        let mut writer = BlockWriter::new(dir.path());
+        for (job, per_sample) in [("a", 1.0), ("b", 3.0)] {
+            let requests = labels(&[("__name__", "requests_total"), ("job", job)]);
+            writer.add_series(requests, &range(&|i| i as f64 * per_sample));
+        }
+        // 10% of the requests take less than 0.1s and 90% less than 1s
+        for (le, share) in [("0.1", 0.1), ("1", 0.9), ("+Inf", 1.0)] {
+            let bucket = labels(&[("__name__", "duration_bucket"), ("job", "a"), ("le", le)]);
+            writer.add_series(bucket, &range(&|i| i as f64 * 10.0 * share));
+        }
+        writer.add_series(
+            labels(&[("__name__", "temperature"), ("job", "a")]),
+            &range(&|i| (i % 4) as f64),
+        );
+        let meta = writer.write().unwrap();
+
+        let querier = BlockQuerier::open(&dir.path().join(meta.ulid.to_string())).unwrap();
+        let engine = Engine::new();
+        let t = 30 * 60 * 1000;
+        let a = labels(&[("job", "a")]);
+        let b = labels(&[("job", "b")]);
+
+        let rate = engine
+            .query(&querier, "rate(requests_total[5m])", t)
+            .unwrap();
+        assert_vector(&[(a.clone(), 1.0 / 15.0), (b.clone(), 3.0 / 15.0)], rate);
+
+        let sum = engine
+            .query(
+                &querier,
+                "sum by (job) (increase(requests_total[1m])) * 2 + 1",
+                t,
+            )
+            .unwrap();
+        assert_vector(&[(a.clone(), 9.0), (b.clone(), 25.0)], sum);
+
+        // the median falls into the second bucket
+        let median = engine
+            .query(
+                &querier,
+                "histogram_quantile(0.5, sum by (le, job) (rate(duration_bucket[5m])))",
+                t,
+            )
+            .unwrap();
+        assert_vector(&[(a.clone(), 0.1 + 0.9 * 0.4 / 0.8)], median);
+
+        let over_time = engine
+            .query(
+                &querier,
+                "max_over_time(temperature[1m]) - avg_over_time(temperature[1m])",
+                t,
+            )
+            .unwrap();
+        assert_vector(&[(a.clone(), 1.5)], over_time);
+
+        // 10 minutes back the counter of a was at 80
+        let offset = engine
+            .query(&querier, "requests_total{job=\"a\"} offset 10m", t)
+            .unwrap();
+        let name = labels(&[("__name__", "requests_total"), ("job", "a")]);
+        assert_vector(&[(name.clone(), 80.0)], offset);
+        let at = engine
+            .query(&querier, "requests_total{job=\"a\"} @ 600", t)
+            .unwrap();
+        assert_vector(&[(name, 40.0)], at);
+
+        let ratio = engine
+            .query(
+                &querier,
+                "requests_total / on (job) group_left requests_total{job=\"b\"} > bool 0",
+                t,
+            )
+            .unwrap();
+        assert_vector(&[(b.clone(), 1.0)], ratio);
+
+        let scalar = engine.query(&querier, "2 ^ 3 ^ 2 - time()", t).unwrap();
+        assert_eq!(Value::Scalar(t, 512.0 - 1800.0), scalar);
+        assert!(engine.query(&querier, "1 > 2", t).is_err());
+        assert!(engine
+            .query(&querier, "unknown(requests_total)", t)
+            .is_err());
+
+        let series = engine
+            .query_range(
+                &querier,
+                "sum(rate(requests_total[5m]))",
+                t,
+                t + 600000,
+                60000,
+            )
+            .unwrap();
+        assert_eq!(1, series.len());
+        assert_eq!(11, series[0].samples.len());
+        assert!(series[0]
+            .samples
+            .iter()
+            .all(|(_, v)| (v - 4.0 / 15.0).abs() < 1e-9));
+
+        // NaN is kept by clamp instead of being replaced by a bound
+        let clamped = engine
+            .query(
+                &querier,
+                "clamp(temperature / 0 - temperature / 0, 0, 1)",
+                t,
+            )
+            .unwrap();
+        match clamped {
+            Value::Vector(v) => assert!(v.len() == 1 && v[0].v.is_nan()),
+            v => panic!("expected vector, got {:?}", v),
+        }
+
+        // times at the limits neither overflow nor loop forever
+        assert!(engine.query(&querier, "requests_total", i64::MIN).is_err());
+        assert!(engine
+            .query(&querier, "requests_total @ 1e300 offset -1h", t)
+            .is_ok());
+        assert!(engine
+            .query(&querier, "rate(requests_total[5m] offset 1000y)", MIN_TIME)
+            .is_ok());
+        let last = engine
+            .query_range(&querier, "1", MAX_TIME - 1, MAX_TIME, i64::MAX)
+            .unwrap();
+        assert_eq!(1, last[0].samples.len());
+        assert!(engine.query_range(&querier, "1", 0, i64::MAX, 1).is_err());
+
+        // nothing is left once the series ended more than 5m ago
+        let absent = engine
+            .query(
+                &querier,
+                "absent(requests_total{job=\"a\"})",
+                3 * 3600 * 1000,
+            )
+            .unwrap();
+        assert_vector(&[(a, 1.0)], absent);
+    }
+}
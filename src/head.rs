@@ -0,0 +1,574 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+};
+
+use crate::block::{BlockWriter, RawChunk};
+use crate::chunkenc::{append_sample, XorChunk, XorIterator, DEFAULT_CHUNK_RANGE, ENCODING_XOR};
+use crate::common::*;
+use crate::datadir::{DataDir, WAL_DIR};
+use crate::labels::{matches, Matcher};
+use crate::meta::MetaData;
+use crate::querier::{ChunkSeries, Querier, Series};
+use crate::wal::{
+    checkpoint, encode_samples, encode_series, segments, RefSample, RefSeries, Wal, WalWriter,
+};
+
+// A series of the head with its samples encoded into XOR chunks as they are
+// appended.
+#[derive(Debug)]
+struct MemSeries {
+    labels: Labels,
+    // sorted by time, samples are appended to the last chunk
+    chunks: Vec<XorChunk>,
+    // predicted end time of the last chunk
+    next_at: i64,
+}
+
+impl MemSeries {
+    fn new(labels: Labels) -> Self {
+        Self {
+            labels,
+            chunks: Vec::new(),
+            next_at: 0,
+        }
+    }
+
+    fn last(&self) -> Option<(i64, f64)> {
+        self.chunks.last().and_then(|c| c.last())
+    }
+
+    fn append(&mut self, t: i64, v: f64) {
+        append_sample(
+            &mut self.chunks,
+            &mut self.next_at,
+            t,
+            v,
+            DEFAULT_CHUNK_RANGE,
+        );
+    }
+
+    // drop the samples before t, a chunk spanning t is encoded again
+    fn truncate(&mut self, t: i64) -> Result<()> {
+        let n = self.chunks.partition_point(|c| c.max_time() < t);
+        self.chunks.drain(..n);
+
+        if let Some(c) = self.chunks.first_mut() {
+            if c.min_time() < t {
+                let mut chunk = XorChunk::new();
+                for (st, sv) in XorIterator::new(c.bytes())?.filter(|(st, _)| *st >= t) {
+                    chunk.append(st, sv);
+                }
+                *c = chunk;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn overlapping(&self, mint: i64, maxt: i64) -> impl Iterator<Item = &XorChunk> {
+        self.chunks
+            .iter()
+            .filter(move |c| c.min_time() <= maxt && mint <= c.max_time())
+    }
+}
+
+fn raw_chunk(chunk: &XorChunk) -> RawChunk {
+    RawChunk {
+        mint: chunk.min_time(),
+        maxt: chunk.max_time(),
+        encoding: ENCODING_XOR,
+        data: chunk.bytes().to_vec(),
+    }
+}
+
+// The in-memory head of a data directory. Series are kept by reference with
+// postings by label name and value, so the head can be queried like a block.
+// Appended samples are written to the WAL on commit and cut into 2h blocks
+// once the head spans more than one and a half block ranges, like Prometheus
+// does it.
+#[derive(Debug)]
+pub struct Head {
+    dir: PathBuf,
+    wal: WalWriter,
+    series: HashMap<u64, MemSeries>,
+    refs: HashMap<Labels, u64>,
+    postings: BTreeMap<String, BTreeMap<String, BTreeSet<u64>>>,
+    next_ref: u64,
+    // samples before this time are persisted in blocks already
+    min_valid_time: i64,
+    // records of the current append batch
+    pending_series: Vec<RefSeries>,
+    pending_samples: Vec<RefSample>,
+}
+
+impl Head {
+    // open the head of a data directory and replay its WAL
+    pub fn open(dir: &Path) -> Result<Self> {
+        create_dir_all(dir)?;
+        let data_dir = DataDir::open(dir)?;
+        let min_valid_time = data_dir
+            .blocks
+            .iter()
+            .map(|b| b.meta.max_time)
+            .max()
+            .unwrap_or(i64::MIN);
+
+        // a record torn by a crash is cut off the WAL before writing to it
+        let wal_dir = dir.join(WAL_DIR);
+        create_dir_all(&wal_dir)?;
+        let replay = Wal::open(&wal_dir)?.replay_and_repair()?;
+        let wal = WalWriter::open(&wal_dir)?;
+
+        let mut head = Self {
+            dir: dir.to_path_buf(),
+            wal,
+            series: HashMap::new(),
+            refs: HashMap::new(),
+            postings: BTreeMap::new(),
+            next_ref: replay.max_series_ref() + 1,
+            min_valid_time,
+            pending_series: Vec::new(),
+            pending_samples: Vec::new(),
+        };
+
+        for (series_ref, s) in replay.series {
+            let mut series = MemSeries::new(s.labels);
+            for (t, v) in s.samples.into_iter().filter(|(t, _)| *t >= min_valid_time) {
+                series.append(t, v);
+            }
+            if !series.chunks.is_empty() {
+                head.add_series(series_ref, series);
+            }
+        }
+
+        Ok(head)
+    }
+
+    pub fn num_series(&self) -> usize {
+        self.series.len()
+    }
+
+    fn add_series(&mut self, series_ref: u64, series: MemSeries) {
+        for (name, value) in series.labels.iter() {
+            self.postings
+                .entry(name.clone())
+                .or_default()
+                .entry(value.clone())
+                .or_default()
+                .insert(series_ref);
+        }
+        self.refs.insert(series.labels.clone(), series_ref);
+        self.series.insert(series_ref, series);
+    }
+
+    fn remove_series(&mut self, series_ref: u64) {
+        let series = match self.series.remove(&series_ref) {
+            Some(s) => s,
+            None => return,
+        };
+        for (name, value) in series.labels.iter() {
+            if let Some(values) = self.postings.get_mut(name) {
+                if let Some(refs) = values.get_mut(value) {
+                    refs.remove(&series_ref);
+                    if refs.is_empty() {
+                        values.remove(value);
+                    }
+                }
+                if values.is_empty() {
+                    self.postings.remove(name);
+                }
+            }
+        }
+        self.refs.remove(&series.labels);
+    }
+
+    // min and max time of the samples in the head, both inclusive
+    fn bounds(&self) -> Option<(i64, i64)> {
+        let mint = self
+            .series
+            .values()
+            .filter_map(|s| s.chunks.first())
+            .map(|c| c.min_time())
+            .min()?;
+        let maxt = self
+            .series
+            .values()
+            .filter_map(|s| s.chunks.last())
+            .map(|c| c.max_time())
+            .max()?;
+        Some((mint, maxt))
+    }
+
+    // Append a sample to a series. Samples have to be appended in order per
+    // series and are durable once they are committed.
+    pub fn append(&mut self, mut labels: Labels, t: i64, v: f64) -> Result<()> {
+        if t < self.min_valid_time {
+            println!("Sample at {} is older than the head.", t);
+            return Err(TSDBError::OutOfBounds);
+        }
+        labels.sort();
+
+        let series_ref = match self.refs.get(&labels) {
+            Some(r) => *r,
+            None => {
+                let r = self.next_ref;
+                self.next_ref += 1;
+                self.pending_series.push(RefSeries {
+                    series_ref: r,
+                    labels: labels.clone(),
+                });
+                self.add_series(r, MemSeries::new(labels));
+                r
+            }
+        };
+
+        let series = self.series.get_mut(&series_ref).ok_or(TSDBError::Default)?;
+        if let Some((last_t, last_v)) = series.last() {
+            // resending the last sample is not an error
+            if t == last_t && v.to_bits() == last_v.to_bits() {
+                return Ok(());
+            }
+            if t <= last_t {
+                println!("Sample at {} is out of order.", t);
+                return Err(TSDBError::OutOfOrderSample);
+            }
+        }
+
+        series.append(t, v);
+        self.pending_samples.push(RefSample { series_ref, t, v });
+
+        Ok(())
+    }
+
+    // write the series and samples appended since the last commit to the WAL
+    pub fn commit(&mut self) -> Result<()> {
+        if !self.pending_series.is_empty() {
+            self.wal.log(&encode_series(&self.pending_series))?;
+            self.pending_series.clear();
+        }
+        if !self.pending_samples.is_empty() {
+            self.wal.log(&encode_samples(&self.pending_samples))?;
+            self.pending_samples.clear();
+        }
+        Ok(())
+    }
+
+    // Cut the oldest 2h of the head into a block as long as the head spans more
+    // than 1.5 times that range, so late samples can still be appended to the
+    // newest range. The chunks of the head are written as they are. Returns the
+    // written blocks.
+    pub fn compact(&mut self) -> Result<Vec<MetaData>> {
+        let mut blocks = Vec::<MetaData>::new();
+
+        while let Some((mint, maxt)) = self.bounds() {
+            if maxt - mint <= DEFAULT_CHUNK_RANGE / 2 * 3 {
+                break;
+            }
+            let start = mint - mint.rem_euclid(DEFAULT_CHUNK_RANGE);
+            let end = start + DEFAULT_CHUNK_RANGE;
+
+            let mut writer = BlockWriter::new(&self.dir);
+            writer.set_time_range(start, end);
+            for s in self.series.values() {
+                for c in s.chunks.iter().filter(|c| c.min_time() < end) {
+                    writer.add_chunk(s.labels.clone(), raw_chunk(c));
+                }
+            }
+            blocks.push(writer.write()?);
+
+            // series without samples get a new ref once they come back
+            let mut empty = Vec::<u64>::new();
+            for (r, s) in self.series.iter_mut() {
+                s.truncate(end)?;
+                if s.chunks.is_empty() {
+                    empty.push(*r);
+                }
+            }
+            for r in empty {
+                self.remove_series(r);
+            }
+            self.min_valid_time = end;
+        }
+
+        if !blocks.is_empty() {
+            self.truncate_wal()?;
+        }
+
+        Ok(blocks)
+    }
+
+    // Checkpoint the first two thirds of the segments before the one that is
+    // written to, like Prometheus does it after cutting a block. Series that
+    // are gone from the head and samples in blocks are dropped.
+    fn truncate_wal(&mut self) -> Result<()> {
+        let dir = self.wal.dir().to_path_buf();
+        let first = match segments(&dir)?.first() {
+            Some((i, _)) => *i,
+            None => return Ok(()),
+        };
+        let last = self.wal.segment().saturating_sub(1);
+        if last <= first {
+            return Ok(());
+        }
+
+        let to = first + (last - first) * 2 / 3;
+        checkpoint(
+            &dir,
+            to,
+            |r| self.series.contains_key(&r),
+            self.min_valid_time,
+        )?;
+        Ok(())
+    }
+
+    // Sorted series matching the matchers. Matchers that match the empty string
+    // also match series without the label, so they are left to the check of
+    // the labels.
+    fn matching_series(&self, matchers: &[Matcher]) -> Vec<&MemSeries> {
+        let mut result: Option<BTreeSet<u64>> = None;
+
+        for m in matchers.iter().filter(|m| !m.matches("")) {
+            let refs: BTreeSet<u64> = self
+                .postings
+                .get(&m.name)
+                .into_iter()
+                .flatten()
+                .filter(|(value, _)| m.matches(value))
+                .flat_map(|(_, refs)| refs.iter().copied())
+                .collect();
+
+            result = match result {
+                Some(r) => Some(r.intersection(&refs).copied().collect()),
+                None => Some(refs),
+            };
+        }
+
+        let mut all: Vec<&MemSeries> = match result {
+            Some(refs) => refs.iter().filter_map(|r| self.series.get(r)).collect(),
+            None => self.series.values().collect(),
+        };
+        all.retain(|s| matches(matchers, &s.labels));
+        all.sort_by(|a, b| a.labels.cmp(&b.labels));
+
+        all
+    }
+}
+
+impl Querier for Head {
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series<'_>>> {
+        let mut set = Vec::<Series>::new();
+
+        for s in self.matching_series(matchers) {
+            let mut chunks = Vec::<XorIterator>::new();
+            for c in s.overlapping(mint, maxt) {
+                chunks.push(XorIterator::new(c.bytes())?);
+            }
+            if chunks.is_empty() {
+                continue;
+            }
+
+            let samples = chunks
+                .into_iter()
+                .flatten()
+                .skip_while(move |(t, _)| *t < mint)
+                .take_while(move |(t, _)| *t <= maxt);
+
+            set.push(Series::new(s.labels.clone(), samples));
+        }
+
+        Ok(set)
+    }
+
+    fn select_chunks(
+        &self,
+        matchers: &[Matcher],
+        mint: i64,
+        maxt: i64,
+    ) -> Result<Vec<ChunkSeries>> {
+        let mut set = Vec::<ChunkSeries>::new();
+
+        for s in self.matching_series(matchers) {
+            let chunks: Vec<RawChunk> = s.overlapping(mint, maxt).map(raw_chunk).collect();
+            if !chunks.is_empty() {
+                set.push(ChunkSeries {
+                    labels: s.labels.clone(),
+                    chunks,
+                });
+            }
+        }
+
+        Ok(set)
+    }
+
+    fn label_names(&self) -> Result<Vec<String>> {
+        Ok(self.postings.keys().cloned().collect())
+    }
+
+    fn label_values(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .postings
+            .get(name)
+            .map(|values| values.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn time_range(&self) -> (i64, i64) {
+        self.bounds()
+            .map_or((0, 0), |(mint, maxt)| (mint, maxt + 1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::BlockReader;
+    use crate::chunkenc::cut_chunks;
+    use crate::labels::MatchType;
+    use std::fs::OpenOptions;
+
+    fn labels(job: &str) -> Labels {
+        vec![
+            (String::from("job"), String::from(job)),
+            (String::from("__name__"), String::from("up")),
+        ]
+    }
+
+    #[test]
+    fn append_and_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut head = Head::open(dir.path()).unwrap();
+
+        // 4h of samples
+        for i in 0..960 {
+            head.append(labels("a"), i * 15000, i as f64).unwrap();
+            if i < 100 {
+                head.append(labels("b"), i * 15000, 1.0).unwrap();
+            }
+        }
+        assert!(matches!(
+            head.append(labels("a"), 1000, 0.0),
+            Err(TSDBError::OutOfOrderSample)
+        ));
+        head.commit().unwrap();
+
+        // the WAL is replayed on open, each open starts a new segment
+        Head::open(dir.path()).unwrap();
+        Head::open(dir.path()).unwrap();
+        let mut head = Head::open(dir.path()).unwrap();
+        assert_eq!(2, head.num_series());
+        assert_eq!((0, 959 * 15000 + 1), head.time_range());
+
+        let blocks = head.compact().unwrap();
+        assert_eq!(1, blocks.len());
+        assert_eq!(
+            (0, DEFAULT_CHUNK_RANGE),
+            (blocks[0].min_time, blocks[0].max_time)
+        );
+        assert_eq!(1, head.num_series());
+        assert_eq!(vec![String::from("a")], head.label_values("job").unwrap());
+        assert!(matches!(
+            head.append(labels("b"), 1000, 0.0),
+            Err(TSDBError::OutOfBounds)
+        ));
+
+        let reader = BlockReader::open(&dir.path().join(blocks[0].ulid.to_string())).unwrap();
+        let series = reader.series().unwrap();
+        assert_eq!(2, series.len());
+        assert_eq!(4, series[0].chunks.len());
+
+        // the WAL is truncated and samples in the block are not replayed
+        // into the head again
+        let wal = Wal::open(&dir.path().join(WAL_DIR)).unwrap();
+        assert_eq!(1, wal.checkpoint.unwrap().0);
+        assert_eq!(2, wal.segments[0].0);
+        let head = Head::open(dir.path()).unwrap();
+        assert_eq!(1, head.num_series());
+        assert_eq!((DEFAULT_CHUNK_RANGE, 959 * 15000 + 1), head.time_range());
+    }
+
+    #[test]
+    fn repair_torn_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut head = Head::open(dir.path()).unwrap();
+        for i in 0..100 {
+            head.append(labels("a"), i * 15000, i as f64).unwrap();
+            head.commit().unwrap();
+        }
+        drop(head);
+        let wal_dir = dir.path().join(WAL_DIR);
+        // a later segment is removed along with the torn record
+        WalWriter::open(&wal_dir)
+            .unwrap()
+            .log(&encode_samples(&[RefSample {
+                series_ref: 1,
+                t: 100 * 15000,
+                v: 100.0,
+            }]))
+            .unwrap();
+
+        // cut off the end of the last sample record as a crash would do it
+        let (_, path) = segments(&wal_dir).unwrap().remove(0);
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+        assert!(Wal::open(&wal_dir).unwrap().replay().is_err());
+
+        let head = Head::open(dir.path()).unwrap();
+        assert_eq!((0, 98 * 15000 + 1), head.time_range());
+        assert_eq!(2, segments(&wal_dir).unwrap().len());
+        drop(head);
+        assert!(Wal::open(&wal_dir).unwrap().replay().is_ok());
+        let head = Head::open(dir.path()).unwrap();
+        assert_eq!((0, 98 * 15000 + 1), head.time_range());
+    }
+
+    #[test]
+    fn query_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut head = Head::open(dir.path()).unwrap();
+
+        for i in 0..1000 {
+            head.append(labels("b"), i * 15000, i as f64).unwrap();
+            head.append(labels("a"), i * 15000, 1.0).unwrap();
+        }
+        let down = vec![(String::from("__name__"), String::from("down"))];
+        head.append(down, 0, 0.0).unwrap();
+
+        let up = Matcher::new(MatchType::Equal, "__name__", "up").unwrap();
+        let set = head
+            .select(std::slice::from_ref(&up), 15000, 30000)
+            .unwrap();
+        assert_eq!(2, set.len());
+        assert_eq!("a", set[0].labels[1].1);
+        assert_eq!(
+            vec![(15000, 1.0), (30000, 2.0)],
+            set.into_iter().nth(1).unwrap().collect::<Vec<(i64, f64)>>()
+        );
+
+        // chunks are cut like the ones of prometheus
+        let not_a = Matcher::new(MatchType::NotEqual, "job", "a").unwrap();
+        let set = head.select_chunks(&[up, not_a], 0, 1000 * 15000).unwrap();
+        assert_eq!(1, set.len());
+        assert_eq!("b", set[0].labels[1].1);
+        let samples: Vec<(i64, f64)> = (0..1000).map(|i| (i * 15000, i as f64)).collect();
+        let expected: Vec<Vec<u8>> = cut_chunks(&samples, DEFAULT_CHUNK_RANGE)
+            .iter()
+            .map(|c| c.bytes().to_vec())
+            .collect();
+        let chunks: Vec<Vec<u8>> = set[0].chunks.iter().map(|c| c.data.clone()).collect();
+        assert_eq!(expected, chunks);
+
+        let down = Matcher::new(MatchType::Equal, "__name__", "down").unwrap();
+        assert_eq!(1, head.select(&[down], 0, 0).unwrap().len());
+        assert_eq!(
+            vec![String::from("__name__"), String::from("job")],
+            head.label_names().unwrap()
+        );
+    }
+}
@@ -1,6 +1,12 @@
 use crc::{Crc, CRC_32_ISCSI};
 use memmap::Mmap;
-use std::{collections::HashMap, fs::File, mem::size_of, path::Path, str};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{write, File},
+    mem::size_of,
+    path::Path,
+    str,
+};
 
 use crate::common::*;
 
@@ -12,6 +18,10 @@ const VERSION_SIZE: usize = 1;
 const NUM_SYMBOLS_SIZE: usize = 4;
 const SYMBOLS_LEN_SIZE: usize = 4;
 const TOC_SIZE: usize = size_of::<TOC>();
+const MAGIC_INDEX: u32 = 0xBAAAD700;
+const INDEX_FORMAT_V2: u8 = 2;
+const SERIES_ALIGNMENT: usize = 16;
+const POSTINGS_ALIGNMENT: usize = 4;
 
 // NOTE: Format of an index file:
 // https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/index.md
@@ -40,6 +50,24 @@ impl Index {
         }
     }
 
+    // like new but failing on missing files and unsupported index versions
+    pub fn open(path: &Path) -> Result<Self> {
+        let f = File::open(path)?;
+        let buf = unsafe { Mmap::map(&f)? };
+
+        if buf.len() < MAGIC_SIZE + VERSION_SIZE + TOC_SIZE + CHECKSUM_SIZE
+            || read_u32(&buf, 0)? != MAGIC_INDEX
+            || buf[MAGIC_SIZE] != INDEX_FORMAT_V2
+        {
+            println!("Unsupported index {}.", path.display());
+            return Err(TSDBError::Default);
+        }
+
+        let toc = Index::toc(&buf)?;
+
+        Ok(Self { toc, buf })
+    }
+
     fn toc(buf: &[u8]) -> Result<TOC> {
         // get table of content
         let pos = buf.len() - TOC_SIZE - CHECKSUM_SIZE;
@@ -76,7 +104,7 @@ impl Index {
     }
 }
 
-pub fn symbol_table(i: &Index) -> Result<SymbolTable> {
+pub fn symbol_table(i: &Index) -> Result<SymbolTable<'_>> {
     let mut curr = i.toc.symbols as usize;
     let len = read_u32(&i.buf, curr)?;
     curr += SYMBOLS_LEN_SIZE;
@@ -105,7 +133,7 @@ pub fn symbol_table(i: &Index) -> Result<SymbolTable> {
     })
 }
 
-pub fn series(i: &Index) -> Result<Series> {
+pub fn series(i: &Index) -> Result<Series<'_>> {
     let start = i.toc.series as usize;
     let end = i.toc.label_index_start as usize;
 
@@ -115,6 +143,7 @@ pub fn series(i: &Index) -> Result<Series> {
 
     Ok(Series {
         buf: data,
+        offset: start,
         current_pos: 0,
     })
 }
@@ -183,9 +212,12 @@ impl SymbolTable<'_> {
                 return Err(TSDBError::SymbolTableLookup);
             }
         }
-        // read n-1th position. n is the number of the symbol starting at index
-        // 1.
-        self.read_symbol(self.positions[n - 1] as usize)
+        // symbols are referenced by their index starting at 0, positions hold
+        // the end of each symbol which is the start of the next one.
+        if n == 0 {
+            return self.read_symbol(0);
+        }
+        self.read_symbol(self.positions[n - 1])
     }
 
     pub fn read_symbol(&self, pos: usize) -> Result<String> {
@@ -209,6 +241,203 @@ impl SymbolTable<'_> {
     }
 }
 
+// read a table of <len> <#entries> <entries> <CRC32> and return the number of
+// entries and the entries
+fn read_table(buf: &[u8], pos: usize) -> Result<(u32, &[u8])> {
+    let len = read_u32(buf, pos)? as usize;
+    if buf.len() < pos + SYMBOLS_LEN_SIZE + len + CHECKSUM_SIZE || len < size_of::<u32>() {
+        return Err(TSDBError::Default);
+    }
+
+    let data = slice_bytes(buf, len, pos + SYMBOLS_LEN_SIZE);
+    let cs = get_checksum(buf, pos + SYMBOLS_LEN_SIZE + len)?;
+    if cs != CASTAGNIOLI.checksum(data) {
+        println!("Checksum mismatch. Corrupted table.");
+        return Err(TSDBError::Checksum);
+    }
+
+    Ok((read_u32(data, 0)?, &data[size_of::<u32>()..]))
+}
+
+// ┌─────────────────────┬──────────────────────┐
+// │ len <4b>            │ #entries <4b>        │
+// ├─────────────────────┴──────────────────────┤
+// │ ┌────────────────────────────────────────┐ │
+// │ │  n = 1 <1b>                            │ │
+// │ ├──────────────────────┬─────────────────┤ │
+// │ │ len(name) <uvarint>  │ name <bytes>    │ │
+// │ ├──────────────────────┴─────────────────┤ │
+// │ │  offset <uvarint64>                    │ │
+// │ └────────────────────────────────────────┘ │
+// │                    . . .                   │
+// ├────────────────────────────────────────────┤
+// │  CRC32 <4b>                                │
+// └────────────────────────────────────────────┘
+//
+// label names with the offset of their label index
+pub fn label_offsets(i: &Index) -> Result<Vec<(String, u64)>> {
+    let (entries, data) = read_table(&i.buf, i.toc.label_offset_table as usize)?;
+
+    let mut pos = 0;
+    let mut offsets = Vec::<(String, u64)>::with_capacity(entries as usize);
+    for _ in 0..entries {
+        let (n, size) = read_varint_u32(data, pos)?;
+        pos += size;
+        if n != 1 {
+            return Err(TSDBError::Default);
+        }
+
+        let (name, size) = read_str(data, pos)?;
+        pos += size;
+
+        let (offset, size) = read_varint_u64(data, pos)?;
+        pos += size;
+
+        offsets.push((name, offset));
+    }
+
+    Ok(offsets)
+}
+
+// read a string prefixed with its length and return it with the bytes read
+fn read_str(buf: &[u8], pos: usize) -> Result<(String, usize)> {
+    let (len, size) = read_varint_u32(buf, pos)?;
+    if size == 0 || buf.len() < pos + size + len as usize {
+        return Err(TSDBError::Default);
+    }
+
+    match str::from_utf8(slice_bytes(buf, len as usize, pos + size)) {
+        Ok(s) => Ok((s.to_string(), size + len as usize)),
+        Err(_) => Err(TSDBError::Default),
+    }
+}
+
+pub fn label_names(i: &Index) -> Result<Vec<String>> {
+    Ok(label_offsets(i)?.into_iter().map(|(n, _)| n).collect())
+}
+
+// sorted values of a label name read from its label index
+pub fn label_values(i: &Index, name: &str) -> Result<Vec<String>> {
+    let offset = match label_offsets(i)?.into_iter().find(|(n, _)| n == name) {
+        Some((_, offset)) => offset,
+        None => return Ok(Vec::new()),
+    };
+
+    let (names, data) = read_table(&i.buf, offset as usize)?;
+    if names != 1 {
+        return Err(TSDBError::Default);
+    }
+    let entries = read_u32(data, 0)?;
+    if data.len() < (entries as usize + 1) * size_of::<u32>() {
+        return Err(TSDBError::Default);
+    }
+
+    let mut sym = symbol_table(i)?;
+    let mut values = Vec::<String>::with_capacity(entries as usize);
+    for n in 0..entries as usize {
+        let r = read_u32(data, (n + 1) * size_of::<u32>())?;
+        values.push(sym.lookup(r as usize)?);
+    }
+
+    Ok(values)
+}
+
+// ┌─────────────────────┬──────────────────────┐
+// │ len <4b>            │ #entries <4b>        │
+// ├─────────────────────┴──────────────────────┤
+// │ ┌────────────────────────────────────────┐ │
+// │ │  n = 2 <1b>                            │ │
+// │ ├──────────────────────┬─────────────────┤ │
+// │ │ len(name) <uvarint>  │ name <bytes>    │ │
+// │ ├──────────────────────┼─────────────────┤ │
+// │ │ len(value) <uvarint> │ value <bytes>   │ │
+// │ ├──────────────────────┴─────────────────┤ │
+// │ │  offset <uvarint64>                    │ │
+// │ └────────────────────────────────────────┘ │
+// │                    . . .                   │
+// ├────────────────────────────────────────────┤
+// │  CRC32 <4b>                                │
+// └────────────────────────────────────────────┘
+//
+// sorted label pairs with the offset of their postings list, the empty pair
+// holds the postings of all series
+pub fn postings_offsets(i: &Index) -> Result<Vec<(String, String, u64)>> {
+    let (entries, data) = read_table(&i.buf, i.toc.postings_offset_table as usize)?;
+
+    let mut pos = 0;
+    let mut offsets = Vec::<(String, String, u64)>::with_capacity(entries as usize);
+    for _ in 0..entries {
+        let (n, size) = read_varint_u32(data, pos)?;
+        pos += size;
+        if n != 2 {
+            return Err(TSDBError::Default);
+        }
+
+        let (name, size) = read_str(data, pos)?;
+        pos += size;
+        let (value, size) = read_str(data, pos)?;
+        pos += size;
+
+        let (offset, size) = read_varint_u64(data, pos)?;
+        pos += size;
+
+        offsets.push((name, value, offset));
+    }
+
+    Ok(offsets)
+}
+
+// ┌────────────────────┬────────────────────┐
+// │ len <4b>           │ #entries <4b>      │
+// ├────────────────────┴────────────────────┤
+// │ ┌─────────────────────────────────────┐ │
+// │ │ ref(series_1) <4b>                  │ │
+// │ ├─────────────────────────────────────┤ │
+// │ │ ...                                 │ │
+// │ ├─────────────────────────────────────┤ │
+// │ │ ref(series_n) <4b>                  │ │
+// │ └─────────────────────────────────────┘ │
+// ├─────────────────────────────────────────┤
+// │ CRC32 <4b>                              │
+// └─────────────────────────────────────────┘
+//
+// sorted references of the series of a postings list
+pub fn postings(i: &Index, offset: u64) -> Result<Vec<u64>> {
+    let (entries, data) = read_table(&i.buf, offset as usize)?;
+    if data.len() < entries as usize * size_of::<u32>() {
+        return Err(TSDBError::Default);
+    }
+
+    let mut refs = Vec::<u64>::with_capacity(entries as usize);
+    for n in 0..entries as usize {
+        refs.push(read_u32(data, n * size_of::<u32>())? as u64);
+    }
+
+    Ok(refs)
+}
+
+// the series at a reference taken from a postings list
+pub fn series_at(i: &Index, series_ref: u64) -> Result<SeriesItem> {
+    let pos = series_ref as usize * SERIES_ALIGNMENT;
+    let (len, size) = read_varint_u32(&i.buf, pos)?;
+    let start = pos + size;
+    let end = start + len as usize;
+    if size == 0 || len == 0 || i.buf.len() < end + CHECKSUM_SIZE {
+        return Err(TSDBError::Default);
+    }
+
+    let data = slice_bytes(&i.buf, len as usize, start);
+    if get_checksum(&i.buf, end)? != CASTAGNIOLI.checksum(data) {
+        println!("Checksum mismatch. Corrupted series.");
+        return Err(TSDBError::Checksum);
+    }
+
+    let mut series_item = SeriesItem::try_from(data)?;
+    series_item.series_ref = series_ref;
+
+    Ok(series_item)
+}
+
 // ┌──────────────────────────────────────────────────────────────────────────┐
 // │ len <uvarint>                                                            │
 // ├──────────────────────────────────────────────────────────────────────────┤
@@ -246,6 +475,8 @@ impl SymbolTable<'_> {
 #[derive(Debug)]
 pub struct Series<'a> {
     buf: &'a [u8],
+    // offset of the series section in the index
+    offset: usize,
     current_pos: usize,
 }
 
@@ -257,10 +488,60 @@ pub enum IntType {
 
 #[derive(Debug)]
 pub struct SeriesItem {
+    // offset of the series in the index divided by 16
+    pub series_ref: u64,
     pub labels: HashMap<usize, usize>,
     pub chunks: Vec<(IntType, u64, u64)>,
 }
 
+impl SeriesItem {
+    // resolve the label symbols, sorted by name
+    pub fn labels(&self, sym: &mut SymbolTable) -> Result<Labels> {
+        let mut labels = Labels::with_capacity(self.labels.len());
+        for (k, v) in self.labels.iter() {
+            labels.push((sym.lookup(*k)?, sym.lookup(*v)?));
+        }
+        labels.sort();
+
+        Ok(labels)
+    }
+
+    // resolve the chunk metas stored as deltas to the previous chunk
+    pub fn chunk_metas(&self) -> Vec<ChunkMeta> {
+        let mut metas = Vec::<ChunkMeta>::with_capacity(self.chunks.len());
+        for (mint, maxt, data) in self.chunks.iter() {
+            let meta = match metas.last() {
+                // the reference is stored as signed delta
+                Some(p) => {
+                    let mint = match mint {
+                        IntType::U64(d) => p.maxt + *d as i64,
+                        IntType::I64(m) => *m,
+                    };
+                    ChunkMeta {
+                        mint,
+                        maxt: mint + *maxt as i64,
+                        chunk_ref: (p.chunk_ref as i64 + zigzag_dec(*data)) as u64,
+                    }
+                }
+                None => {
+                    let mint = match mint {
+                        IntType::U64(m) => *m as i64,
+                        IntType::I64(m) => *m,
+                    };
+                    ChunkMeta {
+                        mint,
+                        maxt: mint + *maxt as i64,
+                        chunk_ref: *data,
+                    }
+                }
+            };
+            metas.push(meta);
+        }
+
+        metas
+    }
+}
+
 impl TryFrom<&[u8]> for SeriesItem {
     type Error = TSDBError;
 
@@ -302,7 +583,11 @@ impl TryFrom<&[u8]> for SeriesItem {
             chunks.push((mint, maxt, data));
         }
 
-        Ok(SeriesItem { labels, chunks })
+        Ok(SeriesItem {
+            series_ref: 0,
+            labels,
+            chunks,
+        })
     }
 }
 
@@ -314,6 +599,7 @@ impl Iterator for Series<'_> {
         if self.current_pos >= self.buf.len() {
             return None;
         }
+        let start = self.offset + self.current_pos;
         match read_varint_u32(self.buf, self.current_pos) {
             Ok((len, size)) => {
                 if size == 0 {
@@ -336,7 +622,8 @@ impl Iterator for Series<'_> {
                         }
 
                         // TODO: don't unwrap
-                        let series_item = data.try_into().unwrap();
+                        let mut series_item: SeriesItem = data.try_into().unwrap();
+                        series_item.series_ref = (start / SERIES_ALIGNMENT) as u64;
                         self.current_pos += CHECKSUM_SIZE;
 
                         Some(series_item)
@@ -374,6 +661,289 @@ pub struct TOC {
     postings_offset_table: u64,
 }
 
+// write a table of <len> <#entries> <entries> <CRC32>
+fn write_table(buf: &mut Vec<u8>, entries: u32, data: &[u8]) {
+    let start = buf.len();
+    write_u32(buf, (data.len() + size_of::<u32>()) as u32);
+    write_u32(buf, entries);
+    buf.extend_from_slice(data);
+    let crc = CASTAGNIOLI.checksum(&buf[start + size_of::<u32>()..]);
+    write_u32(buf, crc);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkMeta {
+    pub mint: i64,
+    pub maxt: i64,
+    pub chunk_ref: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Stage {
+    Symbols,
+    Series,
+}
+
+// Writes an index in format v2. Symbols have to be added in ascending order
+// before the series, which have to be added sorted by their labels.
+#[derive(Debug)]
+pub struct IndexWriter {
+    buf: Vec<u8>,
+    toc: TOC,
+    stage: Stage,
+    symbols: HashMap<String, u32>,
+    last_symbol: Option<String>,
+    last_series: Option<Labels>,
+    // series refs for every label pair, the empty pair holds all series
+    postings: BTreeMap<(String, String), Vec<u32>>,
+}
+
+impl Default for IndexWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IndexWriter {
+    pub fn new() -> Self {
+        let mut buf = Vec::<u8>::new();
+        write_u32(&mut buf, MAGIC_INDEX);
+        buf.push(INDEX_FORMAT_V2);
+
+        let toc = TOC {
+            symbols: buf.len() as u64,
+            series: 0,
+            label_index_start: 0,
+            postings_start: 0,
+            label_offset_table: 0,
+            postings_offset_table: 0,
+        };
+
+        // placeholders for the length and number of symbols
+        write_u32(&mut buf, 0);
+        write_u32(&mut buf, 0);
+
+        Self {
+            buf,
+            toc,
+            stage: Stage::Symbols,
+            symbols: HashMap::new(),
+            last_symbol: None,
+            last_series: None,
+            postings: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_symbol(&mut self, symbol: &str) -> Result<()> {
+        if self.stage != Stage::Symbols {
+            return Err(TSDBError::Default);
+        }
+        if self.last_symbol.as_deref().is_some_and(|l| l >= symbol) {
+            println!("Symbol {:?} out of order.", symbol);
+            return Err(TSDBError::Default);
+        }
+
+        self.symbols
+            .insert(symbol.to_string(), self.symbols.len() as u32);
+        write_str(&mut self.buf, symbol);
+        self.last_symbol = Some(symbol.to_string());
+
+        Ok(())
+    }
+
+    fn finish_symbols(&mut self) {
+        let start = self.toc.symbols as usize;
+        let len = self.buf.len() - start - SYMBOLS_LEN_SIZE;
+
+        self.buf[start..start + SYMBOLS_LEN_SIZE].copy_from_slice(&(len as u32).to_be_bytes());
+        self.buf[start + SYMBOLS_LEN_SIZE..start + SYMBOLS_LEN_SIZE + NUM_SYMBOLS_SIZE]
+            .copy_from_slice(&(self.symbols.len() as u32).to_be_bytes());
+
+        let crc = CASTAGNIOLI.checksum(&self.buf[start + SYMBOLS_LEN_SIZE..]);
+        write_u32(&mut self.buf, crc);
+
+        self.toc.series = self.buf.len() as u64;
+        self.stage = Stage::Series;
+    }
+
+    fn pad(&mut self, alignment: usize) {
+        let len = self.buf.len().div_ceil(alignment) * alignment;
+        self.buf.resize(len, 0);
+    }
+
+    fn symbol(&self, s: &str) -> Result<u32> {
+        match self.symbols.get(s) {
+            Some(r) => Ok(*r),
+            None => {
+                println!("Symbol {:?} not found.", s);
+                Err(TSDBError::SymbolTableLookup)
+            }
+        }
+    }
+
+    // returns the reference of the series, its offset divided by 16
+    pub fn add_series(&mut self, labels: &Labels, chunks: &[ChunkMeta]) -> Result<u32> {
+        if self.stage == Stage::Symbols {
+            self.finish_symbols();
+        }
+        if self.last_series.as_ref().is_some_and(|l| l >= labels) {
+            println!("Series {:?} out of order.", labels);
+            return Err(TSDBError::Default);
+        }
+
+        self.pad(SERIES_ALIGNMENT);
+        let series_ref = match u32::try_from(self.buf.len() / SERIES_ALIGNMENT) {
+            Ok(r) => r,
+            Err(_) => return Err(TSDBError::Default),
+        };
+
+        let mut data = Vec::<u8>::new();
+        write_varint_u64(&mut data, labels.len() as u64);
+        for (name, value) in labels {
+            write_varint_u32(&mut data, self.symbol(name)?);
+            write_varint_u32(&mut data, self.symbol(value)?);
+
+            self.postings
+                .entry((name.clone(), value.clone()))
+                .or_default()
+                .push(series_ref);
+        }
+        self.postings
+            .entry((String::new(), String::new()))
+            .or_default()
+            .push(series_ref);
+
+        // all but the first chunk are stored as deltas to the previous one
+        write_varint_u64(&mut data, chunks.len() as u64);
+        let mut prev: Option<&ChunkMeta> = None;
+        for c in chunks {
+            match prev {
+                None => {
+                    write_varint_i64(&mut data, c.mint);
+                    write_varint_u64(&mut data, (c.maxt - c.mint) as u64);
+                    write_varint_u64(&mut data, c.chunk_ref);
+                }
+                Some(p) => {
+                    write_varint_u64(&mut data, (c.mint - p.maxt) as u64);
+                    write_varint_u64(&mut data, (c.maxt - c.mint) as u64);
+                    write_varint_i64(&mut data, c.chunk_ref as i64 - p.chunk_ref as i64);
+                }
+            }
+            prev = Some(c);
+        }
+
+        write_varint_u64(&mut self.buf, data.len() as u64);
+        self.buf.extend_from_slice(&data);
+        write_u32(&mut self.buf, CASTAGNIOLI.checksum(&data));
+        self.last_series = Some(labels.clone());
+
+        Ok(series_ref)
+    }
+
+    // ┌───────────────┬────────────────┬────────────────┐
+    // │ len <4b>      │ #names <4b>    │ #entries <4b>  │
+    // ├───────────────┴────────────────┴────────────────┤
+    // │ ┌─────────────────────────────────────────────┐ │
+    // │ │ ref(value_0) <4b>                           │ │
+    // │ ├─────────────────────────────────────────────┤ │
+    // │ │ ...                                         │ │
+    // │ ├─────────────────────────────────────────────┤ │
+    // │ │ ref(value_n) <4b>                           │ │
+    // │ └─────────────────────────────────────────────┘ │
+    // ├─────────────────────────────────────────────────┤
+    // │ CRC32 <4b>                                      │
+    // └─────────────────────────────────────────────────┘
+    //
+    // One label index with the sorted values is written per label name,
+    // followed by the label offset table pointing to them.
+    fn write_label_indices(&mut self) -> Result<()> {
+        self.toc.label_index_start = self.buf.len() as u64;
+
+        let mut values = BTreeMap::<&str, Vec<u32>>::new();
+        for (name, value) in self.postings.keys() {
+            // skip the key of all postings
+            if name.is_empty() {
+                continue;
+            }
+            values.entry(name).or_default().push(self.symbol(value)?);
+        }
+
+        let mut buf = std::mem::take(&mut self.buf);
+        let mut offsets = Vec::<u8>::new();
+        for (name, refs) in values.iter() {
+            let len = buf.len().div_ceil(POSTINGS_ALIGNMENT) * POSTINGS_ALIGNMENT;
+            buf.resize(len, 0);
+
+            write_varint_u32(&mut offsets, 1);
+            write_str(&mut offsets, name);
+            write_varint_u64(&mut offsets, buf.len() as u64);
+
+            let mut data = Vec::<u8>::with_capacity((refs.len() + 1) * size_of::<u32>());
+            write_u32(&mut data, refs.len() as u32);
+            for r in refs {
+                write_u32(&mut data, *r);
+            }
+            write_table(&mut buf, 1, &data);
+        }
+
+        self.toc.label_offset_table = buf.len() as u64;
+        write_table(&mut buf, values.len() as u32, &offsets);
+        self.buf = buf;
+
+        Ok(())
+    }
+
+    fn write_postings(&mut self) {
+        self.toc.postings_start = self.buf.len() as u64;
+
+        let postings = std::mem::take(&mut self.postings);
+        let mut offsets = Vec::<u8>::new();
+        for ((name, value), refs) in postings.iter() {
+            self.pad(POSTINGS_ALIGNMENT);
+
+            write_varint_u32(&mut offsets, 2);
+            write_str(&mut offsets, name);
+            write_str(&mut offsets, value);
+            write_varint_u64(&mut offsets, self.buf.len() as u64);
+
+            let mut data = Vec::<u8>::with_capacity(refs.len() * size_of::<u32>());
+            for r in refs {
+                write_u32(&mut data, *r);
+            }
+            write_table(&mut self.buf, refs.len() as u32, &data);
+        }
+
+        self.toc.postings_offset_table = self.buf.len() as u64;
+        write_table(&mut self.buf, postings.len() as u32, &offsets);
+    }
+
+    fn write_toc(&mut self) {
+        let start = self.buf.len();
+        write_u64(&mut self.buf, self.toc.symbols);
+        write_u64(&mut self.buf, self.toc.series);
+        write_u64(&mut self.buf, self.toc.label_index_start);
+        write_u64(&mut self.buf, self.toc.label_offset_table);
+        write_u64(&mut self.buf, self.toc.postings_start);
+        write_u64(&mut self.buf, self.toc.postings_offset_table);
+        let crc = CASTAGNIOLI.checksum(&self.buf[start..]);
+        write_u32(&mut self.buf, crc);
+    }
+
+    pub fn write(mut self, path: &Path) -> Result<()> {
+        if self.stage == Stage::Symbols {
+            self.finish_symbols();
+        }
+
+        self.write_label_indices()?;
+        self.write_postings();
+        self.write_toc();
+
+        write(path, &self.buf)?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -409,4 +979,117 @@ mod test {
         let count = series.count();
         assert_eq!(expected_count, count);
     }
+
+    #[test]
+    fn write_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+
+        let mut writer = IndexWriter::new();
+        for s in ["__name__", "a", "b", "job", "up"] {
+            writer.add_symbol(s).unwrap();
+        }
+        assert!(writer.add_symbol("a").is_err());
+
+        let labels = |job: &str| {
+            vec![
+                (String::from("__name__"), String::from("up")),
+                (String::from("job"), String::from(job)),
+            ]
+        };
+        let chunk = ChunkMeta {
+            mint: 0,
+            maxt: 1000,
+            chunk_ref: 8,
+        };
+        assert_eq!(3, writer.add_series(&labels("a"), &[chunk]).unwrap());
+        assert_eq!(4, writer.add_series(&labels("b"), &[chunk]).unwrap());
+        assert!(writer.add_series(&labels("a"), &[chunk]).is_err());
+        writer.write(&path).unwrap();
+
+        let index = Index::new(&path);
+        assert_eq!(2, series(&index).unwrap().count());
+        assert_eq!(5, symbol_table(&index).unwrap().count());
+    }
+
+    #[test]
+    fn round_trip_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+
+        let label = |n: &str, v: &str| (String::from(n), String::from(v));
+        let input = vec![
+            (
+                vec![label("__name__", "up"), label("job", "a")],
+                vec![
+                    ChunkMeta {
+                        mint: -1000,
+                        maxt: 500,
+                        chunk_ref: 1 << 32 | 8,
+                    },
+                    ChunkMeta {
+                        mint: 600,
+                        maxt: 7000,
+                        chunk_ref: 26,
+                    },
+                ],
+            ),
+            (
+                vec![label("__name__", "up"), label("job", "b")],
+                vec![ChunkMeta {
+                    mint: 0,
+                    maxt: 0,
+                    chunk_ref: 44,
+                }],
+            ),
+            (vec![label("a", "1")], vec![]),
+        ];
+
+        let mut writer = IndexWriter::new();
+        for s in ["1", "__name__", "a", "b", "job", "up"] {
+            writer.add_symbol(s).unwrap();
+        }
+        for (labels, chunks) in input.iter() {
+            writer.add_series(labels, chunks).unwrap();
+        }
+        writer.write(&path).unwrap();
+
+        let index = Index::new(&path);
+        let mut sym = symbol_table(&index).unwrap();
+        let output: Vec<(Labels, Vec<ChunkMeta>)> = series(&index)
+            .unwrap()
+            .map(|s| (s.labels(&mut sym).unwrap(), s.chunk_metas()))
+            .collect();
+        let refs: Vec<u64> = series(&index).unwrap().map(|s| s.series_ref).collect();
+
+        assert_eq!(input, output);
+        assert_eq!(vec![3, 5, 6], refs);
+        assert_eq!(vec!["__name__", "a", "job"], label_names(&index).unwrap());
+        assert_eq!(vec!["a", "b"], label_values(&index, "job").unwrap());
+        assert_eq!(vec!["1"], label_values(&index, "a").unwrap());
+        assert!(label_values(&index, "missing").unwrap().is_empty());
+
+        let offsets = postings_offsets(&index).unwrap();
+        let pairs: Vec<(&str, &str)> = offsets
+            .iter()
+            .map(|(n, v, _)| (n.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("", ""),
+                ("__name__", "up"),
+                ("a", "1"),
+                ("job", "a"),
+                ("job", "b")
+            ],
+            pairs
+        );
+        assert_eq!(vec![3, 5, 6], postings(&index, offsets[0].2).unwrap());
+        assert_eq!(vec![3, 5], postings(&index, offsets[1].2).unwrap());
+
+        let s = series_at(&index, 5).unwrap();
+        assert_eq!(input[1].0, s.labels(&mut sym).unwrap());
+        assert_eq!(input[1].1, s.chunk_metas());
+        assert!(series_at(&index, 4).is_err());
+    }
 }
@@ -0,0 +1,105 @@
+use regex::Regex;
+
+use crate::common::*;
+
+pub const METRIC_NAME: &str = "__name__";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchType {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+// A label matcher as used in PromQL selectors. A label that is not set matches
+// as the empty string.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    pub match_type: MatchType,
+    pub name: String,
+    pub value: String,
+    re: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(match_type: MatchType, name: &str, value: &str) -> Result<Self> {
+        let re = match match_type {
+            // regular expressions are fully anchored
+            MatchType::Regex | MatchType::NotRegex => match Regex::new(&format!("^(?:{})$", value))
+            {
+                Ok(re) => Some(re),
+                Err(_) => {
+                    println!("Invalid regular expression {:?}.", value);
+                    return Err(TSDBError::InvalidMatcher);
+                }
+            },
+            _ => None,
+        };
+
+        Ok(Self {
+            match_type,
+            name: name.to_string(),
+            value: value.to_string(),
+            re,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match (self.match_type, &self.re) {
+            (MatchType::Equal, _) => self.value == value,
+            (MatchType::NotEqual, _) => self.value != value,
+            (MatchType::Regex, Some(re)) => re.is_match(value),
+            (MatchType::NotRegex, Some(re)) => !re.is_match(value),
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.match_type == other.match_type && self.name == other.name && self.value == other.value
+    }
+}
+
+// value of a label or the empty string if it is not set
+pub fn get<'a>(labels: &'a Labels, name: &str) -> &'a str {
+    labels
+        .iter()
+        .find(|(n, _)| n == name)
+        .map_or("", |(_, v)| v.as_str())
+}
+
+pub fn matches(matchers: &[Matcher], labels: &Labels) -> bool {
+    matchers.iter().all(|m| m.matches(get(labels, &m.name)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn match_labels() {
+        let labels = vec![
+            (String::from(METRIC_NAME), String::from("up")),
+            (String::from("job"), String::from("node")),
+        ];
+
+        let m = |t, n, v| Matcher::new(t, n, v).unwrap();
+        assert!(matches(&[m(MatchType::Equal, METRIC_NAME, "up")], &labels));
+        assert!(matches(&[m(MatchType::Regex, "job", "no.*")], &labels));
+        assert!(!matches(&[m(MatchType::Regex, "job", "no")], &labels));
+        assert!(matches(&[m(MatchType::NotRegex, "job", "no")], &labels));
+        assert!(matches(&[m(MatchType::NotEqual, "job", "api")], &labels));
+        // unset labels match the empty string
+        assert!(matches(&[m(MatchType::Equal, "instance", "")], &labels));
+        assert!(!matches(
+            &[
+                m(MatchType::Equal, METRIC_NAME, "up"),
+                m(MatchType::Regex, "instance", ".+")
+            ],
+            &labels
+        ));
+        assert!(Matcher::new(MatchType::Regex, "job", "(").is_err());
+    }
+}
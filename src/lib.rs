@@ -1,4 +1,23 @@
+pub mod api;
+pub mod backfill;
+pub mod block;
+pub mod chunkenc;
 pub mod chunks;
 pub mod common;
+pub mod compact;
+pub mod datadir;
+pub mod dedup;
+pub mod downsample;
+pub mod engine;
+pub mod head;
 pub mod index;
+pub mod labels;
 pub mod meta;
+pub mod promql;
+pub mod querier;
+pub mod relabel;
+pub mod remote;
+pub mod retention;
+pub mod tombstones;
+pub mod ulid;
+pub mod wal;
@@ -1,33 +1,166 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::cmp::{Eq, PartialEq};
-use std::{fs::read_to_string, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs::{read_to_string, rename, write},
+    path::Path,
+};
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+use crate::common::*;
+use crate::ulid::Ulid;
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn is_zero_i64(n: &i64) -> bool {
+    *n == 0
+}
+
+// NOTE: Format of meta.json:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/README.md
+//
+// Fields unknown to this crate are kept in `extra` so a meta.json can be read
+// and written again without losing data.
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct BlockStats {
-    #[serde(rename = "numSamples")]
+    #[serde(rename = "numSamples", default)]
     pub num_samples: u64,
-    #[serde(rename = "numSeries")]
+    #[serde(rename = "numFloatSamples", default, skip_serializing_if = "is_zero")]
+    pub num_float_samples: u64,
+    #[serde(
+        rename = "numHistogramSamples",
+        default,
+        skip_serializing_if = "is_zero"
+    )]
+    pub num_histogram_samples: u64,
+    #[serde(rename = "numSeries", default)]
     pub num_series: u64,
-    #[serde(rename = "numChunks")]
+    #[serde(rename = "numChunks", default)]
     pub num_chunks: u64,
+    #[serde(rename = "numTombstones", default, skip_serializing_if = "is_zero")]
+    pub num_tombstones: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BlockDesc {
+    pub ulid: Ulid,
+    #[serde(rename = "minTime")]
+    pub min_time: i64,
+    #[serde(rename = "maxTime")]
+    pub max_time: i64,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct BlockCompaction {
     pub level: u8,
-    pub sources: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Ulid>,
+    // marked for deletion after it was compacted into another block
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deletable: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<BlockDesc>,
+    // compaction of this block failed before
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub failed: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hints: Vec<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThanosDownsample {
+    // resolution in milliseconds, 0 for raw data
+    pub resolution: i64,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThanosFileHash {
+    #[serde(rename = "hashFunc")]
+    pub hash_func: String,
+    pub value: String,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThanosFile {
+    pub rel_path: String,
+    #[serde(default, skip_serializing_if = "is_zero_i64")]
+    pub size_bytes: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<ThanosFileHash>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThanosIndexStats {
+    #[serde(default, skip_serializing_if = "is_zero_i64")]
+    pub series_max_size: i64,
+    #[serde(default, skip_serializing_if = "is_zero_i64")]
+    pub chunk_max_size: i64,
+}
+
+impl ThanosIndexStats {
+    fn is_empty(&self) -> bool {
+        self.series_max_size == 0 && self.chunk_max_size == 0
+    }
+}
+
+// NOTE: Thanos and Mimir extend meta.json with a thanos section:
+// https://github.com/thanos-io/thanos/blob/main/pkg/block/metadata/meta.go
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ThanosMeta {
+    #[serde(default, skip_serializing_if = "is_zero_i64")]
+    pub version: i64,
+    // external labels of the Prometheus or receiver that produced the block
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub downsample: ThanosDownsample,
+    // component that created the block, e.g. sidecar, compactor or receive
+    #[serde(default)]
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segment_files: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<ThanosFile>,
+    #[serde(default, skip_serializing_if = "ThanosIndexStats::is_empty")]
+    pub index_stats: ThanosIndexStats,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+// Blocks can only be compacted with blocks of the same external labels and
+// resolution.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GroupKey {
+    pub resolution: i64,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct MetaData {
     pub version: u8,
-    pub ulid: String, // TODO use ulid type
+    pub ulid: Ulid,
+    // timestamps are int64 milliseconds, minTime is inclusive and maxTime
+    // exclusive
     #[serde(rename = "minTime")]
-    pub min_time: u64,
+    pub min_time: i64,
     #[serde(rename = "maxTime")]
-    pub max_time: u64,
+    pub max_time: i64,
     pub stats: BlockStats,
     pub compaction: BlockCompaction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thanos: Option<ThanosMeta>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl MetaData {
@@ -39,6 +172,47 @@ impl MetaData {
 
         m
     }
+
+    // blocks without a thanos section are raw data without external labels
+    pub fn group_key(&self) -> GroupKey {
+        match &self.thanos {
+            Some(t) => GroupKey {
+                resolution: t.downsample.resolution,
+                labels: t.labels.clone(),
+            },
+            None => GroupKey::default(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = read_to_string(path)?;
+
+        match serde_json::from_str(&content) {
+            Ok(m) => Ok(m),
+            Err(_) => {
+                println!("Failed to deserialize {}.", path.display());
+                Err(TSDBError::InvalidMeta)
+            }
+        }
+    }
+
+    // write the meta.json the way Prometheus does it, indented by tabs and
+    // atomically replacing an existing file
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut buf = Vec::<u8>::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"\t");
+        let mut ser = serde_json::Serializer::with_formatter(&mut buf, formatter);
+
+        if self.serialize(&mut ser).is_err() {
+            return Err(TSDBError::InvalidMeta);
+        }
+
+        let tmp = path.with_extension("json.tmp");
+        write(&tmp, buf)?;
+        rename(&tmp, path)?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -51,18 +225,21 @@ mod test {
 
         let expected = MetaData {
             version: 1,
-            ulid: String::from("01G0P9P6T0VSA5Z484BRGH2N68"),
+            ulid: "01G0P9P6T0VSA5Z484BRGH2N68".parse().unwrap(),
             min_time: 1650005003777,
             max_time: 1650009600000,
             stats: BlockStats {
                 num_samples: 5551013,
                 num_series: 35354,
                 num_chunks: 37020,
+                ..Default::default()
             },
             compaction: BlockCompaction {
                 level: 1,
-                sources: [String::from("01G0P9P6T0VSA5Z484BRGH2N68")].to_vec(),
+                sources: ["01G0P9P6T0VSA5Z484BRGH2N68".parse().unwrap()].to_vec(),
+                ..Default::default()
             },
+            ..Default::default()
         };
 
         assert_eq!(meta, expected);
@@ -88,4 +265,136 @@ mod test {
 
         assert_eq!(expected, ser);
     }
+
+    #[test]
+    fn round_trip_full_meta_data() {
+        let content = r#"{
+	"ulid": "01G0P9P6T0VSA5Z484BRGH2N68",
+	"minTime": -7200000,
+	"maxTime": 0,
+	"stats": {
+		"numSamples": 10,
+		"numFloatSamples": 8,
+		"numHistogramSamples": 2,
+		"numSeries": 1,
+		"numChunks": 1,
+		"numTombstones": 3,
+		"numBytes": 1024
+	},
+	"compaction": {
+		"level": 2,
+		"sources": [
+			"01G0P9P6T0VSA5Z484BRGH2N68"
+		],
+		"deletable": true,
+		"parents": [
+			{
+				"ulid": "01G0P9P6T0VSA5Z484BRGH2N69",
+				"minTime": -7200000,
+				"maxTime": 0
+			}
+		],
+		"failed": true,
+		"hints": [
+			"from-out-of-order"
+		]
+	},
+	"version": 1,
+	"future": {
+		"field": [
+			1,
+			2
+		]
+	}
+}"#;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("meta.json");
+        std::fs::write(&path, content).unwrap();
+
+        let meta = MetaData::load(&path).unwrap();
+        assert_eq!(-7200000, meta.min_time);
+        assert_eq!(3, meta.stats.num_tombstones);
+        assert_eq!(2, meta.stats.num_histogram_samples);
+        assert!(meta.compaction.deletable);
+        assert!(meta.compaction.failed);
+        assert_eq!(vec!["from-out-of-order"], meta.compaction.hints);
+        assert_eq!(-7200000, meta.compaction.parents[0].min_time);
+        assert!(meta.extra.contains_key("future"));
+        assert!(meta.stats.extra.contains_key("numBytes"));
+
+        meta.write(&path).unwrap();
+        assert_eq!(meta, MetaData::load(&path).unwrap());
+
+        let written: Value = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        let expected: Value = serde_json::from_str(content).unwrap();
+        assert_eq!(expected, written);
+    }
+
+    #[test]
+    fn load_thanos_meta_data() {
+        let content = r#"{
+	"ulid": "01G0P9P6T0VSA5Z484BRGH2N68",
+	"minTime": 1650005003777,
+	"maxTime": 1650009600000,
+	"stats": {
+		"numSamples": 5551013,
+		"numSeries": 35354,
+		"numChunks": 37020
+	},
+	"compaction": {
+		"level": 1,
+		"sources": [
+			"01G0P9P6T0VSA5Z484BRGH2N68"
+		]
+	},
+	"version": 1,
+	"thanos": {
+		"labels": {
+			"cluster": "eu-1",
+			"replica": "a"
+		},
+		"downsample": {
+			"resolution": 300000
+		},
+		"source": "compactor",
+		"segment_files": [
+			"000001"
+		],
+		"files": [
+			{
+				"rel_path": "chunks/000001",
+				"size_bytes": 1844
+			},
+			{
+				"rel_path": "meta.json"
+			}
+		],
+		"index_stats": {
+			"series_max_size": 512
+		}
+	}
+}"#;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("meta.json");
+        std::fs::write(&path, content).unwrap();
+
+        let meta = MetaData::load(&path).unwrap();
+        let thanos = meta.thanos.as_ref().unwrap();
+        assert_eq!("compactor", thanos.source);
+        assert_eq!(vec!["000001"], thanos.segment_files);
+        assert_eq!(1844, thanos.files[0].size_bytes);
+        assert_eq!(512, thanos.index_stats.series_max_size);
+        assert!(meta.extra.is_empty());
+
+        let key = meta.group_key();
+        assert_eq!(300000, key.resolution);
+        assert_eq!(Some(&String::from("eu-1")), key.labels.get("cluster"));
+
+        meta.write(&path).unwrap();
+        let written: Value = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        let expected: Value = serde_json::from_str(content).unwrap();
+        assert_eq!(expected, written);
+    }
 }
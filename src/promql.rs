@@ -0,0 +1,814 @@
+use crate::common::*;
+use crate::labels::{MatchType, Matcher, METRIC_NAME};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Atan2,
+    Eql,
+    Neq,
+    Lss,
+    Gtr,
+    Lte,
+    Gte,
+    And,
+    Or,
+    Unless,
+}
+
+impl BinOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And | BinOp::Unless => 2,
+            BinOp::Eql | BinOp::Neq | BinOp::Lss | BinOp::Gtr | BinOp::Lte | BinOp::Gte => 3,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Mul | BinOp::Div | BinOp::Mod | BinOp::Atan2 => 5,
+            BinOp::Pow => 6,
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinOp::Eql | BinOp::Neq | BinOp::Lss | BinOp::Gtr | BinOp::Lte | BinOp::Gte
+        )
+    }
+
+    pub fn is_set(self) -> bool {
+        matches!(self, BinOp::And | BinOp::Or | BinOp::Unless)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggrOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Group,
+    Stddev,
+    Stdvar,
+    Topk,
+    Bottomk,
+    Quantile,
+    CountValues,
+}
+
+impl AggrOp {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sum" => Some(AggrOp::Sum),
+            "avg" => Some(AggrOp::Avg),
+            "min" => Some(AggrOp::Min),
+            "max" => Some(AggrOp::Max),
+            "count" => Some(AggrOp::Count),
+            "group" => Some(AggrOp::Group),
+            "stddev" => Some(AggrOp::Stddev),
+            "stdvar" => Some(AggrOp::Stdvar),
+            "topk" => Some(AggrOp::Topk),
+            "bottomk" => Some(AggrOp::Bottomk),
+            "quantile" => Some(AggrOp::Quantile),
+            "count_values" => Some(AggrOp::CountValues),
+            _ => None,
+        }
+    }
+
+    fn has_param(self) -> bool {
+        matches!(
+            self,
+            AggrOp::Topk | AggrOp::Bottomk | AggrOp::Quantile | AggrOp::CountValues
+        )
+    }
+}
+
+// evaluation time set by the @ modifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum At {
+    // in milliseconds
+    Time(i64),
+    Start,
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSelector {
+    // including the one for the metric name
+    pub matchers: Vec<Matcher>,
+    // in milliseconds
+    pub offset: i64,
+    pub at: Option<At>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cardinality {
+    OneToOne,
+    ManyToOne,
+    OneToMany,
+}
+
+// how the samples of both sides of a binary operation between vectors are
+// matched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorMatching {
+    pub card: Cardinality,
+    // labels of on(...) if on is set, otherwise the ones of ignoring(...)
+    pub on: bool,
+    pub labels: Vec<String>,
+    // labels of group_left(...) or group_right(...) copied from the one side
+    pub include: Vec<String>,
+}
+
+impl Default for VectorMatching {
+    fn default() -> Self {
+        Self {
+            card: Cardinality::OneToOne,
+            on: false,
+            labels: Vec::new(),
+            include: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    String(String),
+    Paren(Box<Expr>),
+    // negation
+    Unary(Box<Expr>),
+    VectorSelector(VectorSelector),
+    // selector with its range in milliseconds
+    MatrixSelector(VectorSelector, i64),
+    Call(String, Vec<Expr>),
+    Aggregate {
+        op: AggrOp,
+        expr: Box<Expr>,
+        param: Option<Box<Expr>>,
+        // labels of by(...) or without(...)
+        grouping: Vec<String>,
+        without: bool,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        return_bool: bool,
+        matching: VectorMatching,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    // in milliseconds
+    Duration(i64),
+    Str(String),
+    Op(BinOp),
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Colon,
+    At,
+    Assign,
+    RegexMatch,
+    RegexNoMatch,
+}
+
+pub fn error<T>(msg: &str) -> Result<T> {
+    println!("Invalid query: {}.", msg);
+    Err(TSDBError::InvalidQuery)
+}
+
+// NOTE: Format of durations:
+// https://prometheus.io/docs/prometheus/latest/querying/basics/#float-literals-and-time-durations
+//
+// Units are ordered from the longest to the shortest and can be combined like
+// in 1h30m.
+pub fn parse_duration(s: &str) -> Option<i64> {
+    const UNITS: [(&str, i64); 7] = [
+        ("y", 365 * 24 * 3600 * 1000),
+        ("w", 7 * 24 * 3600 * 1000),
+        ("d", 24 * 3600 * 1000),
+        ("h", 3600 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+        ("ms", 1),
+    ];
+
+    if s.is_empty() {
+        return None;
+    }
+
+    let mut rest = s;
+    let mut last = 0;
+    let mut duration = 0i64;
+    while !rest.is_empty() {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let unit_len = rest[digits..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphabetic())
+            .count();
+        if digits == 0 || unit_len == 0 {
+            return None;
+        }
+        let n = rest[..digits].parse::<i64>().ok()?;
+        let unit = &rest[digits..digits + unit_len];
+        let (i, (_, ms)) = UNITS.iter().enumerate().find(|(_, (u, _))| *u == unit)?;
+        // every unit at most once and in order
+        if i < last {
+            return None;
+        }
+        last = i + 1;
+        duration = duration.checked_add(n.checked_mul(*ms)?)?;
+        rest = &rest[digits + unit_len..];
+    }
+
+    Some(duration)
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok().map(|n| n as f64);
+    }
+    s.parse::<f64>().ok()
+}
+
+fn lex(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::<Token>::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() {
+                let d = chars[i];
+                let exp_sign = (d == '+' || d == '-')
+                    && matches!(chars[i - 1], 'e' | 'E')
+                    && !chars[start..i].iter().any(|c| *c == 'x' || *c == 'X');
+                if !(d.is_ascii_alphanumeric() || d == '.' || exp_sign) {
+                    break;
+                }
+                i += 1;
+            }
+            let s: String = chars[start..i].iter().collect();
+            match (parse_duration(&s), parse_number(&s)) {
+                (Some(d), _) => tokens.push(Token::Duration(d)),
+                (None, Some(n)) => tokens.push(Token::Number(n)),
+                _ => return error(&format!("bad number or duration {:?}", s)),
+            }
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "_:".contains(chars[i])) {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+
+        if c == '"' || c == '\'' || c == '`' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return error("unterminated string"),
+                    Some(q) if *q == c => break,
+                    Some('\\') if c != '`' => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some(e) => s.push(*e),
+                            None => return error("unterminated string"),
+                        }
+                    }
+                    Some(ch) => s.push(*ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(s));
+            continue;
+        }
+
+        let (token, len) = match (c, next) {
+            ('=', Some('=')) => (Token::Op(BinOp::Eql), 2),
+            ('=', Some('~')) => (Token::RegexMatch, 2),
+            ('!', Some('=')) => (Token::Op(BinOp::Neq), 2),
+            ('!', Some('~')) => (Token::RegexNoMatch, 2),
+            ('<', Some('=')) => (Token::Op(BinOp::Lte), 2),
+            ('>', Some('=')) => (Token::Op(BinOp::Gte), 2),
+            ('=', _) => (Token::Assign, 1),
+            ('<', _) => (Token::Op(BinOp::Lss), 1),
+            ('>', _) => (Token::Op(BinOp::Gtr), 1),
+            ('+', _) => (Token::Op(BinOp::Add), 1),
+            ('-', _) => (Token::Op(BinOp::Sub), 1),
+            ('*', _) => (Token::Op(BinOp::Mul), 1),
+            ('/', _) => (Token::Op(BinOp::Div), 1),
+            ('%', _) => (Token::Op(BinOp::Mod), 1),
+            ('^', _) => (Token::Op(BinOp::Pow), 1),
+            ('(', _) => (Token::LeftParen, 1),
+            (')', _) => (Token::RightParen, 1),
+            ('{', _) => (Token::LeftBrace, 1),
+            ('}', _) => (Token::RightBrace, 1),
+            ('[', _) => (Token::LeftBracket, 1),
+            (']', _) => (Token::RightBracket, 1),
+            (',', _) => (Token::Comma, 1),
+            (':', _) => (Token::Colon, 1),
+            ('@', _) => (Token::At, 1),
+            _ => return error(&format!("unexpected character {:?}", c)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+// NOTE: Grammar of PromQL:
+// https://prometheus.io/docs/prometheus/latest/querying/basics/
+//
+// Binary operators are parsed by precedence climbing, ^ is right associative
+// and binds stronger than the unary minus.
+pub fn parse(input: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: lex(input)?,
+        pos: 0,
+    };
+
+    let expr = parser.expr(0)?;
+    if let Some(t) = parser.peek() {
+        return error(&format!("unexpected {:?}", t));
+    }
+
+    Ok(expr)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, token: Token) -> Result<()> {
+        match self.next() {
+            Some(t) if t == token => Ok(()),
+            t => error(&format!("expected {:?}, got {:?}", token, t)),
+        }
+    }
+
+    fn is_ident(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(s)) if s == name)
+    }
+
+    // binary operator at the current position, including the keywords
+    fn bin_op(&self) -> Option<BinOp> {
+        match self.peek()? {
+            Token::Op(op) => Some(*op),
+            Token::Ident(s) => match s.as_str() {
+                "and" => Some(BinOp::And),
+                "or" => Some(BinOp::Or),
+                "unless" => Some(BinOp::Unless),
+                "atan2" => Some(BinOp::Atan2),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+
+        while let Some(op) = self.bin_op() {
+            let precedence = op.precedence();
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += 1;
+
+            let return_bool = op.is_comparison() && self.is_ident("bool");
+            if return_bool {
+                self.pos += 1;
+            }
+            let matching = self.vector_matching(op)?;
+
+            let rhs = if op == BinOp::Pow {
+                self.expr(precedence)?
+            } else {
+                self.expr(precedence + 1)?
+            };
+
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                return_bool,
+                matching,
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn vector_matching(&mut self, op: BinOp) -> Result<VectorMatching> {
+        let mut matching = VectorMatching::default();
+
+        if self.is_ident("on") || self.is_ident("ignoring") {
+            matching.on = self.is_ident("on");
+            self.pos += 1;
+            matching.labels = self.label_list()?;
+        }
+
+        if self.is_ident("group_left") || self.is_ident("group_right") {
+            if op.is_set() {
+                return error("no grouping allowed for set operations");
+            }
+            matching.card = if self.is_ident("group_left") {
+                Cardinality::ManyToOne
+            } else {
+                Cardinality::OneToMany
+            };
+            self.pos += 1;
+            if self.peek() == Some(&Token::LeftParen) {
+                matching.include = self.label_list()?;
+            }
+        }
+
+        Ok(matching)
+    }
+
+    fn label_list(&mut self) -> Result<Vec<String>> {
+        self.expect(Token::LeftParen)?;
+        let mut labels = Vec::<String>::new();
+        loop {
+            match self.next() {
+                Some(Token::RightParen) => break,
+                Some(Token::Ident(name)) => labels.push(name),
+                t => return error(&format!("expected label name, got {:?}", t)),
+            }
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::RightParen) => break,
+                t => return error(&format!("expected , or ), got {:?}", t)),
+            }
+        }
+
+        Ok(labels)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(Token::Op(BinOp::Sub)) => {
+                self.pos += 1;
+                let expr = self.expr(BinOp::Pow.precedence())?;
+                match expr {
+                    Expr::Number(n) => Ok(Expr::Number(-n)),
+                    e => Ok(Expr::Unary(Box::new(e))),
+                }
+            }
+            Some(Token::Op(BinOp::Add)) => {
+                self.pos += 1;
+                self.expr(BinOp::Pow.precedence())
+            }
+            _ => {
+                let expr = self.primary()?;
+                self.modifiers(expr)
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Str(s)) => Ok(Expr::String(s)),
+            Some(Token::LeftParen) => {
+                let expr = self.expr(0)?;
+                self.expect(Token::RightParen)?;
+                Ok(Expr::Paren(Box::new(expr)))
+            }
+            Some(Token::LeftBrace) => {
+                self.pos -= 1;
+                self.selector(None)
+            }
+            Some(Token::Ident(name)) => {
+                let next = self.peek();
+                let is_call = next == Some(&Token::LeftParen);
+                let lower = name.to_lowercase();
+                if !is_call && next != Some(&Token::LeftBrace) && (lower == "inf" || lower == "nan")
+                {
+                    return Ok(Expr::Number(lower.parse().unwrap_or(f64::NAN)));
+                }
+
+                match AggrOp::from_name(&name) {
+                    Some(op) if is_call || self.is_ident("by") || self.is_ident("without") => {
+                        self.aggregation(op)
+                    }
+                    _ if is_call => self.call(name),
+                    _ => self.selector(Some(name)),
+                }
+            }
+            t => error(&format!("unexpected {:?}", t)),
+        }
+    }
+
+    fn call(&mut self, name: String) -> Result<Expr> {
+        let args = self.args()?;
+        Ok(Expr::Call(name, args))
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>> {
+        self.expect(Token::LeftParen)?;
+        let mut args = Vec::<Expr>::new();
+        if self.peek() == Some(&Token::RightParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expr(0)?);
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::RightParen) => break,
+                t => return error(&format!("expected , or ), got {:?}", t)),
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn grouping(&mut self) -> Result<Option<(bool, Vec<String>)>> {
+        if self.is_ident("by") || self.is_ident("without") {
+            let without = self.is_ident("without");
+            self.pos += 1;
+            return Ok(Some((without, self.label_list()?)));
+        }
+        Ok(None)
+    }
+
+    // sum by (job) (x) and sum (x) by (job) are the same
+    fn aggregation(&mut self, op: AggrOp) -> Result<Expr> {
+        let before = self.grouping()?;
+        let args = self.args()?;
+        let after = self.grouping()?;
+        if before.is_some() && after.is_some() {
+            return error("grouping given twice");
+        }
+        let (without, grouping) = before.or(after).unwrap_or_default();
+
+        let expected = if op.has_param() { 2 } else { 1 };
+        if args.len() != expected {
+            return error(&format!("aggregation expects {} arguments", expected));
+        }
+        let mut args = args.into_iter().map(Box::new);
+        let param = if op.has_param() { args.next() } else { None };
+        let expr = match args.next() {
+            Some(expr) => expr,
+            None => return error("aggregation without expression"),
+        };
+
+        Ok(Expr::Aggregate {
+            op,
+            expr,
+            param,
+            grouping,
+            without,
+        })
+    }
+
+    fn selector(&mut self, name: Option<String>) -> Result<Expr> {
+        let mut matchers = Vec::<Matcher>::new();
+        if let Some(name) = name {
+            matchers.push(Matcher::new(MatchType::Equal, METRIC_NAME, &name)?);
+        }
+
+        if self.peek() == Some(&Token::LeftBrace) {
+            self.pos += 1;
+            loop {
+                let label = match self.next() {
+                    Some(Token::RightBrace) => break,
+                    Some(Token::Ident(label)) => label,
+                    t => return error(&format!("expected label name, got {:?}", t)),
+                };
+                let match_type = match self.next() {
+                    Some(Token::Assign) => MatchType::Equal,
+                    Some(Token::Op(BinOp::Neq)) => MatchType::NotEqual,
+                    Some(Token::RegexMatch) => MatchType::Regex,
+                    Some(Token::RegexNoMatch) => MatchType::NotRegex,
+                    t => return error(&format!("expected label matching operator, got {:?}", t)),
+                };
+                let value = match self.next() {
+                    Some(Token::Str(value)) => value,
+                    t => return error(&format!("expected string, got {:?}", t)),
+                };
+                matchers.push(Matcher::new(match_type, &label, &value)?);
+
+                match self.next() {
+                    Some(Token::Comma) => {}
+                    Some(Token::RightBrace) => break,
+                    t => return error(&format!("expected , or }}, got {:?}", t)),
+                }
+            }
+        }
+
+        if matchers.iter().all(|m| m.matches("")) {
+            return error("vector selector must contain at least one non-empty matcher");
+        }
+
+        Ok(Expr::VectorSelector(VectorSelector {
+            matchers,
+            offset: 0,
+            at: None,
+        }))
+    }
+
+    // range, offset and @ following a selector
+    fn modifiers(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            match (self.peek(), &mut expr) {
+                (Some(Token::LeftBracket), Expr::VectorSelector(vs)) => {
+                    if vs.offset != 0 || vs.at.is_some() {
+                        return error("range must be given before offset and @");
+                    }
+                    let vs = vs.clone();
+                    self.pos += 1;
+                    let range = match self.next() {
+                        Some(Token::Duration(d)) if d > 0 => d,
+                        t => return error(&format!("expected duration, got {:?}", t)),
+                    };
+                    if self.peek() == Some(&Token::Colon) {
+                        return error("subqueries are not supported");
+                    }
+                    self.expect(Token::RightBracket)?;
+                    expr = Expr::MatrixSelector(vs, range);
+                }
+                (Some(Token::Ident(s)), Expr::VectorSelector(vs) | Expr::MatrixSelector(vs, _))
+                    if s == "offset" =>
+                {
+                    self.pos += 1;
+                    let sign = if self.peek() == Some(&Token::Op(BinOp::Sub)) {
+                        self.pos += 1;
+                        -1
+                    } else {
+                        1
+                    };
+                    match self.tokens.get(self.pos) {
+                        Some(Token::Duration(d)) => vs.offset = sign * d,
+                        t => return error(&format!("expected duration, got {:?}", t)),
+                    }
+                    self.pos += 1;
+                }
+                (Some(Token::At), Expr::VectorSelector(vs) | Expr::MatrixSelector(vs, _)) => {
+                    let at = match (self.peek_at(1).cloned(), self.peek_at(2).cloned()) {
+                        (Some(Token::Number(n)), _) => {
+                            self.pos += 2;
+                            At::Time((n * 1000.0).round() as i64)
+                        }
+                        (Some(Token::Ident(f)), Some(Token::LeftParen))
+                            if f == "start" || f == "end" =>
+                        {
+                            let at = if f == "start" { At::Start } else { At::End };
+                            self.pos += 3;
+                            self.expect(Token::RightParen)?;
+                            at
+                        }
+                        (t, _) => return error(&format!("unexpected {:?} after @", t)),
+                    };
+                    vs.at = Some(at);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn selector(name: &str, offset: i64) -> VectorSelector {
+        VectorSelector {
+            matchers: vec![Matcher::new(MatchType::Equal, METRIC_NAME, name).unwrap()],
+            offset,
+            at: None,
+        }
+    }
+
+    #[test]
+    fn parse_queries() {
+        assert_eq!(Some(5400000), parse_duration("1h30m"));
+        assert_eq!(None, parse_duration("30m1h"));
+
+        let expr =
+            parse("sum by (job) (rate(http_requests_total{code=~\"5..\"}[5m] offset 1h))").unwrap();
+        let mut vs = selector("http_requests_total", 3600000);
+        vs.matchers
+            .push(Matcher::new(MatchType::Regex, "code", "5..").unwrap());
+        let expected = Expr::Aggregate {
+            op: AggrOp::Sum,
+            expr: Box::new(Expr::Call(
+                String::from("rate"),
+                vec![Expr::MatrixSelector(vs, 300000)],
+            )),
+            param: None,
+            grouping: vec![String::from("job")],
+            without: false,
+        };
+        assert_eq!(expected, expr);
+
+        // ^ binds stronger than the unary minus and is right associative
+        let expr = parse("-a ^ b ^ 2 * 3").unwrap();
+        let pow = Expr::Binary {
+            op: BinOp::Pow,
+            lhs: Box::new(Expr::VectorSelector(selector("a", 0))),
+            rhs: Box::new(Expr::Binary {
+                op: BinOp::Pow,
+                lhs: Box::new(Expr::VectorSelector(selector("b", 0))),
+                rhs: Box::new(Expr::Number(2.0)),
+                return_bool: false,
+                matching: VectorMatching::default(),
+            }),
+            return_bool: false,
+            matching: VectorMatching::default(),
+        };
+        let expected = Expr::Binary {
+            op: BinOp::Mul,
+            lhs: Box::new(Expr::Unary(Box::new(pow))),
+            rhs: Box::new(Expr::Number(3.0)),
+            return_bool: false,
+            matching: VectorMatching::default(),
+        };
+        assert_eq!(expected, expr);
+
+        let expr = parse("a > bool on (job) group_left (team) b @ 100").unwrap();
+        match expr {
+            Expr::Binary {
+                op,
+                return_bool,
+                matching,
+                rhs,
+                ..
+            } => {
+                assert_eq!(BinOp::Gtr, op);
+                assert!(return_bool);
+                assert_eq!(Cardinality::ManyToOne, matching.card);
+                assert!(matching.on);
+                assert_eq!(vec!["job"], matching.labels);
+                assert_eq!(vec!["team"], matching.include);
+                let mut b = selector("b", 0);
+                b.at = Some(At::Time(100000));
+                assert_eq!(Expr::VectorSelector(b), *rhs);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        assert!(parse("{job=~\".*\"}").is_err());
+        assert!(parse("a offset 5m [5m]").is_err());
+        assert!(parse("sum(a, b)").is_err());
+        assert!(parse("rate(a[5m:1m])").is_err());
+    }
+}
@@ -0,0 +1,586 @@
+use std::{collections::BTreeMap, iter::Peekable, path::Path};
+
+use crate::block::{BlockReader, RawChunk};
+use crate::chunkenc::{
+    cut_chunks, decode_aggr_chunk, decode_xor_chunk, AggrType, DEFAULT_CHUNK_RANGE, ENCODING_AGGR,
+    ENCODING_XOR,
+};
+use crate::common::*;
+use crate::datadir::DataDir;
+use crate::index::{postings, postings_offsets, series_at, symbol_table, ChunkMeta};
+use crate::labels::{matches, Matcher};
+
+// A series of a series set with an iterator over its samples. The block querier
+// decodes the chunks of a series up front, so the iterator only yields them.
+pub struct Series<'a> {
+    pub labels: Labels,
+    samples: Box<dyn Iterator<Item = (i64, f64)> + 'a>,
+}
+
+impl<'a> Series<'a> {
+    pub fn new(labels: Labels, samples: impl Iterator<Item = (i64, f64)> + 'a) -> Self {
+        Self {
+            labels,
+            samples: Box::new(samples),
+        }
+    }
+}
+
+impl Iterator for Series<'_> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.samples.next()
+    }
+}
+
+// A series with its encoded XOR chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkSeries {
+    pub labels: Labels,
+    // sorted by time and not overlapping
+    pub chunks: Vec<RawChunk>,
+}
+
+// cut sorted samples into XOR chunks
+fn encode_chunks(samples: &[(i64, f64)]) -> Vec<RawChunk> {
+    cut_chunks(samples, DEFAULT_CHUNK_RANGE)
+        .into_iter()
+        .map(|c| RawChunk {
+            mint: c.min_time(),
+            maxt: c.max_time(),
+            encoding: ENCODING_XOR,
+            data: c.bytes().to_vec(),
+        })
+        .collect()
+}
+
+// Read access to series by label matchers and a time range.
+pub trait Querier {
+    // Series matching all matchers with their samples between mint and maxt,
+    // both inclusive, sorted by labels.
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series<'_>>>;
+
+    // Like select, but with the chunks overlapping the time range instead of
+    // the samples. Chunks are passed on without decoding them where possible,
+    // so they can hold samples outside of the range.
+    fn select_chunks(&self, matchers: &[Matcher], mint: i64, maxt: i64)
+        -> Result<Vec<ChunkSeries>>;
+
+    // sorted names of all labels
+    fn label_names(&self) -> Result<Vec<String>>;
+
+    // sorted values of a label
+    fn label_values(&self, name: &str) -> Result<Vec<String>>;
+
+    // min and max time of the samples, the max time is exclusive like the one
+    // of a block
+    fn time_range(&self) -> (i64, i64);
+}
+
+// Queries a single block. Series are looked up by the postings of the index
+// and only chunks overlapping the time range are decoded.
+pub struct BlockQuerier {
+    reader: BlockReader,
+    // offsets of the postings lists by label name and value
+    postings: BTreeMap<String, Vec<(String, u64)>>,
+}
+
+impl BlockQuerier {
+    pub fn open(dir: &Path) -> Result<Self> {
+        Self::new(BlockReader::open(dir)?)
+    }
+
+    pub fn new(reader: BlockReader) -> Result<Self> {
+        let mut all = BTreeMap::<String, Vec<(String, u64)>>::new();
+        for (name, value, offset) in postings_offsets(reader.index())? {
+            all.entry(name).or_default().push((value, offset));
+        }
+
+        Ok(Self {
+            reader,
+            postings: all,
+        })
+    }
+
+    pub fn reader(&self) -> &BlockReader {
+        &self.reader
+    }
+
+    // Sorted series references for the matchers. Matchers that match the empty
+    // string also match series without the label, so they are left to the
+    // check of the labels.
+    fn postings_for_matchers(&self, matchers: &[Matcher]) -> Result<Vec<u64>> {
+        let index = self.reader.index();
+        let mut result: Option<Vec<u64>> = None;
+
+        for m in matchers.iter().filter(|m| !m.matches("")) {
+            let mut refs = Vec::<u64>::new();
+            for (value, offset) in self.postings.get(&m.name).into_iter().flatten() {
+                if m.matches(value) {
+                    refs.extend(postings(index, *offset)?);
+                }
+            }
+            refs.sort_unstable();
+            refs.dedup();
+
+            result = match result {
+                Some(mut r) => {
+                    r.retain(|x| refs.binary_search(x).is_ok());
+                    Some(r)
+                }
+                None => Some(refs),
+            };
+        }
+
+        match result {
+            Some(r) => Ok(r),
+            None => match self.postings.get("").and_then(|v| v.first()) {
+                Some((_, offset)) => postings(index, *offset),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    // Series matching the matchers with their chunk metas overlapping the time
+    // range. Series without such chunks are skipped.
+    fn matching_series(
+        &self,
+        matchers: &[Matcher],
+        mint: i64,
+        maxt: i64,
+    ) -> Result<Vec<(u64, Labels, Vec<ChunkMeta>)>> {
+        let index = self.reader.index();
+        let mut sym = symbol_table(index)?;
+        let mut all = Vec::<(u64, Labels, Vec<ChunkMeta>)>::new();
+
+        // series are sorted by labels in the index, so are their references
+        for series_ref in self.postings_for_matchers(matchers)? {
+            let s = series_at(index, series_ref)?;
+            let labels = s.labels(&mut sym)?;
+            if !matches(matchers, &labels) {
+                continue;
+            }
+
+            let mut chunks = s.chunk_metas();
+            chunks.retain(|c| c.mint <= maxt && mint <= c.maxt);
+            if !chunks.is_empty() {
+                all.push((series_ref, labels, chunks));
+            }
+        }
+
+        Ok(all)
+    }
+
+    // Samples of a chunk without the deleted ones. Aggregate chunks of
+    // downsampled blocks give the average of every window, like Thanos does
+    // for functions without a matching aggregate.
+    fn samples(&self, series_ref: u64, chunk: &ChunkMeta) -> Result<Vec<(i64, f64)>> {
+        let (encoding, data) = self.reader.chunk(chunk.chunk_ref)?;
+        if encoding != ENCODING_AGGR {
+            return self.reader.samples(series_ref, chunk);
+        }
+
+        let aggr = decode_aggr_chunk(data)?;
+        let (count, sum) = match (aggr[AggrType::Count as usize], aggr[AggrType::Sum as usize]) {
+            (Some(count), Some(sum)) => (decode_xor_chunk(count)?, decode_xor_chunk(sum)?),
+            _ => return Ok(Vec::new()),
+        };
+        if count.len() != sum.len() {
+            println!("Corrupted aggregate chunk {}.", chunk.chunk_ref);
+            return Err(TSDBError::Default);
+        }
+
+        let deleted = self.reader.tombstones(series_ref);
+        Ok(sum
+            .into_iter()
+            .zip(count)
+            .map(|((t, s), (_, c))| (t, s / c))
+            .filter(|(t, _)| !deleted.iter().any(|(dmin, dmax)| dmin <= t && t <= dmax))
+            .collect())
+    }
+}
+
+impl Querier for BlockQuerier {
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series<'_>>> {
+        let mut set = Vec::<Series>::new();
+
+        for (series_ref, labels, metas) in self.matching_series(matchers, mint, maxt)? {
+            // chunks are decoded up front, so corrupted ones fail the select
+            let mut chunks = Vec::<Vec<(i64, f64)>>::with_capacity(metas.len());
+            for c in metas.iter() {
+                chunks.push(self.samples(series_ref, c)?);
+            }
+
+            let samples = chunks
+                .into_iter()
+                .flatten()
+                .skip_while(move |(t, _)| *t < mint)
+                .take_while(move |(t, _)| *t <= maxt);
+
+            set.push(Series::new(labels, samples));
+        }
+
+        Ok(set)
+    }
+
+    fn select_chunks(
+        &self,
+        matchers: &[Matcher],
+        mint: i64,
+        maxt: i64,
+    ) -> Result<Vec<ChunkSeries>> {
+        let mut set = Vec::<ChunkSeries>::new();
+
+        for (series_ref, labels, metas) in self.matching_series(matchers, mint, maxt)? {
+            let mut chunks = Vec::<RawChunk>::with_capacity(metas.len());
+            for c in metas.iter() {
+                let (encoding, data) = self.reader.chunk(c.chunk_ref)?;
+                // chunks with deleted samples and aggregate chunks are encoded
+                // again as XOR chunks
+                if encoding != ENCODING_XOR || self.reader.has_deletions(series_ref, c) {
+                    chunks.extend(encode_chunks(&self.samples(series_ref, c)?));
+                    continue;
+                }
+                chunks.push(RawChunk {
+                    mint: c.mint,
+                    maxt: c.maxt,
+                    encoding: ENCODING_XOR,
+                    data: data.to_vec(),
+                });
+            }
+            if !chunks.is_empty() {
+                set.push(ChunkSeries { labels, chunks });
+            }
+        }
+
+        Ok(set)
+    }
+
+    fn label_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .postings
+            .keys()
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .collect())
+    }
+
+    fn label_values(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self
+            .postings
+            .get(name)
+            .map(|values| values.iter().map(|(v, _)| v.clone()).collect())
+            .unwrap_or_default())
+    }
+
+    fn time_range(&self) -> (i64, i64) {
+        (self.reader.meta.min_time, self.reader.meta.max_time)
+    }
+}
+
+// Merges the sorted samples of series with the same labels. For duplicate
+// timestamps the sample of the first series is kept.
+struct MergeSamples<'a> {
+    series: Vec<Peekable<Series<'a>>>,
+}
+
+impl Iterator for MergeSamples<'_> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(usize, i64)> = None;
+        for (i, s) in self.series.iter_mut().enumerate() {
+            if let Some((t, _)) = s.peek() {
+                if next.is_none_or(|(_, n)| *t < n) {
+                    next = Some((i, *t));
+                }
+            }
+        }
+
+        let (i, t) = next?;
+        let sample = self.series[i].next();
+        for s in self.series.iter_mut() {
+            s.next_if(|(st, _)| *st == t);
+        }
+
+        sample
+    }
+}
+
+// Queries several blocks at once. Only the queriers overlapping the time range
+// are asked for series and series with the same labels are merged.
+pub struct MergeQuerier {
+    // in order of precedence for duplicate samples
+    queriers: Vec<Box<dyn Querier>>,
+}
+
+impl MergeQuerier {
+    pub fn new(queriers: Vec<Box<dyn Querier>>) -> Self {
+        Self { queriers }
+    }
+
+    // all blocks of a data directory, older blocks first
+    pub fn open(data_dir: &DataDir) -> Result<Self> {
+        let mut queriers = Vec::<Box<dyn Querier>>::with_capacity(data_dir.blocks.len());
+        for b in data_dir.blocks.iter() {
+            queriers.push(Box::new(BlockQuerier::open(&b.dir)?));
+        }
+
+        Ok(Self::new(queriers))
+    }
+}
+
+impl Querier for MergeQuerier {
+    fn select(&self, matchers: &[Matcher], mint: i64, maxt: i64) -> Result<Vec<Series<'_>>> {
+        let mut grouped = BTreeMap::<Labels, Vec<Series>>::new();
+        for q in self.queriers.iter() {
+            let (min_time, max_time) = q.time_range();
+            if maxt < min_time || max_time <= mint {
+                continue;
+            }
+            for s in q.select(matchers, mint, maxt)? {
+                grouped.entry(s.labels.clone()).or_default().push(s);
+            }
+        }
+
+        let mut set = Vec::<Series>::with_capacity(grouped.len());
+        for (labels, mut series) in grouped {
+            if series.len() == 1 {
+                set.extend(series.pop());
+                continue;
+            }
+            let samples = MergeSamples {
+                series: series.into_iter().map(|s| s.peekable()).collect(),
+            };
+            set.push(Series::new(labels, samples));
+        }
+
+        Ok(set)
+    }
+
+    fn select_chunks(
+        &self,
+        matchers: &[Matcher],
+        mint: i64,
+        maxt: i64,
+    ) -> Result<Vec<ChunkSeries>> {
+        let mut grouped = BTreeMap::<Labels, Vec<RawChunk>>::new();
+        for q in self.queriers.iter() {
+            let (min_time, max_time) = q.time_range();
+            if maxt < min_time || max_time <= mint {
+                continue;
+            }
+            for s in q.select_chunks(matchers, mint, maxt)? {
+                grouped.entry(s.labels).or_default().extend(s.chunks);
+            }
+        }
+
+        let mut set = Vec::<ChunkSeries>::with_capacity(grouped.len());
+        for (labels, mut chunks) in grouped {
+            let mut sorted: Vec<&RawChunk> = chunks.iter().collect();
+            sorted.sort_by_key(|c| (c.mint, c.maxt));
+            if sorted.windows(2).any(|w| w[1].mint <= w[0].maxt) {
+                // overlapping chunks are decoded and cut again, the sort is
+                // stable, so samples of earlier queriers win
+                let mut samples = Vec::<(i64, f64)>::new();
+                for c in chunks.iter() {
+                    samples.extend(decode_xor_chunk(&c.data)?);
+                }
+                samples.sort_by_key(|(t, _)| *t);
+                samples.dedup_by_key(|(t, _)| *t);
+                chunks = encode_chunks(&samples);
+            } else {
+                chunks.sort_by_key(|c| c.mint);
+            }
+            set.push(ChunkSeries { labels, chunks });
+        }
+
+        Ok(set)
+    }
+
+    fn label_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::<String>::new();
+        for q in self.queriers.iter() {
+            names.extend(q.label_names()?);
+        }
+        names.sort();
+        names.dedup();
+
+        Ok(names)
+    }
+
+    fn label_values(&self, name: &str) -> Result<Vec<String>> {
+        let mut values = Vec::<String>::new();
+        for q in self.queriers.iter() {
+            values.extend(q.label_values(name)?);
+        }
+        values.sort();
+        values.dedup();
+
+        Ok(values)
+    }
+
+    fn time_range(&self) -> (i64, i64) {
+        self.queriers
+            .iter()
+            .map(|q| q.time_range())
+            .reduce(|(amin, amax), (bmin, bmax)| (amin.min(bmin), amax.max(bmax)))
+            .unwrap_or((0, 0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::BlockWriter;
+    use crate::datadir::Block;
+    use crate::downsample::{downsample, RESOLUTION_5M};
+    use crate::labels::MatchType;
+
+    fn labels(job: &str, instance: &str) -> Labels {
+        let mut l = vec![
+            (String::from("__name__"), String::from("up")),
+            (String::from("job"), String::from(job)),
+        ];
+        if !instance.is_empty() {
+            l.push((String::from("instance"), String::from(instance)));
+            l.sort();
+        }
+        l
+    }
+
+    #[test]
+    fn select_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let samples: Vec<(i64, f64)> = (0..1000).map(|i| (i * 15000, i as f64)).collect();
+
+        let mut writer = BlockWriter::new(dir.path());
+        writer.add_series(labels("a", "1"), &samples);
+        writer.add_series(labels("a", "2"), &samples);
+        writer.add_series(labels("b", ""), &samples);
+        let meta = writer.write().unwrap();
+        let block_dir = dir.path().join(meta.ulid.to_string());
+
+        let mut block = Block::open(&block_dir).unwrap();
+        let instance = Matcher::new(MatchType::Equal, "instance", "2").unwrap();
+        block.delete(&[instance], 0, 100 * 15000).unwrap();
+
+        let querier = BlockQuerier::open(&block_dir).unwrap();
+        assert_eq!(
+            vec!["__name__", "instance", "job"],
+            querier.label_names().unwrap()
+        );
+        assert_eq!(vec!["a", "b"], querier.label_values("job").unwrap());
+
+        let job = Matcher::new(MatchType::Regex, "job", "a|b").unwrap();
+        let set = querier
+            .select(std::slice::from_ref(&job), 0, i64::MAX)
+            .unwrap();
+        let found: Vec<Labels> = set.iter().map(|s| s.labels.clone()).collect();
+        assert_eq!(
+            vec![labels("a", "1"), labels("a", "2"), labels("b", "")],
+            found
+        );
+
+        // a matcher for the empty value selects series without the label
+        let no_instance = Matcher::new(MatchType::Equal, "instance", "").unwrap();
+        let set = querier.select(&[job, no_instance], 0, i64::MAX).unwrap();
+        assert_eq!(1, set.len());
+        assert_eq!(labels("b", ""), set[0].labels);
+
+        let not_one = Matcher::new(MatchType::NotEqual, "instance", "1").unwrap();
+        let mut set = querier.select(&[not_one], 90 * 15000, 600 * 15000).unwrap();
+        assert_eq!(2, set.len());
+        let deleted: Vec<(i64, f64)> = set.remove(0).collect();
+        assert_eq!((101 * 15000, 101.0), deleted[0]);
+        assert_eq!((600 * 15000, 600.0), *deleted.last().unwrap());
+        let all: Vec<(i64, f64)> = set.remove(0).collect();
+        assert_eq!(samples[90..=600].to_vec(), all);
+
+        // no series have samples after the block
+        assert!(querier
+            .select(&[], 1000 * 15000, i64::MAX)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn merge_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let range = |from: i64, to: i64, v: f64| -> Vec<(i64, f64)> {
+            (from..to).map(|i| (i * 15000, v)).collect()
+        };
+
+        let blocks = [
+            vec![("a", range(0, 200, 1.0)), ("b", range(0, 100, 1.0))],
+            // overlaps the end of the first block
+            vec![("a", range(150, 300, 2.0))],
+            vec![("a", range(300, 400, 3.0)), ("c", range(300, 400, 3.0))],
+        ];
+        for series in blocks.iter() {
+            let mut writer = BlockWriter::new(dir.path());
+            for (job, samples) in series {
+                writer.add_series(labels(job, ""), samples);
+            }
+            writer.write().unwrap();
+        }
+
+        let querier = MergeQuerier::open(&DataDir::open(dir.path()).unwrap()).unwrap();
+        assert_eq!((0, 399 * 15000 + 1), querier.time_range());
+        assert_eq!(vec!["a", "b", "c"], querier.label_values("job").unwrap());
+
+        let job = Matcher::new(MatchType::NotEqual, "job", "c").unwrap();
+        let set = querier.select(&[job], 0, i64::MAX).unwrap();
+        let found: Vec<(Labels, Vec<(i64, f64)>)> = set
+            .into_iter()
+            .map(|s| (s.labels.clone(), s.collect()))
+            .collect();
+
+        let mut expected = range(0, 200, 1.0);
+        expected.extend(range(200, 300, 2.0));
+        expected.extend(range(300, 400, 3.0));
+        assert_eq!(
+            vec![
+                (labels("a", ""), expected),
+                (labels("b", ""), range(0, 100, 1.0))
+            ],
+            found
+        );
+
+        let set = querier.select(&[], 250 * 15000, 310 * 15000).unwrap();
+        let found: Vec<(Labels, usize)> = set
+            .into_iter()
+            .map(|s| (s.labels.clone(), s.count()))
+            .collect();
+        assert_eq!(vec![(labels("a", ""), 61), (labels("c", ""), 11)], found);
+    }
+
+    #[test]
+    fn select_downsampled() {
+        let raw_dir = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        // 20 samples in each 5m window
+        let samples: Vec<(i64, f64)> = (0..480).map(|i| (i * 15000, i as f64)).collect();
+
+        let mut writer = BlockWriter::new(raw_dir.path());
+        writer.add_series(labels("a", ""), &samples);
+        let meta = writer.write().unwrap();
+        let raw = Block::open(&raw_dir.path().join(meta.ulid.to_string())).unwrap();
+        downsample(dir.path(), &raw, RESOLUTION_5M).unwrap();
+
+        // the aggregate chunks give the average of every window
+        let querier = MergeQuerier::open(&DataDir::open(dir.path()).unwrap()).unwrap();
+        let set = querier.select(&[], 0, i64::MAX).unwrap();
+        let found: Vec<(i64, f64)> = set.into_iter().next().unwrap().collect();
+        assert_eq!(24, found.len());
+        assert_eq!((RESOLUTION_5M - 1, 9.5), found[0]);
+        assert_eq!((479 * 15000, 469.5), found[23]);
+
+        let set = querier.select_chunks(&[], 0, i64::MAX).unwrap();
+        assert_eq!(1, set.len());
+        assert!(set[0].chunks.iter().all(|c| c.encoding == ENCODING_XOR));
+        let decoded = decode_xor_chunk(&set[0].chunks[0].data).unwrap();
+        assert_eq!(found, decoded);
+    }
+}
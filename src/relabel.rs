@@ -0,0 +1,181 @@
+use regex::Regex;
+
+use crate::common::*;
+
+const DEFAULT_SEPARATOR: &str = ";";
+const DEFAULT_REPLACEMENT: &str = "$1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Replace,
+    Keep,
+    Drop,
+    LabelDrop,
+    LabelKeep,
+    LabelMap,
+}
+
+// NOTE: Semantics of relabel configs:
+// https://prometheus.io/docs/prometheus/latest/configuration/configuration/#relabel_config
+//
+// The regular expression is fully anchored. The other fields default to the
+// values Prometheus uses, so usually only a few have to be set.
+#[derive(Debug, Clone)]
+pub struct RelabelConfig {
+    pub action: Action,
+    pub source_labels: Vec<String>,
+    pub separator: String,
+    pub target_label: String,
+    pub replacement: String,
+    regex: Regex,
+}
+
+impl RelabelConfig {
+    pub fn new(action: Action, regex: &str) -> Result<Self> {
+        let regex = match Regex::new(&format!("^(?:{})$", regex)) {
+            Ok(re) => re,
+            Err(_) => {
+                println!("Invalid regular expression {:?}.", regex);
+                return Err(TSDBError::InvalidMatcher);
+            }
+        };
+
+        Ok(Self {
+            action,
+            source_labels: Vec::new(),
+            separator: String::from(DEFAULT_SEPARATOR),
+            target_label: String::new(),
+            replacement: String::from(DEFAULT_REPLACEMENT),
+            regex,
+        })
+    }
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn set(labels: &mut Labels, name: &str, value: &str) {
+    labels.retain(|(n, _)| n != name);
+    // empty labels are the same as labels that are not set
+    if !value.is_empty() {
+        labels.push((name.to_string(), value.to_string()));
+    }
+}
+
+// Apply the relabel configs in order. Returns None if the series is dropped.
+pub fn relabel(labels: &Labels, configs: &[RelabelConfig]) -> Option<Labels> {
+    let mut labels = labels.clone();
+
+    for c in configs {
+        let value = c
+            .source_labels
+            .iter()
+            .map(|name| crate::labels::get(&labels, name))
+            .collect::<Vec<&str>>()
+            .join(&c.separator);
+
+        match c.action {
+            Action::Replace => {
+                let caps = match c.regex.captures(&value) {
+                    Some(caps) => caps,
+                    None => continue,
+                };
+                let mut target = String::new();
+                caps.expand(&c.target_label, &mut target);
+                if !is_valid_label_name(&target) {
+                    continue;
+                }
+                let mut replaced = String::new();
+                caps.expand(&c.replacement, &mut replaced);
+                set(&mut labels, &target, &replaced);
+            }
+            Action::Keep if !c.regex.is_match(&value) => return None,
+            Action::Drop if c.regex.is_match(&value) => return None,
+            Action::Keep | Action::Drop => {}
+            Action::LabelDrop => labels.retain(|(name, _)| !c.regex.is_match(name)),
+            Action::LabelKeep => labels.retain(|(name, _)| c.regex.is_match(name)),
+            Action::LabelMap => {
+                let mut mapped = labels.clone();
+                for (name, value) in labels.iter() {
+                    if c.regex.is_match(name) {
+                        let target = c.regex.replace(name, c.replacement.as_str());
+                        set(&mut mapped, &target, value);
+                    }
+                }
+                labels = mapped;
+            }
+        }
+    }
+
+    labels.sort();
+    if labels.is_empty() {
+        return None;
+    }
+
+    Some(labels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn relabel_series() {
+        let series = labels(&[
+            ("__meta_pod", "web-1"),
+            ("__name__", "http_requests_total"),
+            ("instance", "10.0.0.1:9090"),
+            ("job", "web"),
+        ]);
+
+        let mut host = RelabelConfig::new(Action::Replace, "(.*):(\\d+)").unwrap();
+        host.source_labels = vec![String::from("instance")];
+        host.target_label = String::from("host");
+        let mut pod = RelabelConfig::new(Action::LabelMap, "__meta_(.+)").unwrap();
+        pod.replacement = String::from("k8s_$1");
+        let drop_meta = RelabelConfig::new(Action::LabelDrop, "__meta_.*|instance").unwrap();
+
+        let expected = labels(&[
+            ("__name__", "http_requests_total"),
+            ("host", "10.0.0.1"),
+            ("job", "web"),
+            ("k8s_pod", "web-1"),
+        ]);
+        let relabeled = relabel(&series, &[host, pod, drop_meta]).unwrap();
+        assert_eq!(expected, relabeled);
+
+        let mut keep = RelabelConfig::new(Action::Keep, "web;.*").unwrap();
+        keep.source_labels = vec![String::from("job"), String::from("host")];
+        assert!(relabel(&relabeled, &[keep.clone()]).is_some());
+        keep.regex = Regex::new("^(?:api;.*)$").unwrap();
+        assert!(relabel(&relabeled, &[keep]).is_none());
+
+        let mut drop = RelabelConfig::new(Action::Drop, "http_.*").unwrap();
+        drop.source_labels = vec![String::from("__name__")];
+        assert!(relabel(&series, &[drop]).is_none());
+
+        // an empty replacement removes the target label
+        let mut remove = RelabelConfig::new(Action::Replace, ".*").unwrap();
+        remove.target_label = String::from("job");
+        remove.replacement = String::new();
+        let keep = RelabelConfig::new(Action::LabelKeep, "__name__|job").unwrap();
+        assert_eq!(
+            labels(&[("__name__", "http_requests_total")]),
+            relabel(&series, &[remove, keep]).unwrap()
+        );
+    }
+}
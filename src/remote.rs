@@ -0,0 +1,436 @@
+use crc::{Crc, CRC_32_ISCSI};
+use prost::Message;
+
+use crate::chunkenc::ENCODING_XOR;
+use crate::common::*;
+use crate::head::Head;
+use crate::labels::{MatchType, Matcher};
+use crate::querier::Querier;
+
+const CASTAGNIOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+pub const CONTENT_TYPE_PROTOBUF: &str = "application/x-protobuf";
+pub const CONTENT_TYPE_STREAMED: &str =
+    "application/x-streamed-protobuf; proto=prometheus.ChunkedReadResponse";
+pub const CONTENT_ENCODING_SNAPPY: &str = "snappy";
+
+// NOTE: Messages of the remote read and write protocols:
+// https://github.com/prometheus/prometheus/blob/main/prompb/remote.proto
+// https://github.com/prometheus/prometheus/blob/main/prompb/types.proto
+//
+// Only the fields used for float samples are declared, unknown fields like
+// the ones of native histograms are skipped when decoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ResponseType {
+    Samples = 0,
+    StreamedXorChunks = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum MatcherType {
+    Eq = 0,
+    Neq = 1,
+    Re = 2,
+    Nre = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ChunkEncoding {
+    Unknown = 0,
+    Xor = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelMatcher {
+    #[prost(enumeration = "MatcherType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Query {
+    #[prost(int64, tag = "1")]
+    pub start_timestamp_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub end_timestamp_ms: i64,
+    #[prost(message, repeated, tag = "3")]
+    pub matchers: Vec<LabelMatcher>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub queries: Vec<Query>,
+    #[prost(enumeration = "ResponseType", repeated, tag = "2")]
+    pub accepted_response_types: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<QueryResult>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Chunk {
+    #[prost(int64, tag = "1")]
+    pub min_time_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub max_time_ms: i64,
+    #[prost(enumeration = "ChunkEncoding", tag = "3")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunkedSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ChunkedReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub chunked_series: Vec<ChunkedSeries>,
+    #[prost(int64, tag = "2")]
+    pub query_index: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+// A response with its HTTP headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadResult {
+    pub content_type: &'static str,
+    pub content_encoding: Option<&'static str>,
+    pub body: Vec<u8>,
+}
+
+fn matchers(query: &Query) -> Result<Vec<Matcher>> {
+    let mut all = Vec::<Matcher>::with_capacity(query.matchers.len());
+    for m in query.matchers.iter() {
+        let match_type = match MatcherType::try_from(m.r#type) {
+            Ok(MatcherType::Eq) => MatchType::Equal,
+            Ok(MatcherType::Neq) => MatchType::NotEqual,
+            Ok(MatcherType::Re) => MatchType::Regex,
+            Ok(MatcherType::Nre) => MatchType::NotRegex,
+            Err(_) => {
+                println!("Unknown matcher type {}.", m.r#type);
+                return Err(TSDBError::InvalidMatcher);
+            }
+        };
+        all.push(Matcher::new(match_type, &m.name, &m.value)?);
+    }
+    Ok(all)
+}
+
+fn labels(labels: Labels) -> Vec<Label> {
+    labels
+        .into_iter()
+        .map(|(name, value)| Label { name, value })
+        .collect()
+}
+
+// requests of both protocols are snappy compressed protobuf messages
+fn decode<M: Message + Default>(request: &[u8]) -> Result<M> {
+    let decompressed = match snap::raw::Decoder::new().decompress_vec(request) {
+        Ok(d) => d,
+        Err(_) => {
+            println!("Invalid snappy compressed request.");
+            return Err(TSDBError::Default);
+        }
+    };
+    match M::decode(decompressed.as_slice()) {
+        Ok(m) => Ok(m),
+        Err(_) => {
+            println!("Invalid request message.");
+            Err(TSDBError::Default)
+        }
+    }
+}
+
+// ┌────────────────────┬────────────────┬────────────────────────────────┐
+// │ len <uvarint>      │ CRC32 <4b>     │ ChunkedReadResponse <len b>    │
+// └────────────────────┴────────────────┴────────────────────────────────┘
+fn write_frame(buf: &mut Vec<u8>, msg: &ChunkedReadResponse) {
+    let data = msg.encode_to_vec();
+    write_varint_u64(buf, data.len() as u64);
+    write_u32(buf, CASTAGNIOLI.checksum(&data));
+    buf.extend_from_slice(&data);
+}
+
+// NOTE: Remote read as described by Prometheus:
+// https://prometheus.io/docs/prometheus/latest/querying/remote_read_api/
+//
+// The request is a snappy compressed ReadRequest. The response type is the
+// first accepted one, samples are sent as one snappy compressed ReadResponse,
+// streamed chunks as a frame per series with the chunks of the querier as
+// they are.
+pub fn read(querier: &dyn Querier, request: &[u8]) -> Result<ReadResult> {
+    let request: ReadRequest = decode(request)?;
+
+    let streamed = request
+        .accepted_response_types
+        .first()
+        .is_some_and(|t| *t == ResponseType::StreamedXorChunks as i32);
+
+    if streamed {
+        let mut body = Vec::<u8>::new();
+        for (i, query) in request.queries.iter().enumerate() {
+            let matchers = matchers(query)?;
+            let set = querier.select_chunks(
+                &matchers,
+                query.start_timestamp_ms,
+                query.end_timestamp_ms,
+            )?;
+            for s in set {
+                let chunks = s
+                    .chunks
+                    .into_iter()
+                    .filter(|c| c.encoding == ENCODING_XOR)
+                    .map(|c| Chunk {
+                        min_time_ms: c.mint,
+                        max_time_ms: c.maxt,
+                        r#type: ChunkEncoding::Xor as i32,
+                        data: c.data,
+                    })
+                    .collect();
+                let series = ChunkedSeries {
+                    labels: labels(s.labels),
+                    chunks,
+                };
+                write_frame(
+                    &mut body,
+                    &ChunkedReadResponse {
+                        chunked_series: vec![series],
+                        query_index: i as i64,
+                    },
+                );
+            }
+        }
+
+        return Ok(ReadResult {
+            content_type: CONTENT_TYPE_STREAMED,
+            content_encoding: None,
+            body,
+        });
+    }
+
+    let mut response = ReadResponse::default();
+    for query in request.queries.iter() {
+        let matchers = matchers(query)?;
+        let mut result = QueryResult::default();
+        for s in querier.select(&matchers, query.start_timestamp_ms, query.end_timestamp_ms)? {
+            let labels = labels(s.labels.clone());
+            let samples = s
+                .map(|(timestamp, value)| Sample { value, timestamp })
+                .collect();
+            result.timeseries.push(TimeSeries { labels, samples });
+        }
+        response.results.push(result);
+    }
+
+    let body = match snap::raw::Encoder::new().compress_vec(&response.encode_to_vec()) {
+        Ok(b) => b,
+        Err(_) => return Err(TSDBError::Default),
+    };
+    Ok(ReadResult {
+        content_type: CONTENT_TYPE_PROTOBUF,
+        content_encoding: Some(CONTENT_ENCODING_SNAPPY),
+        body,
+    })
+}
+
+// NOTE: Remote write 1.0 as specified by Prometheus:
+// https://prometheus.io/docs/specs/remote_write_spec/
+//
+// The request is a snappy compressed WriteRequest. Samples the head rejects are
+// skipped and the error is returned once all other samples are committed, so
+// the sender does not retry the request. Returns the number of appended
+// samples.
+pub fn write(head: &mut Head, request: &[u8]) -> Result<usize> {
+    let request: WriteRequest = decode(request)?;
+    let mut appended = 0;
+    let mut rejected = None;
+
+    for ts in request.timeseries {
+        let labels: Labels = ts.labels.into_iter().map(|l| (l.name, l.value)).collect();
+        for s in ts.samples {
+            match head.append(labels.clone(), s.timestamp, s.value) {
+                Ok(()) => appended += 1,
+                Err(e) => rejected = Some(e),
+            }
+        }
+    }
+    head.commit()?;
+
+    match rejected {
+        Some(e) => Err(e),
+        None => Ok(appended),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{BlockReader, BlockWriter};
+    use crate::querier::BlockQuerier;
+
+    fn request(response_type: ResponseType) -> Vec<u8> {
+        let request = ReadRequest {
+            queries: vec![Query {
+                start_timestamp_ms: 0,
+                end_timestamp_ms: 600 * 15000,
+                matchers: vec![LabelMatcher {
+                    r#type: MatcherType::Eq as i32,
+                    name: String::from("__name__"),
+                    value: String::from("up"),
+                }],
+            }],
+            accepted_response_types: vec![response_type as i32],
+        };
+        snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn remote_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let samples: Vec<(i64, f64)> = (0..1000).map(|i| (i * 15000, i as f64)).collect();
+        let mut writer = BlockWriter::new(dir.path());
+        writer.add_series(
+            vec![
+                (String::from("__name__"), String::from("up")),
+                (String::from("job"), String::from("a")),
+            ],
+            &samples,
+        );
+        let meta = writer.write().unwrap();
+        let block_dir = dir.path().join(meta.ulid.to_string());
+        let querier = BlockQuerier::open(&block_dir).unwrap();
+
+        let result = read(&querier, &request(ResponseType::Samples)).unwrap();
+        assert_eq!(Some(CONTENT_ENCODING_SNAPPY), result.content_encoding);
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&result.body)
+            .unwrap();
+        let response = ReadResponse::decode(body.as_slice()).unwrap();
+        let series = &response.results[0].timeseries;
+        assert_eq!(1, series.len());
+        assert_eq!("job", series[0].labels[1].name);
+        assert_eq!(601, series[0].samples.len());
+        assert_eq!(600.0, series[0].samples[600].value);
+
+        let result = read(&querier, &request(ResponseType::StreamedXorChunks)).unwrap();
+        assert_eq!(CONTENT_TYPE_STREAMED, result.content_type);
+        let (len, size) = read_varint_u64(&result.body, 0).unwrap();
+        let data = &result.body[size + 4..];
+        assert_eq!(len as usize, data.len());
+        assert_eq!(
+            CASTAGNIOLI.checksum(data),
+            read_u32(&result.body, size).unwrap()
+        );
+
+        // the chunks are the ones of the block
+        let response = ChunkedReadResponse::decode(data).unwrap();
+        let reader = BlockReader::open(&block_dir).unwrap();
+        let block_chunks = &reader.series().unwrap()[0].chunks;
+        let chunks = &response.chunked_series[0].chunks;
+        assert!(chunks.len() < block_chunks.len());
+        for (c, meta) in chunks.iter().zip(block_chunks.iter()) {
+            assert_eq!(meta.mint, c.min_time_ms);
+            assert_eq!(reader.chunk(meta.chunk_ref).unwrap().1, c.data.as_slice());
+        }
+    }
+
+    #[test]
+    fn remote_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut head = Head::open(dir.path()).unwrap();
+        let request = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![
+                    Label {
+                        name: String::from("__name__"),
+                        value: String::from("up"),
+                    },
+                    Label {
+                        name: String::from("job"),
+                        value: String::from("a"),
+                    },
+                ],
+                samples: (0..10)
+                    .map(|i| Sample {
+                        value: 1.0,
+                        timestamp: i * 15000,
+                    })
+                    .collect(),
+            }],
+        };
+        let payload = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+
+        assert_eq!(10, write(&mut head, &payload).unwrap());
+        assert!(write(&mut head, b"invalid").is_err());
+
+        // samples older than the last one of the series are rejected
+        let mut request = request;
+        request.timeseries[0].samples[9].value = 2.0;
+        let payload = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+        assert!(matches!(
+            write(&mut head, &payload),
+            Err(TSDBError::OutOfOrderSample)
+        ));
+
+        let head = Head::open(dir.path()).unwrap();
+        assert_eq!(1, head.num_series());
+        assert_eq!((0, 9 * 15000 + 1), head.time_range());
+    }
+}
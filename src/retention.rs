@@ -0,0 +1,298 @@
+use std::{
+    fmt,
+    fs::{read_dir, remove_dir_all, rename},
+    path::Path,
+};
+
+use crate::common::*;
+use crate::datadir::DataDir;
+use crate::ulid::Ulid;
+
+const DELETION_SUFFIX: &str = ".tmp-for-deletion";
+
+// NOTE: Retention works like in Prometheus:
+// https://prometheus.io/docs/prometheus/latest/storage/#operational-aspects
+//
+// Time based retention is relative to the newest block, not the current time.
+// Size based retention counts the WAL and head chunks as well, but only ever
+// deletes whole blocks, oldest first.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Retention {
+    // in milliseconds
+    pub time: Option<i64>,
+    // in bytes
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionReason {
+    Time,
+    Size,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDeletion {
+    pub ulid: Ulid,
+    pub min_time: i64,
+    pub max_time: i64,
+    pub bytes: u64,
+    pub reason: RetentionReason,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    // oldest blocks first
+    pub deleted: Vec<BlockDeletion>,
+    pub deleted_bytes: u64,
+    // size of the remaining blocks, WAL and head chunks
+    pub retained_bytes: u64,
+}
+
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        };
+
+        for d in self.deleted.iter() {
+            let reason = match d.reason {
+                RetentionReason::Time => "time",
+                RetentionReason::Size => "size",
+            };
+            writeln!(
+                f,
+                "{} block {} [{}, {}) with {} bytes beyond {} retention.",
+                action, d.ulid, d.min_time, d.max_time, d.bytes, reason
+            )?;
+        }
+
+        writeln!(
+            f,
+            "{} {} blocks with {} bytes, {} bytes retained.",
+            action,
+            self.deleted.len(),
+            self.deleted_bytes,
+            self.retained_bytes
+        )
+    }
+}
+
+// size of all files in a directory and its subdirectories
+pub fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+// Delete the blocks of a data directory beyond the time or size retention.
+// With dry_run nothing is deleted and only the report is created. Blocks are
+// renamed before they are deleted, so a partially deleted block is never
+// loaded. Blocks of a data directory locked by a running Prometheus are only
+// deleted with force.
+pub fn apply_retention(
+    data_dir: &mut DataDir,
+    retention: &Retention,
+    dry_run: bool,
+    force: bool,
+) -> Result<RetentionReport> {
+    if data_dir.locked && !dry_run && !force {
+        println!(
+            "Data directory {} is locked by a running Prometheus.",
+            data_dir.dir.display()
+        );
+        return Err(TSDBError::Default);
+    }
+
+    let mut report = RetentionReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    for dir in [&data_dir.wal, &data_dir.wbl, &data_dir.chunks_head]
+        .into_iter()
+        .flatten()
+    {
+        report.retained_bytes += dir_size(dir)?;
+    }
+
+    // newest blocks first
+    let mut blocks = Vec::<(usize, u64)>::with_capacity(data_dir.blocks.len());
+    for (i, b) in data_dir.blocks.iter().enumerate() {
+        blocks.push((i, dir_size(&b.dir)?));
+    }
+    blocks.sort_by_key(|(i, _)| std::cmp::Reverse(data_dir.blocks[*i].meta.max_time));
+
+    let newest = blocks
+        .first()
+        .map(|(i, _)| data_dir.blocks[*i].meta.max_time);
+    let mut deleted = Vec::<(usize, RetentionReason)>::new();
+    let mut over_size = false;
+
+    for (n, (i, bytes)) in blocks.iter().enumerate() {
+        let meta = &data_dir.blocks[*i].meta;
+        // the newest block is never too old, like in Prometheus
+        let reason = match (retention.time, newest) {
+            (Some(time), Some(newest)) if n > 0 && newest - meta.max_time >= time => {
+                Some(RetentionReason::Time)
+            }
+            _ if over_size => Some(RetentionReason::Size),
+            _ => match retention.size {
+                Some(size) if report.retained_bytes + bytes > size => {
+                    over_size = true;
+                    Some(RetentionReason::Size)
+                }
+                _ => None,
+            },
+        };
+
+        match reason {
+            Some(reason) => {
+                deleted.push((*i, reason));
+                report.deleted_bytes += bytes;
+                report.deleted.push(BlockDeletion {
+                    ulid: meta.ulid,
+                    min_time: meta.min_time,
+                    max_time: meta.max_time,
+                    bytes: *bytes,
+                    reason,
+                });
+            }
+            None => report.retained_bytes += bytes,
+        }
+    }
+    report.deleted.reverse();
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    for (i, _) in deleted.iter() {
+        let block = &data_dir.blocks[*i];
+        let tmp = block
+            .dir
+            .with_file_name(format!("{}{}", block.meta.ulid, DELETION_SUFFIX));
+        rename(&block.dir, &tmp)?;
+        remove_dir_all(&tmp)?;
+    }
+
+    let blocks = std::mem::take(&mut data_dir.blocks);
+    data_dir.blocks = blocks
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !deleted.iter().any(|(d, _)| d == i))
+        .map(|(_, b)| b)
+        .collect();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::meta::MetaData;
+    use std::fs::{create_dir, write};
+
+    fn write_block(dir: &Path, min_time: i64, max_time: i64, bytes: usize) -> Ulid {
+        let ulid = Ulid::from_parts(min_time as u64, 0);
+        let block = dir.join(ulid.to_string());
+        create_dir(&block).unwrap();
+        write(block.join("index"), vec![0u8; bytes]).unwrap();
+
+        let meta = MetaData {
+            version: 1,
+            ulid,
+            min_time,
+            max_time,
+            ..Default::default()
+        };
+        meta.write(&block.join("meta.json")).unwrap();
+
+        ulid
+    }
+
+    #[test]
+    fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let hour = 3600 * 1000;
+        let ulids: Vec<Ulid> = (0..6)
+            .map(|i| write_block(dir.path(), i * 2 * hour, (i + 1) * 2 * hour, 100000))
+            .collect();
+        create_dir(dir.path().join("wal")).unwrap();
+        write(dir.path().join("wal/00000000"), vec![0u8; 50000]).unwrap();
+
+        // meta.json files differ slightly in size
+        let sizes: Vec<u64> = ulids
+            .iter()
+            .map(|u| dir_size(&dir.path().join(u.to_string())).unwrap())
+            .collect();
+        let block_size = sizes[0];
+        let mut data_dir = DataDir::open(dir.path()).unwrap();
+
+        // the two oldest blocks end more than 7h before the newest block
+        let retention = Retention {
+            time: Some(7 * hour),
+            size: Some(2 * block_size + 60000),
+        };
+        let report = apply_retention(&mut data_dir, &retention, true, false).unwrap();
+        let deleted: Vec<(Ulid, RetentionReason)> =
+            report.deleted.iter().map(|d| (d.ulid, d.reason)).collect();
+        assert_eq!(
+            vec![
+                (ulids[0], RetentionReason::Time),
+                (ulids[1], RetentionReason::Time),
+                (ulids[2], RetentionReason::Size),
+                (ulids[3], RetentionReason::Size),
+            ],
+            deleted
+        );
+        assert_eq!(sizes[..4].iter().sum::<u64>(), report.deleted_bytes);
+        assert_eq!(sizes[4] + sizes[5] + 50000, report.retained_bytes);
+        assert!(report.to_string().starts_with("Would delete block"));
+
+        // a dry run does not delete anything
+        assert_eq!(6, DataDir::open(dir.path()).unwrap().blocks.len());
+
+        // blocks ending exactly the retention time before the newest block
+        // are deleted, but never the newest block itself
+        for (time, n) in [(6 * hour, 3), (0, 5)] {
+            let retention = Retention {
+                time: Some(time),
+                size: None,
+            };
+            let report = apply_retention(&mut data_dir, &retention, true, false).unwrap();
+            let deleted: Vec<Ulid> = report.deleted.iter().map(|d| d.ulid).collect();
+            assert_eq!(ulids[..n].to_vec(), deleted);
+        }
+
+        let retention = Retention {
+            time: Some(7 * hour),
+            size: None,
+        };
+        // a locked data directory is only changed with force
+        write(dir.path().join("lock"), "").unwrap();
+        let mut data_dir = DataDir::open(dir.path()).unwrap();
+        assert!(apply_retention(&mut data_dir, &retention, true, false).is_ok());
+        assert!(apply_retention(&mut data_dir, &retention, false, false).is_err());
+        assert_eq!(6, DataDir::open(dir.path()).unwrap().blocks.len());
+
+        let report = apply_retention(&mut data_dir, &retention, false, true).unwrap();
+        assert_eq!(2, report.deleted.len());
+        assert_eq!(4, data_dir.blocks.len());
+        assert_eq!(ulids[2], data_dir.blocks[0].meta.ulid);
+
+        let reopened = DataDir::open(dir.path()).unwrap();
+        let remaining: Vec<Ulid> = reopened.blocks.iter().map(|b| b.meta.ulid).collect();
+        assert_eq!(ulids[2..].to_vec(), remaining);
+    }
+}
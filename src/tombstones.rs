@@ -0,0 +1,153 @@
+use crc::{Crc, CRC_32_ISCSI};
+use std::{
+    fs::{read, rename, write},
+    path::Path,
+};
+
+use crate::common::*;
+use crate::wal::RefTombstone;
+
+const CASTAGNIOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+const MAGIC_TOMBSTONES: u32 = 0x0130BA30;
+const TOMBSTONES_FORMAT_V1: u8 = 1;
+const HEADER_SIZE: usize = 5;
+const CHECKSUM_SIZE: usize = 4;
+
+// NOTE: Format of the tombstones file:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/tombstones.md
+//
+// ┌────────────────────────────┬─────────────────────┐
+// │ magic(0x0130BA30) <4b>     │ version(1) <1 byte> │
+// ├────────────────────────────┴─────────────────────┤
+// │ ┌──────────────────────────────────────────────┐ │
+// │ │ series ref <uvarint64>                       │ │
+// │ ├──────────────────────────────────────────────┤ │
+// │ │ mint <varint64>                              │ │
+// │ ├──────────────────────────────────────────────┤ │
+// │ │ maxt <varint64>                              │ │
+// │ └──────────────────────────────────────────────┘ │
+// │                       ...                        │
+// ├──────────────────────────────────────────────────┤
+// │ CRC<4b>                                          │
+// └──────────────────────────────────────────────────┘
+pub fn write_tombstones(path: &Path, tombstones: &[RefTombstone]) -> Result<()> {
+    let mut buf = Vec::<u8>::new();
+    write_u32(&mut buf, MAGIC_TOMBSTONES);
+    buf.push(TOMBSTONES_FORMAT_V1);
+
+    let start = buf.len();
+    for t in tombstones {
+        write_varint_u64(&mut buf, t.series_ref);
+        write_varint_i64(&mut buf, t.mint);
+        write_varint_i64(&mut buf, t.maxt);
+    }
+    let crc = CASTAGNIOLI.checksum(&buf[start..]);
+    write_u32(&mut buf, crc);
+
+    // replace an existing file atomically
+    let tmp = path.with_extension("tmp");
+    write(&tmp, buf)?;
+    rename(&tmp, path)?;
+
+    Ok(())
+}
+
+pub fn read_tombstones(path: &Path) -> Result<Vec<RefTombstone>> {
+    let buf = read(path)?;
+    if buf.len() < HEADER_SIZE + CHECKSUM_SIZE
+        || read_u32(&buf, 0)? != MAGIC_TOMBSTONES
+        || buf[4] != TOMBSTONES_FORMAT_V1
+    {
+        println!("Invalid tombstones file {}.", path.display());
+        return Err(TSDBError::Default);
+    }
+
+    let end = buf.len() - CHECKSUM_SIZE;
+    let data = slice_bytes(&buf, end - HEADER_SIZE, HEADER_SIZE);
+    if CASTAGNIOLI.checksum(data) != get_checksum(&buf, end)? {
+        println!("Checksum mismatch. Corrupted tombstones.");
+        return Err(TSDBError::Checksum);
+    }
+
+    let mut pos = 0;
+    let mut tombstones = Vec::<RefTombstone>::new();
+    while pos < data.len() {
+        let (series_ref, size) = read_varint_u64(data, pos)?;
+        pos += size;
+        let (mint, size) = read_varint_i64(data, pos)?;
+        pos += size;
+        let (maxt, size) = read_varint_i64(data, pos)?;
+        pos += size;
+
+        if size == 0 {
+            return Err(TSDBError::Default);
+        }
+
+        tombstones.push(RefTombstone {
+            series_ref,
+            mint,
+            maxt,
+        });
+    }
+
+    Ok(tombstones)
+}
+
+// Sort tombstones by series and merge overlapping or adjacent intervals of a
+// series. Both ends of an interval are inclusive.
+pub fn merge_tombstones(mut tombstones: Vec<RefTombstone>) -> Vec<RefTombstone> {
+    tombstones.sort_by_key(|t| (t.series_ref, t.mint, t.maxt));
+
+    let mut merged = Vec::<RefTombstone>::with_capacity(tombstones.len());
+    for t in tombstones {
+        match merged.last_mut() {
+            Some(l) if l.series_ref == t.series_ref && t.mint <= l.maxt.saturating_add(1) => {
+                l.maxt = l.maxt.max(t.maxt);
+            }
+            _ => merged.push(t),
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_write_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tombstones");
+        let t = |series_ref, mint, maxt| RefTombstone {
+            series_ref,
+            mint,
+            maxt,
+        };
+
+        let merged = merge_tombstones(vec![
+            t(5, 100, 200),
+            t(3, -10, 10),
+            t(5, 201, 300),
+            t(5, 150, 160),
+            t(5, 400, 500),
+        ]);
+        assert_eq!(vec![t(3, -10, 10), t(5, 100, 300), t(5, 400, 500)], merged);
+
+        write_tombstones(&path, &merged).unwrap();
+        assert_eq!(merged, read_tombstones(&path).unwrap());
+        assert!(read_tombstones(Path::new("testdata/testblock/tombstones"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn write_empty_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tombstones");
+        write_tombstones(&path, &[]).unwrap();
+
+        let expected = std::fs::read("testdata/testblock/tombstones").unwrap();
+        assert_eq!(expected, std::fs::read(&path).unwrap());
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::common::*;
+
+// Crockford's base32 alphabet
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const ULID_LEN: usize = 26;
+const RANDOM_BITS: u32 = 80;
+
+// NOTE: Spec of ULIDs:
+// https://github.com/ulid/spec
+//
+// ┌──────────────────────────┬─────────────────────┐
+// │ timestamp in ms <48 bit> │ randomness <80 bit> │
+// └──────────────────────────┴─────────────────────┘
+//
+// ULIDs are ordered by their creation time, Prometheus uses them to name
+// blocks. The default is the nil ULID, new ones come from new().
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ulid(u128);
+
+impl Ulid {
+    // new ULID for the current time
+    pub fn new() -> Self {
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as u64,
+            Err(_) => 0,
+        };
+
+        // randomness from the OS, like crypto/rand in the ulid package
+        let mut random = [0u8; 16];
+        getrandom::getrandom(&mut random).expect("no randomness from the OS");
+
+        Ulid::from_parts(now, u128::from_be_bytes(random))
+    }
+
+    pub fn from_parts(timestamp: u64, random: u128) -> Self {
+        Ulid((timestamp as u128) << RANDOM_BITS | random & ((1 << RANDOM_BITS) - 1))
+    }
+
+    // creation time in milliseconds since the epoch
+    pub fn timestamp(&self) -> u64 {
+        (self.0 >> RANDOM_BITS) as u64
+    }
+}
+
+fn decode_char(c: u8) -> Option<u8> {
+    ALPHABET
+        .iter()
+        .position(|a| *a == c.to_ascii_uppercase())
+        .map(|p| p as u8)
+}
+
+impl FromStr for Ulid {
+    type Err = TSDBError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.len() != ULID_LEN {
+            return Err(TSDBError::InvalidUlid);
+        }
+
+        // 26 characters encode 130 bits, so the first one can not exceed 7
+        let mut n: u128 = 0;
+        for (i, c) in s.bytes().enumerate() {
+            match decode_char(c) {
+                Some(d) if i == 0 && d > 7 => return Err(TSDBError::InvalidUlid),
+                Some(d) => n = n << 5 | d as u128,
+                None => return Err(TSDBError::InvalidUlid),
+            }
+        }
+
+        Ok(Ulid(n))
+    }
+}
+
+impl fmt::Display for Ulid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = [0u8; ULID_LEN];
+        for (i, b) in buf.iter_mut().enumerate() {
+            let shift = 5 * (ULID_LEN - 1 - i);
+            *b = ALPHABET[(self.0 >> shift) as usize & 0x1f];
+        }
+
+        // the alphabet is ascii only
+        f.write_str(std::str::from_utf8(&buf).unwrap())
+    }
+}
+
+impl Serialize for Ulid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ulid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid ULID {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ulid() {
+        let s = "01G0P9P6T0VSA5Z484BRGH2N68";
+        let ulid: Ulid = s.parse().unwrap();
+
+        assert_eq!(1650015804224, ulid.timestamp());
+        assert_eq!(s, ulid.to_string());
+        assert_eq!(ulid, s.to_lowercase().parse().unwrap());
+    }
+
+    #[test]
+    fn invalid_ulid() {
+        // too short, invalid character, overflow
+        for s in [
+            "01G0P9P6T0VSA5Z484BRGH2N6",
+            "01G0P9P6T0VSA5Z484BRGH2NU8",
+            "81G0P9P6T0VSA5Z484BRGH2N68",
+        ] {
+            assert!(s.parse::<Ulid>().is_err());
+        }
+    }
+
+    #[test]
+    fn new_ulid() {
+        let a = Ulid::new();
+        let b = Ulid::new();
+
+        assert_ne!(a, b);
+        assert!(a.timestamp() > 1650015804224);
+        assert_eq!(a, a.to_string().parse().unwrap());
+        assert_eq!(Ulid::from_parts(0, 0), Ulid::default());
+    }
+
+    #[test]
+    fn order_ulids() {
+        let a = Ulid::from_parts(1000, u128::MAX);
+        let b = Ulid::from_parts(1001, 0);
+
+        assert!(a < b);
+        assert!(a.to_string() < b.to_string());
+        assert_eq!(1000, a.timestamp());
+    }
+}
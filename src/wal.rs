@@ -0,0 +1,1381 @@
+use crc::{Crc, CRC_32_ISCSI};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str,
+};
+
+use crate::common::*;
+
+const CASTAGNIOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+const PAGE_SIZE: usize = 32 * 1024;
+const RECORD_HEADER_SIZE: usize = 7;
+const RECORD_LEN_SIZE: usize = 2;
+const CHECKPOINT_PREFIX: &str = "checkpoint.";
+const TMP_SUFFIX: &str = ".tmp";
+// segments are cut once the next record does not fit anymore
+pub const DEFAULT_SEGMENT_SIZE: usize = 128 * 1024 * 1024;
+
+// fragment types, stored in the lower 3 bits of the fragment type byte
+const PAGE_TERM: u8 = 0;
+const FULL: u8 = 1;
+const FIRST: u8 = 2;
+const MIDDLE: u8 = 3;
+const LAST: u8 = 4;
+const TYPE_MASK: u8 = 0b111;
+const SNAPPY_MASK: u8 = 1 << 3;
+const ZSTD_MASK: u8 = 1 << 4;
+
+// record types, stored in the first byte of a record
+const SERIES: u8 = 1;
+const SAMPLES: u8 = 2;
+const TOMBSTONES: u8 = 3;
+const MMAP_MARKERS: u8 = 5;
+
+// NOTE: Format of the write ahead log:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/wal.md
+//
+// ┌──────────────────────────────────────────────────┐
+// │ type <1b> │ len <2b> │ CRC32 <4b> │ data <bytes> │
+// └──────────────────────────────────────────────────┘
+//
+// Records are written into 32KiB pages and split into fragments if they do not
+// fit into the remainder of the current page. A record never spans segments.
+#[derive(Debug)]
+pub struct Segment {
+    buf: Vec<u8>,
+    current_pos: usize,
+}
+
+impl Segment {
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut f = File::open(path)?;
+        let mut buf: Vec<u8> = Vec::new();
+
+        f.read_to_end(&mut buf)?;
+
+        Ok(Segment::from(buf))
+    }
+
+    fn skip_page(&mut self) {
+        self.current_pos += PAGE_SIZE - self.current_pos % PAGE_SIZE;
+    }
+
+    fn fragment(&mut self) -> Result<Option<(u8, &[u8])>> {
+        loop {
+            if self.current_pos >= self.buf.len() {
+                return Ok(None);
+            }
+
+            // the remainder of a page is zero padded if a header does not fit
+            let page_left = PAGE_SIZE - self.current_pos % PAGE_SIZE;
+            if page_left < RECORD_HEADER_SIZE || self.buf[self.current_pos] == PAGE_TERM {
+                self.skip_page();
+                continue;
+            }
+
+            if self.current_pos + RECORD_HEADER_SIZE > self.buf.len() {
+                return Err(TSDBError::Default);
+            }
+
+            let typ = self.buf[self.current_pos];
+            let len = read_u16(&self.buf, self.current_pos + 1)? as usize;
+            let cs = get_checksum(&self.buf, self.current_pos + 1 + RECORD_LEN_SIZE)?;
+            let start = self.current_pos + RECORD_HEADER_SIZE;
+
+            if len > page_left - RECORD_HEADER_SIZE || start + len > self.buf.len() {
+                return Err(TSDBError::Default);
+            }
+
+            let data = slice_bytes(&self.buf, len, start);
+            if CASTAGNIOLI.checksum(data) != cs {
+                println!("Checksum mismatch. Corrupted WAL record.");
+                return Err(TSDBError::Checksum);
+            }
+
+            self.current_pos = start + len;
+
+            return Ok(Some((typ, data)));
+        }
+    }
+
+    fn record(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut record = Vec::<u8>::new();
+        let mut compression = 0;
+        let mut complete = false;
+
+        while let Some((typ, data)) = self.fragment()? {
+            compression = typ & (SNAPPY_MASK | ZSTD_MASK);
+            record.extend_from_slice(data);
+
+            match typ & TYPE_MASK {
+                FULL if record.len() == data.len() => complete = true,
+                FIRST if record.len() == data.len() => continue,
+                MIDDLE if record.len() > data.len() => continue,
+                LAST if record.len() > data.len() => complete = true,
+                _ => return Err(TSDBError::Default),
+            }
+            break;
+        }
+
+        if record.is_empty() {
+            return Ok(None);
+        }
+        // the segment ends after the first or a middle fragment
+        if !complete {
+            println!("Torn WAL record at the end of a segment.");
+            return Err(TSDBError::Default);
+        }
+
+        // the compression is applied to the whole record before it is split
+        // into fragments
+        match compression {
+            0 => Ok(Some(record)),
+            SNAPPY_MASK => match snap::raw::Decoder::new().decompress_vec(&record) {
+                Ok(r) => Ok(Some(r)),
+                Err(_) => Err(TSDBError::Default),
+            },
+            // TODO: support zstd compressed records
+            _ => Err(TSDBError::Default),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Segment {
+    fn from(buf: Vec<u8>) -> Self {
+        Self {
+            buf,
+            current_pos: 0,
+        }
+    }
+}
+
+// A corrupted or undecodable record ends the segment with an error, the
+// records after it can not be trusted.
+impl Iterator for Segment {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match self.record() {
+            Ok(Some(r)) => Some(r.as_slice().try_into()),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        };
+        if let Some(Err(_)) = result {
+            self.current_pos = self.buf.len();
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefSeries {
+    pub series_ref: u64,
+    pub labels: Labels,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RefSample {
+    pub series_ref: u64,
+    pub t: i64,
+    pub v: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefTombstone {
+    pub series_ref: u64,
+    pub mint: i64,
+    pub maxt: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefMmapMarker {
+    pub series_ref: u64,
+    pub mmap_ref: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Series(Vec<RefSeries>),
+    Samples(Vec<RefSample>),
+    Tombstones(Vec<RefTombstone>),
+    MmapMarkers(Vec<RefMmapMarker>),
+    // exemplars, metadata and histograms are not decoded yet
+    Unknown(u8),
+}
+
+fn read_string(buf: &[u8], pos: usize) -> Result<(String, usize)> {
+    let (len, size) = read_varint_u32(buf, pos)?;
+    if size == 0 || buf.len() < pos + size + len as usize {
+        return Err(TSDBError::Default);
+    }
+    let data = slice_bytes(buf, len as usize, pos + size);
+
+    match str::from_utf8(data) {
+        Ok(s) => Ok((s.to_string(), size + len as usize)),
+        Err(_) => Err(TSDBError::Default),
+    }
+}
+
+fn read_i64(buf: &[u8], pos: usize) -> Result<i64> {
+    Ok(read_u64(buf, pos)? as i64)
+}
+
+fn check_len(buf: &[u8], pos: usize, len: usize) -> Result<()> {
+    if buf.len() < pos + len {
+        return Err(TSDBError::Default);
+    }
+    Ok(())
+}
+
+impl TryFrom<&[u8]> for Record {
+    type Error = TSDBError;
+
+    fn try_from(buf: &[u8]) -> std::result::Result<Self, Self::Error> {
+        if buf.is_empty() {
+            return Err(TSDBError::Default);
+        }
+        let mut pos = 1;
+
+        match buf[0] {
+            SERIES => {
+                let mut series = Vec::<RefSeries>::new();
+                while pos < buf.len() {
+                    check_len(buf, pos, 8)?;
+                    let series_ref = read_u64(buf, pos)?;
+                    pos += 8;
+                    let (num_labels, size) = read_varint_u64(buf, pos)?;
+                    pos += size;
+
+                    let mut labels = Labels::new();
+                    for _ in 0..num_labels {
+                        let (name, size) = read_string(buf, pos)?;
+                        pos += size;
+                        let (value, size) = read_string(buf, pos)?;
+                        pos += size;
+
+                        labels.push((name, value));
+                    }
+
+                    series.push(RefSeries { series_ref, labels });
+                }
+                Ok(Record::Series(series))
+            }
+            SAMPLES => {
+                let mut samples = Vec::<RefSample>::new();
+                if pos == buf.len() {
+                    return Ok(Record::Samples(samples));
+                }
+                // all samples are stored as deltas to the first ref and time
+                check_len(buf, pos, 16)?;
+                let base_ref = read_u64(buf, pos)?;
+                pos += 8;
+                let base_time = read_i64(buf, pos)?;
+                pos += 8;
+
+                while pos < buf.len() {
+                    let (dref, size) = read_varint_i64(buf, pos)?;
+                    pos += size;
+                    let (dtime, size) = read_varint_i64(buf, pos)?;
+                    pos += size;
+                    check_len(buf, pos, 8)?;
+                    let v = f64::from_bits(read_u64(buf, pos)?);
+                    pos += 8;
+
+                    match (
+                        (base_ref as i64).checked_add(dref),
+                        base_time.checked_add(dtime),
+                    ) {
+                        (Some(series_ref), Some(t)) => samples.push(RefSample {
+                            series_ref: series_ref as u64,
+                            t,
+                            v,
+                        }),
+                        _ => return Err(TSDBError::Default),
+                    }
+                }
+                Ok(Record::Samples(samples))
+            }
+            TOMBSTONES => {
+                let mut tombstones = Vec::<RefTombstone>::new();
+                while pos < buf.len() {
+                    check_len(buf, pos, 8)?;
+                    let series_ref = read_u64(buf, pos)?;
+                    pos += 8;
+                    let (mint, size) = read_varint_i64(buf, pos)?;
+                    pos += size;
+                    let (maxt, size) = read_varint_i64(buf, pos)?;
+                    pos += size;
+
+                    tombstones.push(RefTombstone {
+                        series_ref,
+                        mint,
+                        maxt,
+                    });
+                }
+                Ok(Record::Tombstones(tombstones))
+            }
+            MMAP_MARKERS => {
+                let mut markers = Vec::<RefMmapMarker>::new();
+                while pos < buf.len() {
+                    check_len(buf, pos, 16)?;
+                    let series_ref = read_u64(buf, pos)?;
+                    pos += 8;
+                    let mmap_ref = read_u64(buf, pos)?;
+                    pos += 8;
+
+                    markers.push(RefMmapMarker {
+                        series_ref,
+                        mmap_ref,
+                    });
+                }
+                Ok(Record::MmapMarkers(markers))
+            }
+            t => Ok(Record::Unknown(t)),
+        }
+    }
+}
+
+// encode records the way Prometheus does it, the counterpart of
+// Record::try_from
+pub fn encode_series(series: &[RefSeries]) -> Vec<u8> {
+    let mut buf = vec![SERIES];
+    for s in series {
+        write_u64(&mut buf, s.series_ref);
+        write_varint_u64(&mut buf, s.labels.len() as u64);
+        for (name, value) in s.labels.iter() {
+            write_str(&mut buf, name);
+            write_str(&mut buf, value);
+        }
+    }
+    buf
+}
+
+pub fn encode_samples(samples: &[RefSample]) -> Vec<u8> {
+    let mut buf = vec![SAMPLES];
+    let first = match samples.first() {
+        Some(s) => *s,
+        None => return buf,
+    };
+    write_u64(&mut buf, first.series_ref);
+    write_u64(&mut buf, first.t as u64);
+    for s in samples {
+        write_varint_i64(&mut buf, s.series_ref as i64 - first.series_ref as i64);
+        write_varint_i64(&mut buf, s.t - first.t);
+        write_u64(&mut buf, s.v.to_bits());
+    }
+    buf
+}
+
+pub fn encode_tombstones(tombstones: &[RefTombstone]) -> Vec<u8> {
+    let mut buf = vec![TOMBSTONES];
+    for t in tombstones {
+        write_u64(&mut buf, t.series_ref);
+        write_varint_i64(&mut buf, t.mint);
+        write_varint_i64(&mut buf, t.maxt);
+    }
+    buf
+}
+
+fn segment_name(index: u32) -> String {
+    format!("{:08}", index)
+}
+
+// list all numbered segment files in a directory sorted by their index
+pub fn segments(dir: &Path) -> Result<Vec<(u32, PathBuf)>> {
+    let mut segments = Vec::<(u32, PathBuf)>::new();
+
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(index) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            segments.push((index, entry.path()));
+        }
+    }
+    segments.sort();
+
+    // segments have to be consecutive, a gap means data loss
+    for w in segments.windows(2) {
+        if w[0].0 + 1 != w[1].0 {
+            println!("Missing WAL segment {}.", w[0].0 + 1);
+            return Err(TSDBError::Default);
+        }
+    }
+
+    Ok(segments)
+}
+
+// find the checkpoint with the highest index, unfinished checkpoints have a
+// .tmp suffix and are ignored
+pub fn last_checkpoint(dir: &Path) -> Result<Option<(u32, PathBuf)>> {
+    let mut last: Option<(u32, PathBuf)> = None;
+
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let index = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_prefix(CHECKPOINT_PREFIX))
+            .and_then(|n| n.parse::<u32>().ok());
+
+        if let Some(index) = index {
+            if last.as_ref().is_none_or(|(l, _)| index > *l) {
+                last = Some((index, entry.path()));
+            }
+        }
+    }
+
+    Ok(last)
+}
+
+// A write ahead log directory. A checkpoint covers all segments up to and
+// including its index, so on replay the checkpoint is read first followed by
+// all segments after it.
+#[derive(Debug)]
+pub struct Wal {
+    pub checkpoint: Option<(u32, PathBuf)>,
+    pub segments: Vec<(u32, PathBuf)>,
+}
+
+impl Wal {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let checkpoint = last_checkpoint(dir)?;
+        let first = checkpoint.as_ref().map_or(0, |(i, _)| i + 1);
+
+        let segments = segments(dir)?
+            .into_iter()
+            .filter(|(i, _)| *i >= first)
+            .collect();
+
+        Ok(Self {
+            checkpoint,
+            segments,
+        })
+    }
+
+    // files in the order they have to be replayed
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::<PathBuf>::new();
+
+        if let Some((_, dir)) = &self.checkpoint {
+            for (_, path) in segments(dir)? {
+                files.push(path);
+            }
+        }
+        for (_, path) in &self.segments {
+            files.push(path.clone());
+        }
+
+        Ok(files)
+    }
+
+    pub fn records(&self) -> Result<Records> {
+        Ok(Records::new(self.files()?))
+    }
+
+    pub fn replay(&self) -> Result<Replay> {
+        let mut replay = Replay::default();
+
+        for record in self.records()? {
+            replay.apply(record?);
+        }
+
+        Ok(replay)
+    }
+
+    // Replay like Prometheus does on startup. A corrupted record in a
+    // segment, e.g. one torn by a crash, cuts the segment off before it and
+    // removes all later segments, the records before it are kept. A corrupted
+    // checkpoint is not repaired.
+    pub fn replay_and_repair(&mut self) -> Result<Replay> {
+        let mut replay = Replay::default();
+
+        if let Some((_, dir)) = &self.checkpoint {
+            let files = segments(dir)?.into_iter().map(|(_, p)| p).collect();
+            for record in Records::new(files) {
+                replay.apply(record?);
+            }
+        }
+
+        let mut corrupted = None;
+        'segments: for (n, (_, path)) in self.segments.iter().enumerate() {
+            let mut segment = Segment::new(path)?;
+            loop {
+                let end = segment.current_pos;
+                match segment.next() {
+                    Some(Ok(record)) => replay.apply(record),
+                    Some(Err(_)) => {
+                        corrupted = Some((n, end));
+                        break 'segments;
+                    }
+                    None => break,
+                }
+            }
+        }
+        if let Some((n, end)) = corrupted {
+            self.repair(n, end)?;
+        }
+
+        Ok(replay)
+    }
+
+    // truncate the n-th segment at offset and remove all segments after it
+    fn repair(&mut self, n: usize, offset: usize) -> Result<()> {
+        let path = &self.segments[n].1;
+        println!(
+            "Truncating corrupted WAL segment {} at offset {}.",
+            path.display(),
+            offset
+        );
+        // the last page is zero padded like the one of a finished segment
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(offset as u64)?;
+        file.set_len((offset.div_ceil(PAGE_SIZE) * PAGE_SIZE) as u64)?;
+        file.sync_all()?;
+
+        for (_, path) in self.segments.drain(n + 1..) {
+            println!("Removing WAL segment {}.", path.display());
+            remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Appends records to the segments of a WAL directory. Like Prometheus on
+// startup, writing starts with a new segment after the existing ones.
+#[derive(Debug)]
+pub struct WalWriter {
+    dir: PathBuf,
+    file: File,
+    // index of the current segment and the bytes written to it
+    index: u32,
+    size: usize,
+    segment_size: usize,
+}
+
+impl WalWriter {
+    pub fn open(dir: &Path) -> Result<Self> {
+        create_dir_all(dir)?;
+        let index = segments(dir)?.last().map_or(0, |(i, _)| i + 1);
+        let file = File::create(dir.join(segment_name(index)))?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file,
+            index,
+            size: 0,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+        })
+    }
+
+    // rounded up to full pages
+    pub fn set_segment_size(&mut self, size: usize) {
+        self.segment_size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // index of the segment that is written to
+    pub fn segment(&self) -> u32 {
+        self.index
+    }
+
+    // bytes of record data that still fit into the current segment
+    fn available(&self) -> usize {
+        let mut size = self.size;
+        let mut page_left = PAGE_SIZE - size % PAGE_SIZE;
+        if page_left <= RECORD_HEADER_SIZE {
+            size += page_left;
+            page_left = PAGE_SIZE;
+        }
+        if size >= self.segment_size {
+            return 0;
+        }
+
+        let pages = (self.segment_size - size - page_left) / PAGE_SIZE;
+        page_left - RECORD_HEADER_SIZE + pages * (PAGE_SIZE - RECORD_HEADER_SIZE)
+    }
+
+    // pad the last page and sync the segment, Prometheus expects all but the
+    // last segment to consist of full pages
+    fn finish_segment(&mut self) -> Result<()> {
+        let padding = (PAGE_SIZE - self.size % PAGE_SIZE) % PAGE_SIZE;
+        self.file.write_all(&vec![0; padding])?;
+        self.file.sync_all()?;
+        self.size += padding;
+        Ok(())
+    }
+
+    // continue with a new segment, the previous ones can be checkpointed
+    pub fn next_segment(&mut self) -> Result<()> {
+        self.finish_segment()?;
+        self.file = File::create(self.dir.join(segment_name(self.index + 1)))?;
+        self.index += 1;
+        self.size = 0;
+        Ok(())
+    }
+
+    // Write a record, split into fragments if it does not fit into the
+    // remainder of the current page. A record never spans segments, so a new
+    // segment is started if it does not fit into the current one.
+    pub fn log(&mut self, record: &[u8]) -> Result<()> {
+        if record.len() > self.available() {
+            if self.size == 0 {
+                println!("Record of {} bytes exceeds the segment size.", record.len());
+                return Err(TSDBError::Default);
+            }
+            self.next_segment()?;
+            if record.len() > self.available() {
+                println!("Record of {} bytes exceeds the segment size.", record.len());
+                return Err(TSDBError::Default);
+            }
+        }
+
+        let mut buf = Vec::<u8>::new();
+        let mut rest = record;
+        let mut first = true;
+
+        loop {
+            let mut page_left = PAGE_SIZE - self.size % PAGE_SIZE;
+            // a header never spans pages, the remainder is zero padded
+            if page_left <= RECORD_HEADER_SIZE {
+                buf.resize(buf.len() + page_left, 0);
+                self.size += page_left;
+                page_left = PAGE_SIZE;
+            }
+
+            let n = rest.len().min(page_left - RECORD_HEADER_SIZE);
+            let last = n == rest.len();
+            let typ = match (first, last) {
+                (true, true) => FULL,
+                (true, false) => FIRST,
+                (false, false) => MIDDLE,
+                (false, true) => LAST,
+            };
+
+            buf.push(typ);
+            buf.extend_from_slice(&(n as u16).to_be_bytes());
+            write_u32(&mut buf, CASTAGNIOLI.checksum(&rest[..n]));
+            buf.extend_from_slice(&rest[..n]);
+            self.size += RECORD_HEADER_SIZE + n;
+            rest = &rest[n..];
+            first = false;
+
+            if last {
+                break;
+            }
+        }
+
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        self.finish_segment()
+    }
+}
+
+// write the filtered records of files as segments of a new checkpoint in dir
+fn write_checkpoint(
+    dir: &Path,
+    files: Vec<PathBuf>,
+    keep: impl Fn(u64) -> bool,
+    mint: i64,
+) -> Result<()> {
+    let mut writer = WalWriter::open(dir)?;
+    for record in Records::new(files) {
+        let encoded = match record? {
+            Record::Series(mut series) => {
+                series.retain(|s| keep(s.series_ref));
+                (!series.is_empty()).then(|| encode_series(&series))
+            }
+            Record::Samples(mut samples) => {
+                samples.retain(|s| s.t >= mint);
+                (!samples.is_empty()).then(|| encode_samples(&samples))
+            }
+            Record::Tombstones(mut tombstones) => {
+                tombstones.retain(|t| t.maxt >= mint);
+                (!tombstones.is_empty()).then(|| encode_tombstones(&tombstones))
+            }
+            Record::MmapMarkers(_) | Record::Unknown(_) => None,
+        };
+        if let Some(r) = encoded {
+            writer.log(&r)?;
+        }
+    }
+    writer.close()
+}
+
+// NOTE: Checkpoints as written by Prometheus:
+// https://github.com/prometheus/prometheus/blob/main/tsdb/docs/format/wal.md
+//
+// The records of the last checkpoint and of all segments up to and including
+// segment `to` are written into checkpoint.<to>. Only series for which keep
+// returns true and samples and tombstones from mint on are kept, m-map
+// markers and records that are not decoded are dropped. The checkpoint is
+// written to a temporary directory first. Once it is complete, the segments
+// and checkpoints it covers are removed, so segment `to` must not be the one
+// that is written to.
+pub fn checkpoint(dir: &Path, to: u32, keep: impl Fn(u64) -> bool, mint: i64) -> Result<PathBuf> {
+    let last = last_checkpoint(dir)?;
+    if last.as_ref().is_some_and(|(i, _)| *i >= to) {
+        println!("Segment {} is covered by a checkpoint already.", to);
+        return Err(TSDBError::Default);
+    }
+    let first = last.as_ref().map_or(0, |(i, _)| i + 1);
+
+    let mut files = Vec::<PathBuf>::new();
+    if let Some((_, cp)) = &last {
+        files.extend(segments(cp)?.into_iter().map(|(_, p)| p));
+    }
+    let covered: Vec<(u32, PathBuf)> = segments(dir)?
+        .into_iter()
+        .filter(|(i, _)| *i <= to)
+        .collect();
+    files.extend(
+        covered
+            .iter()
+            .filter(|(i, _)| *i >= first)
+            .map(|(_, p)| p.clone()),
+    );
+
+    let name = format!("{}{}", CHECKPOINT_PREFIX, segment_name(to));
+    let tmp = dir.join(format!("{}{}", name, TMP_SUFFIX));
+    let cp = dir.join(name);
+    if tmp.exists() {
+        remove_dir_all(&tmp)?;
+    }
+
+    // nothing is removed unless all records could be read and written
+    if let Err(e) = write_checkpoint(&tmp, files, keep, mint) {
+        if tmp.exists() {
+            remove_dir_all(&tmp)?;
+        }
+        return Err(e);
+    }
+    rename(&tmp, &cp)?;
+
+    for (_, path) in covered {
+        remove_file(path)?;
+    }
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let index = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_prefix(CHECKPOINT_PREFIX))
+            .and_then(|n| n.parse::<u32>().ok());
+        if index.is_some_and(|i| i < to) {
+            remove_dir_all(entry.path())?;
+        }
+    }
+
+    Ok(cp)
+}
+
+// The write-behind log in wbl/ uses the segment format of the WAL. It holds
+// out of order samples and m-map markers, series records are only written to
+// the WAL. It is not checkpointed.
+#[derive(Debug)]
+pub struct Wbl {
+    pub segments: Vec<(u32, PathBuf)>,
+}
+
+impl Wbl {
+    pub fn open(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            segments: segments(dir)?,
+        })
+    }
+
+    pub fn records(&self) -> Records {
+        Records::new(self.segments.iter().map(|(_, p)| p.clone()).collect())
+    }
+
+    // merge the out of order samples into a replayed WAL
+    pub fn replay(&self, replay: &mut Replay) -> Result<()> {
+        for record in self.records() {
+            replay.apply_ooo(record?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Records {
+    // remaining files in reverse order
+    files: Vec<PathBuf>,
+    current: Option<(PathBuf, Segment)>,
+}
+
+impl Records {
+    fn new(mut files: Vec<PathBuf>) -> Self {
+        files.reverse();
+
+        Self {
+            files,
+            current: None,
+        }
+    }
+}
+
+// Records of all files in order. The first unreadable file or corrupted
+// record is returned as an error and ends the iteration.
+impl Iterator for Records {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.current.as_mut().and_then(|(_, s)| s.next()) {
+                Some(Ok(record)) => return Some(Ok(record)),
+                Some(Err(e)) => {
+                    if let Some((path, _)) = self.current.take() {
+                        println!("Corrupted WAL segment {}.", path.display());
+                    }
+                    self.files.clear();
+                    return Some(Err(e));
+                }
+                None => {}
+            }
+
+            let path = self.files.pop()?;
+            match Segment::new(&path) {
+                Ok(segment) => self.current = Some((path, segment)),
+                Err(e) => {
+                    println!("Failed to read WAL segment {}.", path.display());
+                    self.files.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplaySeries {
+    pub labels: Labels,
+    pub samples: Vec<(i64, f64)>,
+    // sorted out of order samples from the write-behind log
+    pub ooo_samples: Vec<(i64, f64)>,
+}
+
+impl ReplaySeries {
+    // iterate in-order and out of order samples sorted by time, in-order
+    // samples win for duplicate timestamps
+    pub fn iter(&self) -> MergedSamples<'_> {
+        MergedSamples {
+            a: &self.samples,
+            b: &self.ooo_samples,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MergedSamples<'a> {
+    a: &'a [(i64, f64)],
+    b: &'a [(i64, f64)],
+}
+
+impl Iterator for MergedSamples<'_> {
+    type Item = (i64, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match (self.a.first(), self.b.first()) {
+            (Some(a), Some(b)) if b.0 < a.0 => {
+                self.b = &self.b[1..];
+                Some(*b)
+            }
+            (Some(a), Some(b)) => {
+                if a.0 == b.0 {
+                    self.b = &self.b[1..];
+                }
+                self.a = &self.a[1..];
+                Some(*a)
+            }
+            (Some(a), None) => {
+                self.a = &self.a[1..];
+                Some(*a)
+            }
+            (None, Some(b)) => {
+                self.b = &self.b[1..];
+                Some(*b)
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+// The state of the head after replaying a write ahead log.
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub series: BTreeMap<u64, ReplaySeries>,
+    pub tombstones: Vec<RefTombstone>,
+    // last m-map marker per series, out of order samples before it have been
+    // written to chunks_head
+    pub mmap_markers: HashMap<u64, u64>,
+    // series records can be repeated with a new ref for the same labels, those
+    // refs are mapped to the ref seen first
+    refs: HashMap<u64, u64>,
+    by_labels: HashMap<Labels, u64>,
+}
+
+impl Replay {
+    pub fn apply(&mut self, record: Record) {
+        match record {
+            Record::Series(series) => {
+                for s in series {
+                    match self.by_labels.get(&s.labels) {
+                        Some(r) => {
+                            self.refs.insert(s.series_ref, *r);
+                        }
+                        None => {
+                            self.by_labels.insert(s.labels.clone(), s.series_ref);
+                            self.refs.insert(s.series_ref, s.series_ref);
+                            self.series.insert(
+                                s.series_ref,
+                                ReplaySeries {
+                                    labels: s.labels,
+                                    ..Default::default()
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            Record::Samples(samples) => {
+                for s in samples {
+                    let series = self
+                        .refs
+                        .get(&s.series_ref)
+                        .and_then(|r| self.series.get_mut(r));
+
+                    // samples for unknown series and out of order samples are
+                    // dropped as the head would do it
+                    if let Some(series) = series {
+                        if series.samples.last().is_none_or(|(t, _)| s.t > *t) {
+                            series.samples.push((s.t, s.v));
+                        }
+                    }
+                }
+            }
+            Record::Tombstones(tombstones) => {
+                for mut t in tombstones {
+                    if let Some(r) = self.refs.get(&t.series_ref) {
+                        t.series_ref = *r;
+                    }
+                    self.tombstones.push(t);
+                }
+            }
+            Record::MmapMarkers(_) | Record::Unknown(_) => {}
+        }
+    }
+
+    // highest ref of all series records, new series must not reuse any of them
+    pub fn max_series_ref(&self) -> u64 {
+        self.refs.keys().max().copied().unwrap_or(0)
+    }
+
+    pub fn apply_ooo(&mut self, record: Record) {
+        match record {
+            Record::Samples(samples) => {
+                for s in samples {
+                    let series = self
+                        .refs
+                        .get(&s.series_ref)
+                        .and_then(|r| self.series.get_mut(r));
+
+                    if let Some(series) = series {
+                        // the first sample for a timestamp is kept
+                        if let Err(i) = series.ooo_samples.binary_search_by_key(&s.t, |(t, _)| *t) {
+                            series.ooo_samples.insert(i, (s.t, s.v));
+                        }
+                    }
+                }
+            }
+            Record::MmapMarkers(markers) => {
+                for m in markers {
+                    let r = self.refs.get(&m.series_ref).unwrap_or(&m.series_ref);
+                    self.mmap_markers.insert(*r, m.mmap_ref);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir, write};
+
+    fn series_record(series_ref: u64, labels: &[(&str, &str)]) -> Vec<u8> {
+        encode_series(&[RefSeries {
+            series_ref,
+            labels: labels
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        }])
+    }
+
+    fn samples_record(samples: &[(u64, i64, f64)]) -> Vec<u8> {
+        let samples: Vec<RefSample> = samples
+            .iter()
+            .map(|(series_ref, t, v)| RefSample {
+                series_ref: *series_ref,
+                t: *t,
+                v: *v,
+            })
+            .collect();
+        encode_samples(&samples)
+    }
+
+    // Log records into the next segment of dir. The segment is not padded,
+    // like the last one of a running WAL.
+    fn write_segment(dir: &Path, records: &[Vec<u8>]) -> PathBuf {
+        let mut writer = WalWriter::open(dir).unwrap();
+        for r in records {
+            writer.log(r).unwrap();
+        }
+        dir.join(segment_name(writer.segment()))
+    }
+
+    #[test]
+    fn read_fragmented_records() {
+        let big = vec![("a", "b"); 10_000];
+        let records = vec![
+            series_record(1, &[("__name__", "up")]),
+            series_record(2, &big),
+            samples_record(&[(1, 1000, 1.0), (2, 1001, 2.5)]),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_segment(dir.path(), &records);
+        let buf = std::fs::read(&path).unwrap();
+
+        // a record torn after its first fragment is not returned
+        let torn: Vec<Result<Record>> = Segment::from(buf[..PAGE_SIZE].to_vec()).collect();
+        assert_eq!(2, torn.len());
+        assert!(torn[1].is_err());
+
+        let records: Vec<Record> = Segment::from(buf).collect::<Result<_>>().unwrap();
+
+        assert_eq!(3, records.len());
+        match &records[1] {
+            Record::Series(s) => assert_eq!(10_000, s[0].labels.len()),
+            r => panic!("unexpected record {:?}", r),
+        }
+        assert_eq!(
+            Record::Samples(vec![
+                RefSample {
+                    series_ref: 1,
+                    t: 1000,
+                    v: 1.0
+                },
+                RefSample {
+                    series_ref: 2,
+                    t: 1001,
+                    v: 2.5
+                },
+            ]),
+            records[2]
+        );
+    }
+
+    #[test]
+    fn write_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let series = vec![
+            RefSeries {
+                series_ref: 1,
+                labels: vec![(String::from("__name__"), String::from("up"))],
+            },
+            RefSeries {
+                series_ref: 2,
+                labels: vec![(String::from("a"), String::from("b")); 10_000],
+            },
+        ];
+        let samples: Vec<RefSample> = (0..5000)
+            .map(|i| RefSample {
+                series_ref: 1 + i % 2,
+                t: i as i64 * 1000,
+                v: i as f64,
+            })
+            .collect();
+
+        let mut writer = WalWriter::open(dir.path()).unwrap();
+        writer.log(&encode_series(&series)).unwrap();
+        writer.log(&encode_samples(&samples)).unwrap();
+        writer.close().unwrap();
+
+        // the series record is split into fragments over the first two pages and
+        // the closed segment is padded to full pages
+        let buf = std::fs::read(dir.path().join("00000000")).unwrap();
+        assert_eq!(0, buf.len() % PAGE_SIZE);
+        assert_eq!(FIRST, buf[0]);
+        assert_eq!(
+            (PAGE_SIZE - RECORD_HEADER_SIZE) as u16,
+            read_u16(&buf, 1).unwrap()
+        );
+        assert_eq!(LAST, buf[PAGE_SIZE]);
+        let records: Vec<Record> = Segment::from(buf).collect::<Result<_>>().unwrap();
+        assert_eq!(
+            vec![Record::Series(series), Record::Samples(samples)],
+            records
+        );
+
+        // a new writer continues with the next segment
+        let mut writer = WalWriter::open(dir.path()).unwrap();
+        assert_eq!(1, writer.segment());
+        writer
+            .log(&encode_samples(&[RefSample {
+                series_ref: 1,
+                t: 5_000_000,
+                v: 1.0,
+            }]))
+            .unwrap();
+
+        let replay = Wal::open(dir.path()).unwrap().replay().unwrap();
+        assert_eq!(2, replay.series.len());
+        assert_eq!(2501, replay.series.get(&1).unwrap().samples.len());
+        assert_eq!(
+            (4999 * 1000, 4999.0),
+            replay.series.get(&2).unwrap().samples[2499]
+        );
+    }
+
+    #[test]
+    fn rotate_and_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = WalWriter::open(dir.path()).unwrap();
+        writer.set_segment_size(2 * PAGE_SIZE);
+
+        let series: Vec<RefSeries> = (1..=100)
+            .map(|i| RefSeries {
+                series_ref: i,
+                labels: vec![(String::from("i"), i.to_string())],
+            })
+            .collect();
+        writer.log(&encode_series(&series)).unwrap();
+        writer
+            .log(&encode_tombstones(&[
+                RefTombstone {
+                    series_ref: 2,
+                    mint: 0,
+                    maxt: 50_000,
+                },
+                RefTombstone {
+                    series_ref: 4,
+                    mint: 0,
+                    maxt: 150_000,
+                },
+            ]))
+            .unwrap();
+        for k in 0..200 {
+            let samples: Vec<RefSample> = (1..=100)
+                .map(|i| RefSample {
+                    series_ref: i,
+                    t: k * 1000,
+                    v: k as f64,
+                })
+                .collect();
+            writer.log(&encode_samples(&samples)).unwrap();
+        }
+
+        // records never span segments and all but the last one are padded
+        let all = segments(dir.path()).unwrap();
+        assert_eq!(writer.segment() as usize + 1, all.len());
+        assert!(all.len() > 2);
+        for (_, path) in &all[..all.len() - 1] {
+            assert_eq!(2 * PAGE_SIZE, std::fs::read(path).unwrap().len());
+        }
+        let before = Wal::open(dir.path()).unwrap().replay().unwrap();
+        assert_eq!(200, before.series.get(&1).unwrap().samples.len());
+
+        let to = writer.segment() - 1;
+        let cp = checkpoint(dir.path(), to, |r| r % 2 == 0, 100_000).unwrap();
+        assert!(checkpoint(dir.path(), to, |_| true, 0).is_err());
+
+        let wal = Wal::open(dir.path()).unwrap();
+        assert_eq!(Some((to, cp)), wal.checkpoint);
+        assert_eq!(1, segments(dir.path()).unwrap().len());
+
+        let replay = wal.replay().unwrap();
+        assert_eq!(50, replay.series.len());
+        let samples = &replay.series.get(&2).unwrap().samples;
+        assert_eq!(before.series.get(&2).unwrap().samples[100..], samples[..]);
+        assert_eq!(
+            vec![RefTombstone {
+                series_ref: 4,
+                mint: 0,
+                maxt: 150_000
+            }],
+            replay.tombstones
+        );
+
+        // a new checkpoint replaces the old one
+        writer.next_segment().unwrap();
+        checkpoint(dir.path(), writer.segment() - 1, |_| true, 0).unwrap();
+        let wal = Wal::open(dir.path()).unwrap();
+        assert_eq!(writer.segment() - 1, wal.checkpoint.as_ref().unwrap().0);
+        assert_eq!(50, wal.replay().unwrap().series.len());
+        assert_eq!(
+            1,
+            read_dir(dir.path())
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().path().is_dir())
+                .count()
+        );
+    }
+
+    #[test]
+    fn replay_corrupted_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_segment(
+            dir.path(),
+            &[
+                series_record(1, &[("__name__", "up")]),
+                samples_record(&[(1, 1000, 1.0)]),
+                samples_record(&[(1, 2000, 2.0)]),
+            ],
+        );
+        let mut buf = std::fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        write(&path, &buf).unwrap();
+
+        let records: Vec<Result<Record>> = Segment::from(buf).collect();
+        assert_eq!(3, records.len());
+        assert!(matches!(records[2], Err(TSDBError::Checksum)));
+
+        // the replay does not stop silently at the corrupted record
+        assert!(Wal::open(dir.path()).unwrap().replay().is_err());
+
+        // a time delta overflowing the base time
+        let mut overflow = vec![SAMPLES];
+        write_u64(&mut overflow, 1);
+        write_u64(&mut overflow, i64::MAX as u64);
+        write_varint_i64(&mut overflow, 0);
+        write_varint_i64(&mut overflow, 1);
+        write_u64(&mut overflow, 0);
+        assert!(Record::try_from(overflow.as_slice()).is_err());
+    }
+
+    #[test]
+    fn checkpoint_corrupted_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = [
+            series_record(1, &[("__name__", "up")]),
+            samples_record(&[(1, 1000, 1.0)]),
+        ];
+        write_segment(dir.path(), &records);
+        let path = write_segment(dir.path(), &[samples_record(&[(1, 2000, 2.0)])]);
+        let mut buf = std::fs::read(&path).unwrap();
+        buf[RECORD_HEADER_SIZE] ^= 0xff;
+        write(&path, &buf).unwrap();
+        write_segment(dir.path(), &records);
+
+        // the checkpoint is aborted before any segment is removed
+        assert!(matches!(
+            checkpoint(dir.path(), 1, |_| true, 0),
+            Err(TSDBError::Checksum)
+        ));
+        assert_eq!(3, segments(dir.path()).unwrap().len());
+        assert_eq!(None, last_checkpoint(dir.path()).unwrap());
+        assert!(!dir.path().join("checkpoint.00000001.tmp").exists());
+    }
+
+    #[test]
+    fn replay_out_of_order_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = dir.path().join("wal");
+        let wbl = dir.path().join("wbl");
+        create_dir(&wal).unwrap();
+        create_dir(&wbl).unwrap();
+
+        write_segment(
+            &wal,
+            &[
+                series_record(1, &[("__name__", "up")]),
+                samples_record(&[(1, 1000, 1.0), (1, 2000, 2.0), (1, 3000, 3.0)]),
+            ],
+        );
+
+        let mut markers = vec![MMAP_MARKERS];
+        markers.extend_from_slice(&1u64.to_be_bytes());
+        markers.extend_from_slice(&8u64.to_be_bytes());
+        write_segment(
+            &wbl,
+            &[
+                samples_record(&[(1, 2500, 2.5), (1, 1500, 1.5), (1, 2000, 0.0)]),
+                markers,
+            ],
+        );
+
+        let mut replay = Wal::open(&wal).unwrap().replay().unwrap();
+        Wbl::open(&wbl).unwrap().replay(&mut replay).unwrap();
+
+        let series = replay.series.get(&1).unwrap();
+        assert_eq!(
+            vec![
+                (1000, 1.0),
+                (1500, 1.5),
+                (2000, 2.0),
+                (2500, 2.5),
+                (3000, 3.0)
+            ],
+            series.iter().collect::<Vec<(i64, f64)>>()
+        );
+        assert_eq!(Some(&8), replay.mmap_markers.get(&1));
+    }
+
+    #[test]
+    fn replay_checkpoint_and_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("checkpoint.00000001");
+        create_dir(&checkpoint).unwrap();
+        create_dir(dir.path().join("checkpoint.00000002.tmp")).unwrap();
+
+        write_segment(
+            &checkpoint,
+            &[
+                series_record(1, &[("__name__", "up")]),
+                samples_record(&[(1, 1000, 1.0)]),
+            ],
+        );
+        // covered by the checkpoint and must be skipped
+        write_segment(dir.path(), &[]);
+        write_segment(dir.path(), &[samples_record(&[(1, 500, 0.0)])]);
+        write_segment(
+            dir.path(),
+            &[
+                series_record(5, &[("__name__", "up")]),
+                samples_record(&[(5, 2000, 2.0), (5, 1500, 1.5)]),
+            ],
+        );
+
+        let wal = Wal::open(dir.path()).unwrap();
+        assert_eq!(1, wal.checkpoint.as_ref().unwrap().0);
+        assert_eq!(1, wal.segments.len());
+
+        let replay = wal.replay().unwrap();
+        assert_eq!(1, replay.series.len());
+        assert_eq!(
+            &ReplaySeries {
+                labels: vec![(String::from("__name__"), String::from("up"))],
+                samples: vec![(1000, 1.0), (2000, 2.0)],
+                ..Default::default()
+            },
+            replay.series.get(&1).unwrap()
+        );
+    }
+}